@@ -1,12 +1,27 @@
 use std::env;
 use std::fs;
 use std::path::PathBuf;
+use std::process;
 use parser::parser::expression_parser;
 use parser::ast::Expr;
-use combine::Parser;
+use combine::stream::position;
+use combine::{eof, EasyParser, Parser};
+
+#[allow(dead_code)]
+#[path = "src/diagnostic.rs"]
+mod diagnostic;
+
+use diagnostic::Diagnostic;
+
+/// Stops the build with `message` instead of a panic and its backtrace.
+fn fail(message: impl std::fmt::Display) -> ! {
+  eprintln!("{}", message);
+  process::exit(1);
+}
 
 fn main() {
-  let file_path = std::env::var("TARGET_FILE").expect("Missing TARGET_FILE env variable");
+  let file_path = env::var("TARGET_FILE")
+    .unwrap_or_else(|_| fail("Missing TARGET_FILE env variable"));
   let path = file_path.clone();
 
   /////////// Tell Rust to re-run this build script //////////////
@@ -14,13 +29,18 @@ fn main() {
   println!("cargo:rerun-if-env-changed=TARGET_FILE");
   ////////////////////////////////////////////////////////////////
 
-  let out_dir = env::var("OUT_DIR").unwrap();
+  let out_dir = env::var("OUT_DIR").unwrap_or_else(|_| fail("Missing OUT_DIR env variable"));
   let dest_path = PathBuf::from(out_dir).join("gen.rs");
-  let content = fs::read_to_string(file_path).unwrap();
-  let ast: Expr = expression_parser().parse(content.trim()).unwrap().0;
+  let content = fs::read_to_string(&file_path)
+    .unwrap_or_else(|err| fail(format!("Could not read `{}`: {}", file_path, err)));
+  let ast: Expr = match expression_parser().skip(eof()).easy_parse(position::Stream::new(content.as_str())) {
+    Ok((ast, _)) => ast,
+    Err(err) => fail(Diagnostic::from_parse_error(&content, err).with_file(&file_path)),
+  };
   fs::write(&dest_path, format!("
     const TARGET_FILE: &str = \"{}\";
     const EXPR: &str = \"{}\";
     pub fn gen_expr() -> Expr {{ {} }}
-    ", path, content, ast)).unwrap();
-}
\ No newline at end of file
+    ", path, content, ast))
+    .unwrap_or_else(|err| fail(format!("Could not write `{}`: {}", dest_path.display(), err)));
+}
@@ -0,0 +1,488 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tiny_keccak::{Hasher, Keccak};
+
+/// The namespace of the Ethereum Address Manager, whose delegated (f410)
+/// addresses wrap an Ethereum address.
+pub const EAM_NAMESPACE: u64 = 10;
+
+const CHECKSUM_LEN: usize = 4;
+const MAX_SUBADDRESS_LEN: usize = 54;
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+  /// `f` addresses.
+  Mainnet,
+  /// `t` addresses.
+  Testnet,
+}
+
+impl Network {
+  fn prefix(self) -> char {
+    match self {
+      Network::Mainnet => 'f',
+      Network::Testnet => 't',
+    }
+  }
+}
+
+/// What a Filecoin address holds, by protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Payload {
+  /// `f0`: an actor id.
+  Id(u64),
+  /// `f1`: the hash of a secp256k1 public key.
+  Secp256k1([u8; 20]),
+  /// `f2`: the hash of an actor's creation.
+  Actor([u8; 20]),
+  /// `f3`: a BLS public key.
+  Bls(Vec<u8>),
+  /// `f4`: an address assigned by the actor with id `namespace`.
+  Delegated { namespace: u64, subaddress: Vec<u8> },
+}
+
+impl Payload {
+  fn protocol(&self) -> u8 {
+    match self {
+      Payload::Id(_) => 0,
+      Payload::Secp256k1(_) => 1,
+      Payload::Actor(_) => 2,
+      Payload::Bls(_) => 3,
+      Payload::Delegated{ .. } => 4,
+    }
+  }
+
+  /// The bytes the checksum is taken over, after the protocol byte.
+  fn bytes(&self) -> Vec<u8> {
+    match self {
+      Payload::Id(id) => leb128(*id),
+      Payload::Secp256k1(hash) | Payload::Actor(hash) => hash.to_vec(),
+      Payload::Bls(key) => key.clone(),
+      Payload::Delegated{ namespace, subaddress } => {
+        let mut bytes = leb128(*namespace);
+        bytes.extend_from_slice(subaddress);
+        bytes
+      },
+    }
+  }
+}
+
+/// A Filecoin address in any of its protocols, or an Ethereum address.
+/// Parsing checks the checksum of every form that has one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+  Filecoin { network: Network, payload: Payload },
+  Ethereum([u8; 20]),
+}
+
+impl Address {
+  /// The `0x` form of an Ethereum address or of an f410 address wrapping
+  /// one, `None` for every other address.
+  pub fn to_ethereum(&self) -> Option<Address> {
+    match self {
+      Address::Ethereum(_) => Some(self.clone()),
+      Address::Filecoin{ payload: Payload::Delegated{ namespace: EAM_NAMESPACE, subaddress }, .. } => {
+        subaddress.as_slice().try_into().ok().map(Address::Ethereum)
+      },
+      Address::Filecoin{ .. } => None,
+    }
+  }
+
+  /// The f410 form of an Ethereum address on `network`. Filecoin addresses
+  /// are returned as they are.
+  pub fn to_filecoin(&self, network: Network) -> Address {
+    match self {
+      Address::Ethereum(bytes) => Address::Filecoin{
+        network,
+        payload: Payload::Delegated{ namespace: EAM_NAMESPACE, subaddress: bytes.to_vec() },
+      },
+      Address::Filecoin{ .. } => self.clone(),
+    }
+  }
+
+  /// The one form all the addresses of an account share: on mainnet, with
+  /// an Ethereum address as its f410 form.
+  pub fn canonical(&self) -> Address {
+    match self {
+      Address::Ethereum(_) => self.to_filecoin(Network::Mainnet),
+      Address::Filecoin{ payload, .. } => Address::Filecoin{ network: Network::Mainnet, payload: payload.clone() },
+    }
+  }
+
+  /// The binary form actors and messages use: the protocol byte followed
+  /// by the payload. An Ethereum address is written as its f410 form.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    match self.canonical() {
+      Address::Filecoin{ payload, .. } => {
+        let mut bytes = vec![payload.protocol()];
+        bytes.extend(payload.bytes());
+        bytes
+      },
+      Address::Ethereum(_) => unreachable!("canonical addresses are Filecoin addresses"),
+    }
+  }
+
+  /// Reads the binary form back, as a mainnet address.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Address, String> {
+    let (protocol, rest) = bytes.split_first().ok_or("An address can't be empty")?;
+    let payload = match protocol {
+      0 => match read_leb128(rest) {
+        Some((id, [])) => Payload::Id(id),
+        _ => return Err("Invalid id address".to_string()),
+      },
+      1 | 2 => {
+        let hash: [u8; 20] = rest
+          .try_into()
+          .map_err(|_| format!("A protocol {} address has a {}-byte payload", protocol, rest.len()))?;
+        if *protocol == 1 { Payload::Secp256k1(hash) } else { Payload::Actor(hash) }
+      },
+      3 if rest.len() == 48 => Payload::Bls(rest.to_vec()),
+      3 => return Err(format!("A BLS address has a {}-byte payload", rest.len())),
+      4 => match read_leb128(rest) {
+        Some((namespace, subaddress)) if subaddress.len() <= MAX_SUBADDRESS_LEN => {
+          Payload::Delegated{ namespace, subaddress: subaddress.to_vec() }
+        },
+        _ => return Err("Invalid delegated address".to_string()),
+      },
+      protocol => return Err(format!("Unknown address protocol {}", protocol)),
+    };
+    Ok(Address::Filecoin{ network: Network::Mainnet, payload })
+  }
+
+  /// Whether both name the same account: the same payload on either
+  /// network, taking an Ethereum address and its f410 form as the same.
+  pub fn same_account(&self, other: &Address) -> bool {
+    self.canonical() == other.canonical()
+  }
+}
+
+impl FromStr for Address {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    if let Some(hex) = s.strip_prefix("0x") {
+      return parse_ethereum(s, hex);
+    }
+
+    let mut chars = s.chars();
+    let network = match chars.next() {
+      Some('f') => Network::Mainnet,
+      Some('t') => Network::Testnet,
+      _ => return Err(format!("`{}` is not an address: expected `f`, `t` or `0x`", s)),
+    };
+    let protocol = chars.next();
+    let rest = chars.as_str();
+
+    let payload = match protocol {
+      Some('0') => {
+        let id = rest.parse::<u64>().ok().filter(|id| id.to_string() == rest);
+        Payload::Id(id.ok_or_else(|| format!("`{}` is not an id address", s))?)
+      },
+      Some(protocol @ '1'..='3') => {
+        let payload = decode_checked(s, protocol as u8 - b'0', &[], rest)?;
+        match (protocol, payload.len()) {
+          ('1', 20) => Payload::Secp256k1(payload.try_into().unwrap()),
+          ('2', 20) => Payload::Actor(payload.try_into().unwrap()),
+          ('3', 48) => Payload::Bls(payload),
+          (_, len) => return Err(format!("`{}` has a {}-byte payload", s, len)),
+        }
+      },
+      Some('4') => {
+        let (namespace, encoded) = rest
+          .split_once('f')
+          .ok_or_else(|| format!("`{}` is missing the `f` after its namespace", s))?;
+        let namespace = namespace
+          .parse::<u64>()
+          .ok()
+          .filter(|n| n.to_string() == namespace)
+          .ok_or_else(|| format!("`{}` has an invalid namespace", s))?;
+        let subaddress = decode_checked(s, 4, &leb128(namespace), encoded)?;
+        if subaddress.len() > MAX_SUBADDRESS_LEN {
+          return Err(format!("`{}` has a {}-byte subaddress", s, subaddress.len()));
+        }
+        Payload::Delegated{ namespace, subaddress }
+      },
+      _ => return Err(format!("`{}` has an unknown protocol", s)),
+    };
+    Ok(Address::Filecoin{ network, payload })
+  }
+}
+
+/// Addresses serialize as their string form, e.g. `"f01234"`.
+impl Serialize for Address {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Address {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+  }
+}
+
+impl fmt::Display for Address {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Address::Ethereum(bytes) => write!(f, "0x{}", eip55(bytes)),
+      Address::Filecoin{ network, payload } => {
+        write!(f, "{}{}", network.prefix(), payload.protocol())?;
+        let mut checked = match payload {
+          Payload::Id(id) => return write!(f, "{}", id),
+          Payload::Delegated{ namespace, subaddress } => {
+            write!(f, "{}f", namespace)?;
+            subaddress.clone()
+          },
+          payload => payload.bytes(),
+        };
+        checked.extend(checksum(payload.protocol(), &payload.bytes()));
+        write!(f, "{}", base32_encode(&checked))
+      },
+    }
+  }
+}
+
+fn parse_ethereum(s: &str, hex: &str) -> Result<Address, String> {
+  if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+    return Err(format!("`{}` is not an Ethereum address: expected 40 hex digits", s));
+  }
+  let mut bytes = [0; 20];
+  for (i, byte) in bytes.iter_mut().enumerate() {
+    *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).unwrap();
+  }
+  // All lower or all upper case carries no checksum.
+  let mixed = hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase());
+  if mixed && eip55(&bytes) != hex {
+    return Err(format!("`{}` has an invalid EIP-55 checksum", s));
+  }
+  Ok(Address::Ethereum(bytes))
+}
+
+/// Hex with the letters upper-cased wherever the matching nibble of the
+/// Keccak-256 hash of the lower-case hex is 8 or more.
+fn eip55(bytes: &[u8; 20]) -> String {
+  let lower: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+  let mut hash = [0; 32];
+  let mut keccak = Keccak::v256();
+  keccak.update(lower.as_bytes());
+  keccak.finalize(&mut hash);
+
+  lower
+    .chars()
+    .enumerate()
+    .map(|(i, c)| {
+      let nibble = (hash[i / 2] >> if i % 2 == 0 { 4 } else { 0 }) & 0xf;
+      if nibble >= 8 { c.to_ascii_uppercase() } else { c }
+    })
+    .collect()
+}
+
+/// Decodes the base32 part of an address and checks and strips its
+/// trailing checksum.
+fn decode_checked(s: &str, protocol: u8, prefix: &[u8], encoded: &str) -> Result<Vec<u8>, String> {
+  let mut bytes = base32_decode(encoded).ok_or_else(|| format!("`{}` is not valid base32", s))?;
+  if bytes.len() < CHECKSUM_LEN {
+    return Err(format!("`{}` is too short", s));
+  }
+  let found = bytes.split_off(bytes.len() - CHECKSUM_LEN);
+  let mut checked = prefix.to_vec();
+  checked.extend_from_slice(&bytes);
+  if checksum(protocol, &checked) != found {
+    return Err(format!("`{}` has an invalid checksum", s));
+  }
+  Ok(bytes)
+}
+
+/// A 4-byte Blake2b hash of the protocol byte and the payload.
+fn checksum(protocol: u8, payload: &[u8]) -> Vec<u8> {
+  blake2b_simd::Params::new()
+    .hash_length(CHECKSUM_LEN)
+    .to_state()
+    .update(&[protocol])
+    .update(payload)
+    .finalize()
+    .as_bytes()
+    .to_vec()
+}
+
+fn leb128(mut n: u64) -> Vec<u8> {
+  let mut bytes = Vec::new();
+  loop {
+    let byte = (n & 0x7f) as u8;
+    n >>= 7;
+    if n == 0 {
+      bytes.push(byte);
+      return bytes;
+    }
+    bytes.push(byte | 0x80);
+  }
+}
+
+/// An unsigned LEB128 number off the front of `bytes`, in its shortest
+/// form, and the bytes after it.
+fn read_leb128(bytes: &[u8]) -> Option<(u64, &[u8])> {
+  let mut n: u64 = 0;
+  for (i, byte) in bytes.iter().enumerate().take(10) {
+    n |= u64::from(byte & 0x7f).checked_shl(7 * i as u32)?;
+    if byte & 0x80 == 0 {
+      let rest = &bytes[i + 1..];
+      return (leb128(n) == bytes[..=i]).then_some((n, rest));
+    }
+  }
+  None
+}
+
+/// Lower-case RFC 4648 base32 without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+  let mut s = String::new();
+  let (mut buffer, mut bits) = (0u16, 0);
+  for byte in bytes {
+    buffer = buffer << 8 | *byte as u16;
+    bits += 8;
+    while bits >= 5 {
+      bits -= 5;
+      s.push(BASE32[(buffer >> bits) as usize & 0x1f] as char);
+    }
+  }
+  if bits > 0 {
+    s.push(BASE32[(buffer << (5 - bits)) as usize & 0x1f] as char);
+  }
+  s
+}
+
+/// The inverse of `base32_encode`, rejecting leftover bits that aren't zero
+/// so that every byte string has exactly one encoding.
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+  let mut bytes = Vec::new();
+  let (mut buffer, mut bits) = (0u16, 0);
+  for c in s.bytes() {
+    let value = BASE32.iter().position(|b| *b == c)? as u16;
+    buffer = (buffer << 5 | value) & 0x1fff;
+    bits += 5;
+    if bits >= 8 {
+      bits -= 8;
+      bytes.push((buffer >> bits) as u8);
+    }
+  }
+  (bits < 5 && buffer & ((1 << bits) - 1) == 0).then_some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn parse(s: &str) -> Result<Address, String> {
+    s.parse()
+  }
+
+  #[test]
+  fn test_round_trip() {
+    for s in [
+      "f01234",
+      "t00",
+      "f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy",
+      "f24vg6ut43yw2h2jqydgbg2xq7x6f4kub3bg6as6i",
+      "f3vvmn62lofvhjd2ugzca6sof2j2ubwok6cj4xxbfzz4yuxfkgobpihhd2thlanmsh3w2ptld2gqkn2jvlss4a",
+      "f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy",
+      "t410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy",
+      "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+      "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+    ] {
+      assert_eq!(parse(s).map(|a| a.to_string()), Ok(s.to_string()));
+    }
+
+    // Unchecksummed Ethereum addresses are accepted and displayed with
+    // their checksum.
+    assert_eq!(
+      parse("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap().to_string(),
+      "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+    );
+  }
+
+  #[test]
+  fn test_bytes() {
+    // The go-address test vectors.
+    let address = parse("f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy").unwrap();
+    let mut bytes = vec![0x01];
+    bytes.extend([
+      0xfd, 0x1d, 0x0f, 0x4d, 0xfc, 0xd7, 0xe9, 0x9a, 0xfc, 0xb9,
+      0x9a, 0x83, 0x26, 0xb7, 0xdc, 0x45, 0x9d, 0x32, 0xc6, 0x28,
+    ]);
+    assert_eq!(address.to_bytes(), bytes);
+    assert_eq!(Address::from_bytes(&bytes), Ok(address));
+    assert_eq!(parse("f01234").unwrap().to_bytes(), vec![0x00, 0xd2, 0x09]);
+
+    for s in [
+      "f00",
+      "f24vg6ut43yw2h2jqydgbg2xq7x6f4kub3bg6as6i",
+      "f3vvmn62lofvhjd2ugzca6sof2j2ubwok6cj4xxbfzz4yuxfkgobpihhd2thlanmsh3w2ptld2gqkn2jvlss4a",
+      "f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy",
+    ] {
+      let address = parse(s).unwrap();
+      assert_eq!(Address::from_bytes(&address.to_bytes()), Ok(address));
+    }
+    // Testnet addresses and Ethereum addresses come back in their canonical
+    // form.
+    let ethereum = parse("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed").unwrap();
+    assert_eq!(Address::from_bytes(&ethereum.to_bytes()), Ok(ethereum.canonical()));
+
+    assert!(Address::from_bytes(&[]).is_err());
+    assert!(Address::from_bytes(&[0x00, 0x80]).is_err());
+    assert!(Address::from_bytes(&[0x00, 0x80, 0x00]).is_err());
+    assert!(Address::from_bytes(&[0x01, 0x00]).is_err());
+    assert!(Address::from_bytes(&[0x05]).is_err());
+  }
+
+  #[test]
+  fn test_payloads() {
+    assert_eq!(parse("f01234"), Ok(Address::Filecoin{ network: Network::Mainnet, payload: Payload::Id(1234) }));
+    match parse("f3vvmn62lofvhjd2ugzca6sof2j2ubwok6cj4xxbfzz4yuxfkgobpihhd2thlanmsh3w2ptld2gqkn2jvlss4a") {
+      Ok(Address::Filecoin{ payload: Payload::Bls(key), .. }) => assert_eq!(key.len(), 48),
+      a => panic!("Expected a BLS address, got {:?}", a),
+    }
+  }
+
+  #[test]
+  fn test_ethereum_conversion() {
+    let eth = parse("0xd388aB098ed3E84c0D808776440B48F685198498").unwrap();
+    let f410 = parse("f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy").unwrap();
+    let t410 = parse("t410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy").unwrap();
+
+    assert_eq!(eth.to_filecoin(Network::Mainnet), f410);
+    assert_eq!(eth.to_filecoin(Network::Testnet), t410);
+    assert_eq!(f410.to_ethereum(), Some(eth.clone()));
+    assert_eq!(t410.to_ethereum(), Some(eth.clone()));
+    assert!(eth.same_account(&f410));
+    assert!(t410.same_account(&f410));
+
+    let f1 = parse("f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy").unwrap();
+    assert_eq!(f1.to_ethereum(), None);
+    assert_eq!(f1.to_filecoin(Network::Testnet), f1);
+    assert!(!f1.same_account(&eth));
+    assert!(f1.same_account(&parse("t17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy").unwrap()));
+    assert!(!f1.same_account(&parse("f24vg6ut43yw2h2jqydgbg2xq7x6f4kub3bg6as6i").unwrap()));
+
+    assert_eq!(eth.canonical(), f410);
+    assert_eq!(t410.canonical(), f410);
+    assert_eq!(parse("t01234").unwrap().canonical(), parse("f01234").unwrap());
+  }
+
+  #[test]
+  fn test_errors() {
+    assert_eq!(parse("f17uoq6tp427uzv7fztkbsnn64iwotfrristwqryy"), Err("`f17uoq6tp427uzv7fztkbsnn64iwotfrristwqryy` has an invalid checksum".to_string()));
+    assert_eq!(parse("f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx3gmy"), Err("`f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx3gmy` has an invalid checksum".to_string()));
+    assert_eq!(parse("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD"), Err("`0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD` has an invalid EIP-55 checksum".to_string()));
+    assert_eq!(parse("0x5aAeb6053F3E94C9"), Err("`0x5aAeb6053F3E94C9` is not an Ethereum address: expected 40 hex digits".to_string()));
+    assert_eq!(parse("f1Uoq6tp427uzv7fztkbsnn64iwotfrristwpryy"), Err("`f1Uoq6tp427uzv7fztkbsnn64iwotfrristwpryy` is not valid base32".to_string()));
+    assert_eq!(parse("f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryz"), Err("`f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryz` is not valid base32".to_string()));
+    assert_eq!(parse("f1aaaa"), Err("`f1aaaa` is too short".to_string()));
+    assert_eq!(parse("f5abc"), Err("`f5abc` has an unknown protocol".to_string()));
+    assert_eq!(parse("f0012"), Err("`f0012` is not an id address".to_string()));
+    assert_eq!(parse("f410abc"), Err("`f410abc` is missing the `f` after its namespace".to_string()));
+    assert_eq!(parse("f4x0f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy"), Err("`f4x0f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy` has an invalid namespace".to_string()));
+    assert_eq!(parse("x1abc"), Err("`x1abc` is not an address: expected `f`, `t` or `0x`".to_string()));
+  }
+}
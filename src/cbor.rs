@@ -0,0 +1,293 @@
+use cid::Cid;
+
+use crate::error::ContractError;
+
+// Major types, see RFC 8949 section 3.1.
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+/// DAG-CBOR tag for CIDs.
+const CID_TAG: u64 = 42;
+
+fn error(message: String) -> ContractError {
+  ContractError::Cbor(message)
+}
+
+/// Writes DAG-CBOR: definite lengths and the shortest header for every
+/// value, which is all the market actor's `DealProposal` tuple needs.
+#[derive(Debug, Default)]
+pub(crate) struct Encoder {
+  buf: Vec<u8>,
+}
+
+impl Encoder {
+  pub(crate) fn new() -> Self {
+    Self::default()
+  }
+
+  fn header(&mut self, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+      self.buf.push(major | n as u8);
+    } else if n <= u8::MAX as u64 {
+      self.buf.push(major | 24);
+      self.buf.push(n as u8);
+    } else if n <= u16::MAX as u64 {
+      self.buf.push(major | 25);
+      self.buf.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= u32::MAX as u64 {
+      self.buf.push(major | 26);
+      self.buf.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+      self.buf.push(major | 27);
+      self.buf.extend_from_slice(&n.to_be_bytes());
+    }
+  }
+
+  pub(crate) fn array(&mut self, len: usize) -> &mut Self {
+    self.header(ARRAY, len as u64);
+    self
+  }
+
+  pub(crate) fn uint(&mut self, n: u64) -> &mut Self {
+    self.header(UNSIGNED, n);
+    self
+  }
+
+  pub(crate) fn int(&mut self, n: i64) -> &mut Self {
+    if n < 0 {
+      self.header(NEGATIVE, !(n as u64));
+    } else {
+      self.header(UNSIGNED, n as u64);
+    }
+    self
+  }
+
+  pub(crate) fn bool(&mut self, b: bool) -> &mut Self {
+    self.buf.push(SIMPLE << 5 | if b { 21 } else { 20 });
+    self
+  }
+
+  pub(crate) fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+    self.header(BYTES, bytes.len() as u64);
+    self.buf.extend_from_slice(bytes);
+    self
+  }
+
+  pub(crate) fn text(&mut self, s: &str) -> &mut Self {
+    self.header(TEXT, s.len() as u64);
+    self.buf.extend_from_slice(s.as_bytes());
+    self
+  }
+
+  /// A CID as tag 42 over its binary form, behind the identity multibase
+  /// prefix.
+  pub(crate) fn cid(&mut self, cid: &Cid) -> &mut Self {
+    let mut bytes = vec![0];
+    bytes.extend(cid.to_bytes());
+    self.header(TAG, CID_TAG);
+    self.bytes(&bytes)
+  }
+
+  /// A non-negative big integer the way Filecoin serializes token amounts:
+  /// a sign byte followed by the big-endian magnitude, or nothing for zero.
+  pub(crate) fn big_uint(&mut self, n: u128) -> &mut Self {
+    if n == 0 {
+      return self.bytes(&[]);
+    }
+    let magnitude = n.to_be_bytes();
+    let first = magnitude.iter().position(|b| *b != 0).unwrap_or(magnitude.len());
+    let mut bytes = vec![0];
+    bytes.extend_from_slice(&magnitude[first..]);
+    self.bytes(&bytes)
+  }
+
+  pub(crate) fn into_bytes(self) -> Vec<u8> {
+    self.buf
+  }
+}
+
+/// Reads back what `Encoder` writes, rejecting anything that isn't in the
+/// shortest form.
+pub(crate) struct Decoder<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+  pub(crate) fn new(bytes: &'a [u8]) -> Self {
+    Self { bytes, pos: 0 }
+  }
+
+  fn take(&mut self, n: usize) -> Result<&'a [u8], ContractError> {
+    if self.bytes.len() - self.pos < n {
+      return Err(error(format!("Unexpected end of input at byte {}", self.pos)));
+    }
+    let slice = &self.bytes[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(slice)
+  }
+
+  fn header(&mut self, expected: u8) -> Result<u64, ContractError> {
+    let start = self.pos;
+    let initial = self.take(1)?[0];
+    let (major, info) = (initial >> 5, initial & 0x1f);
+    if major != expected {
+      return Err(error(format!(
+        "Expected major type {} at byte {}, found {}", expected, start, major
+      )));
+    }
+    let (n, min) = match info {
+      0..=23 => return Ok(info as u64),
+      24 => (self.take(1)?[0] as u64, 24),
+      25 => (u16::from_be_bytes(self.take(2)?.try_into().unwrap()) as u64, u8::MAX as u64 + 1),
+      26 => (u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as u64, u16::MAX as u64 + 1),
+      27 => (u64::from_be_bytes(self.take(8)?.try_into().unwrap()), u32::MAX as u64 + 1),
+      _ => return Err(error(format!("Unsupported additional info {} at byte {}", info, start))),
+    };
+    if n < min {
+      return Err(error(format!("Non-canonical integer encoding at byte {}", start)));
+    }
+    Ok(n)
+  }
+
+  pub(crate) fn array(&mut self, len: usize) -> Result<(), ContractError> {
+    let start = self.pos;
+    let found = self.header(ARRAY)?;
+    if found != len as u64 {
+      return Err(error(format!(
+        "Expected an array of {} elements at byte {}, found {}", len, start, found
+      )));
+    }
+    Ok(())
+  }
+
+  pub(crate) fn uint(&mut self) -> Result<u64, ContractError> {
+    self.header(UNSIGNED)
+  }
+
+  pub(crate) fn int(&mut self) -> Result<i64, ContractError> {
+    let start = self.pos;
+    let negative = self.bytes.get(start).is_some_and(|b| b >> 5 == NEGATIVE);
+    let n = if negative { self.header(NEGATIVE)? } else { self.header(UNSIGNED)? };
+    if n > i64::MAX as u64 {
+      return Err(error(format!("Integer at byte {} overflows i64", start)));
+    }
+    Ok(if negative { !(n as i64) } else { n as i64 })
+  }
+
+  pub(crate) fn bool(&mut self) -> Result<bool, ContractError> {
+    let start = self.pos;
+    match self.take(1)?[0] {
+      0xf4 => Ok(false),
+      0xf5 => Ok(true),
+      b => Err(error(format!("Expected a bool at byte {}, found {:#04x}", start, b))),
+    }
+  }
+
+  pub(crate) fn bytes(&mut self) -> Result<&'a [u8], ContractError> {
+    let len = self.header(BYTES)?;
+    self.take(len as usize)
+  }
+
+  pub(crate) fn text(&mut self) -> Result<String, ContractError> {
+    let start = self.pos;
+    let len = self.header(TEXT)?;
+    let bytes = self.take(len as usize)?;
+    String::from_utf8(bytes.to_vec())
+      .map_err(|_| error(format!("Invalid UTF-8 in text string at byte {}", start)))
+  }
+
+  pub(crate) fn cid(&mut self) -> Result<Cid, ContractError> {
+    let start = self.pos;
+    let tag = self.header(TAG)?;
+    if tag != CID_TAG {
+      return Err(error(format!("Expected tag {} at byte {}, found {}", CID_TAG, start, tag)));
+    }
+    match self.bytes()? {
+      [0, cid @ ..] => Cid::try_from(cid)
+        .map_err(|err| error(format!("Invalid CID at byte {}: {}", start, err))),
+      _ => Err(error(format!("Missing multibase prefix in CID at byte {}", start))),
+    }
+  }
+
+  pub(crate) fn big_uint(&mut self) -> Result<u128, ContractError> {
+    let start = self.pos;
+    match self.bytes()? {
+      [] => Ok(0),
+      [0, magnitude @ ..] if !magnitude.is_empty() && magnitude[0] != 0 && magnitude.len() <= 16 => {
+        Ok(magnitude.iter().fold(0, |n, b| n << 8 | *b as u128))
+      },
+      [1, ..] => Err(error(format!("Negative amount at byte {}", start))),
+      _ => Err(error(format!("Malformed big integer at byte {}", start))),
+    }
+  }
+
+  /// Fails if anything is left after the decoded value.
+  pub(crate) fn finish(self) -> Result<(), ContractError> {
+    if self.pos != self.bytes.len() {
+      return Err(error(format!("{} trailing bytes", self.bytes.len() - self.pos)));
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn encode(f: impl FnOnce(&mut Encoder)) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    f(&mut encoder);
+    encoder.into_bytes()
+  }
+
+  #[test]
+  fn test_integers() {
+    assert_eq!(encode(|e| { e.uint(0); }), vec![0x00]);
+    assert_eq!(encode(|e| { e.uint(23); }), vec![0x17]);
+    assert_eq!(encode(|e| { e.uint(24); }), vec![0x18, 0x18]);
+    assert_eq!(encode(|e| { e.uint(1000); }), vec![0x19, 0x03, 0xe8]);
+    assert_eq!(encode(|e| { e.uint(1_000_000); }), vec![0x1a, 0x00, 0x0f, 0x42, 0x40]);
+    assert_eq!(encode(|e| { e.int(-1); }), vec![0x20]);
+    assert_eq!(encode(|e| { e.int(-1000); }), vec![0x39, 0x03, 0xe7]);
+
+    let bytes = encode(|e| { e.array(3).int(-1000).uint(1 << 40).int(7); });
+    let mut decoder = Decoder::new(&bytes);
+    decoder.array(3).unwrap();
+    assert_eq!(decoder.int(), Ok(-1000));
+    assert_eq!(decoder.uint(), Ok(1 << 40));
+    assert_eq!(decoder.int(), Ok(7));
+    assert_eq!(decoder.finish(), Ok(()));
+  }
+
+  #[test]
+  fn test_big_uint() {
+    assert_eq!(encode(|e| { e.big_uint(0); }), vec![0x40]);
+    assert_eq!(encode(|e| { e.big_uint(1); }), vec![0x42, 0x00, 0x01]);
+    assert_eq!(encode(|e| { e.big_uint(256); }), vec![0x43, 0x00, 0x01, 0x00]);
+
+    let bytes = encode(|e| { e.big_uint(u128::MAX); });
+    assert_eq!(Decoder::new(&bytes).big_uint(), Ok(u128::MAX));
+    assert!(Decoder::new(&[0x42, 0x01, 0x01]).big_uint().is_err());
+    assert!(Decoder::new(&[0x42, 0x00, 0x00]).big_uint().is_err());
+  }
+
+  #[test]
+  fn test_rejects_malformed_input() {
+    assert!(Decoder::new(&[0x18, 0x01]).uint().is_err());
+    assert!(Decoder::new(&[0x19]).uint().is_err());
+    assert!(Decoder::new(&[0x61, 0xff]).text().is_err());
+    assert!(Decoder::new(&[0x00]).bool().is_err());
+    assert!(Decoder::new(&[0x82]).array(3).is_err());
+
+    let mut decoder = Decoder::new(&[0x01, 0x02]);
+    assert_eq!(decoder.uint(), Ok(1));
+    assert_eq!(decoder.finish(), Err(ContractError::Cbor("1 trailing bytes".to_string())));
+  }
+}
@@ -0,0 +1,100 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where the engine reads the current chain epoch from, to expire clauses
+/// with a `timeout`.
+pub trait Clock: fmt::Debug + Send + Sync {
+  fn epoch(&self) -> u64;
+}
+
+/// Seconds between Filecoin epochs.
+pub const EPOCH_SECONDS: u64 = 30;
+
+/// The epoch of a Filecoin network from the system time, given when its
+/// genesis block was, in seconds since the Unix epoch.
+#[derive(Debug, Clone, Copy)]
+pub struct ChainClock {
+  genesis: u64,
+}
+
+impl ChainClock {
+  pub const fn new(genesis: u64) -> Self {
+    Self { genesis }
+  }
+
+  pub const fn mainnet() -> Self {
+    Self::new(1598306400)
+  }
+
+  /// The epoch at `time`, in seconds since the Unix epoch. Before genesis
+  /// it is epoch 0.
+  pub fn epoch_at(&self, time: u64) -> u64 {
+    time.saturating_sub(self.genesis) / EPOCH_SECONDS
+  }
+
+  /// The epoch at `time`, which is epoch 0 before the Unix epoch too.
+  pub fn epoch_of(&self, time: SystemTime) -> u64 {
+    self.epoch_at(time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs()))
+  }
+}
+
+impl Clock for ChainClock {
+  fn epoch(&self) -> u64 {
+    self.epoch_of(SystemTime::now())
+  }
+}
+
+/// A clock that only moves when told to, for tests and simulations. Clones
+/// share the same epoch, so a test can keep one to advance the engine's.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedClock {
+  epoch: Arc<AtomicU64>,
+}
+
+impl SimulatedClock {
+  pub fn new(epoch: u64) -> Self {
+    Self { epoch: Arc::new(AtomicU64::new(epoch)) }
+  }
+
+  pub fn set(&self, epoch: u64) {
+    self.epoch.store(epoch, Ordering::SeqCst);
+  }
+
+  pub fn advance(&self, epochs: u64) {
+    self.epoch.fetch_add(epochs, Ordering::SeqCst);
+  }
+}
+
+impl Clock for SimulatedClock {
+  fn epoch(&self) -> u64 {
+    self.epoch.load(Ordering::SeqCst)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+
+  #[test]
+  fn test_simulated_clock() {
+    let clock = SimulatedClock::new(10);
+    let shared = clock.clone();
+    shared.advance(5);
+    assert_eq!(clock.epoch(), 15);
+    shared.set(3);
+    assert_eq!(clock.epoch(), 3);
+  }
+
+  #[test]
+  fn test_chain_clock() {
+    let clock = ChainClock::mainnet();
+    assert_eq!(clock.epoch_at(0), 0);
+    assert_eq!(clock.epoch_at(1598306400 + 3 * EPOCH_SECONDS + 29), 3);
+    assert_eq!(clock.epoch_of(UNIX_EPOCH + Duration::from_secs(1598306400 + 100 * EPOCH_SECONDS)), 100);
+    assert_eq!(clock.epoch_of(UNIX_EPOCH - Duration::from_secs(1)), 0);
+  }
+}
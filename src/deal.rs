@@ -0,0 +1,292 @@
+use std::fmt;
+
+use cid::Cid;
+
+use crate::error::{ContractError, FieldError};
+use crate::expr::Expr;
+use crate::op::parse_piece_cid;
+
+/// Where a deal is in its lifecycle. A deal moves through these in order,
+/// one step at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DealStatus {
+  ProposalCreated,
+  Published,
+  Activated,
+  Terminated,
+}
+
+impl DealStatus {
+  /// The status a lifecycle event moves its deal to, `None` for any other
+  /// event.
+  pub fn from_event(name: &str) -> Option<Self> {
+    match name {
+      "DealProposalCreated" => Some(DealStatus::ProposalCreated),
+      "DealPublished" => Some(DealStatus::Published),
+      "DealActivated" => Some(DealStatus::Activated),
+      "DealTerminated" => Some(DealStatus::Terminated),
+      _ => None,
+    }
+  }
+
+  /// The status a deal must be in to move to this one.
+  fn previous(self) -> Option<DealStatus> {
+    match self {
+      DealStatus::ProposalCreated => None,
+      DealStatus::Published => Some(DealStatus::ProposalCreated),
+      DealStatus::Activated => Some(DealStatus::Published),
+      DealStatus::Terminated => Some(DealStatus::Activated),
+    }
+  }
+}
+
+impl fmt::Display for DealStatus {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      DealStatus::ProposalCreated => "created",
+      DealStatus::Published => "published",
+      DealStatus::Activated => "activated",
+      DealStatus::Terminated => "terminated",
+    };
+    write!(f, "{}", s)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Deal {
+  piece_cid: Option<Cid>,
+  deal_id: Option<u64>,
+  status: DealStatus,
+}
+
+impl fmt::Display for Deal {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (&self.piece_cid, self.deal_id) {
+      (_, Some(deal_id)) => write!(f, "{}", deal_id),
+      (Some(piece_cid), None) => write!(f, "{}", piece_cid),
+      (None, None) => write!(f, "?"),
+    }
+  }
+}
+
+/// Follows deals through their lifecycle events. A deal is known by its
+/// piece CID, its deal id or both; an event carrying both links the two, so
+/// later events may use either. Once a deal has an id, an event with an id
+/// finds it by that alone, so two deals of the same piece stay apart.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DealTracker {
+  deals: Vec<Deal>,
+}
+
+impl DealTracker {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// The deal with `deal_id`, or else one of `piece_cid` that has no id
+  /// yet. Without an id, the latest deal of the piece.
+  fn find(&self, piece_cid: Option<&Cid>, deal_id: Option<u64>) -> Option<usize> {
+    let of_piece = |deal: &Deal| piece_cid.is_some() && deal.piece_cid.as_ref() == piece_cid;
+    match deal_id {
+      Some(_) => self.deals.iter()
+        .position(|deal| deal.deal_id == deal_id)
+        .or_else(|| self.deals.iter().position(|deal| deal.deal_id.is_none() && of_piece(deal))),
+      None => self.deals.iter().rposition(of_piece),
+    }
+  }
+
+  pub fn status_by_piece_cid(&self, piece_cid: &Cid) -> Option<DealStatus> {
+    self.find(Some(piece_cid), None).map(|i| self.deals[i].status)
+  }
+
+  pub fn status_by_deal_id(&self, deal_id: u64) -> Option<DealStatus> {
+    self.find(None, Some(deal_id)).map(|i| self.deals[i].status)
+  }
+
+  /// Moves a deal to `status`, rejecting anything but the next step of its
+  /// lifecycle, including a repeat of the current one.
+  pub fn advance(
+    &mut self,
+    piece_cid: Option<Cid>,
+    deal_id: Option<u64>,
+    status: DealStatus,
+  ) -> Result<(), ContractError> {
+    let index = self.find(piece_cid.as_ref(), deal_id);
+    let current = index.map(|i| self.deals[i].status);
+    if current != status.previous() {
+      let deal = match index {
+        Some(i) => self.deals[i].to_string(),
+        None => Deal{ piece_cid, deal_id, status }.to_string(),
+      };
+      return Err(ContractError::IllegalTransition{ deal, from: current, to: status });
+    }
+
+    match index {
+      Some(i) => {
+        let deal = &mut self.deals[i];
+        deal.status = status;
+        deal.piece_cid = deal.piece_cid.or(piece_cid);
+        deal.deal_id = deal.deal_id.or(deal_id);
+      },
+      None => self.deals.push(Deal{ piece_cid, deal_id, status }),
+    }
+    Ok(())
+  }
+
+  /// Applies a lifecycle event, identified by its `piece_cid` and/or
+  /// `deal_id`, and returns the deal's new status. Other events are
+  /// ignored.
+  pub fn apply(&mut self, event: &Expr) -> Result<Option<DealStatus>, ContractError> {
+    let (name, args) = match event {
+      Expr::Event{ name, args } => (name, args),
+      _ => return Ok(None),
+    };
+    let status = match DealStatus::from_event(name) {
+      Some(status) => status,
+      None => return Ok(None),
+    };
+
+    let mut errors = Vec::new();
+    let piece_cid = match args.get("piece_cid") {
+      Some(Expr::QuotedString(s)) => match parse_piece_cid(s) {
+        Ok(cid) => Some(cid),
+        Err(reason) => {
+          errors.push(FieldError::Invalid{ field: "piece_cid".to_string(), reason });
+          None
+        },
+      },
+      Some(e) => {
+        errors.push(FieldError::WrongType{
+          field: "piece_cid".to_string(),
+          expected: "Expr::QuotedString",
+          found: e.variant_name(),
+        });
+        None
+      },
+      None => None,
+    };
+    let deal_id = match args.get("deal_id") {
+      Some(Expr::Integer(n)) => Some(*n as u64),
+      Some(e) => {
+        errors.push(FieldError::WrongType{
+          field: "deal_id".to_string(),
+          expected: "Expr::Integer",
+          found: e.variant_name(),
+        });
+        None
+      },
+      None => None,
+    };
+    if errors.is_empty() && piece_cid.is_none() && deal_id.is_none() {
+      errors.push(FieldError::Missing{ field: "piece_cid".to_string(), expected: "Expr::QuotedString" });
+      errors.push(FieldError::Missing{ field: "deal_id".to_string(), expected: "Expr::Integer" });
+    }
+    if !errors.is_empty() {
+      return Err(ContractError::InvalidArguments{ target: name.to_string(), errors });
+    }
+
+    self.advance(piece_cid, deal_id, status)?;
+    Ok(Some(status))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::expr::decode;
+
+  const PIECE_CID: &str = "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy";
+
+  fn event(name: &str, args: &str) -> Expr {
+    match decode(args).unwrap() {
+      Expr::Dict(args) => Expr::Event{ name: name.to_string(), args },
+      e => panic!("Expected a dict, got {:?}", e),
+    }
+  }
+
+  #[test]
+  fn test_lifecycle() {
+    let mut tracker = DealTracker::new();
+    let piece_cid = Cid::try_from(PIECE_CID).unwrap();
+
+    let created = event("DealProposalCreated", &format!(r#"{{ piece_cid: "{}" }}"#, PIECE_CID));
+    assert_eq!(tracker.apply(&created), Ok(Some(DealStatus::ProposalCreated)));
+    assert_eq!(tracker.status_by_piece_cid(&piece_cid), Some(DealStatus::ProposalCreated));
+
+    // Publishing links the deal id to the piece CID.
+    let published = event("DealPublished", &format!(r#"{{ piece_cid: "{}", deal_id: 42 }}"#, PIECE_CID));
+    assert_eq!(tracker.apply(&published), Ok(Some(DealStatus::Published)));
+    assert_eq!(tracker.status_by_deal_id(42), Some(DealStatus::Published));
+
+    assert_eq!(tracker.apply(&event("DealActivated", "{ deal_id: 42 }")), Ok(Some(DealStatus::Activated)));
+    assert_eq!(tracker.status_by_piece_cid(&piece_cid), Some(DealStatus::Activated));
+
+    let terminated = event("DealTerminated", &format!(r#"{{ piece_cid: "{}" }}"#, PIECE_CID));
+    assert_eq!(tracker.apply(&terminated), Ok(Some(DealStatus::Terminated)));
+    assert_eq!(tracker.status_by_deal_id(42), Some(DealStatus::Terminated));
+  }
+
+  #[test]
+  fn test_same_piece() {
+    let mut tracker = DealTracker::new();
+    let piece_cid = Cid::try_from(PIECE_CID).unwrap();
+    let published = |deal_id| event("DealPublished", &format!(r#"{{ piece_cid: "{}", deal_id: {} }}"#, PIECE_CID, deal_id));
+
+    tracker.advance(Some(piece_cid), None, DealStatus::ProposalCreated).unwrap();
+    assert_eq!(tracker.apply(&published(42)), Ok(Some(DealStatus::Published)));
+    // A second deal of the piece, known by its id, isn't taken for the first.
+    tracker.advance(None, Some(43), DealStatus::ProposalCreated).unwrap();
+    assert_eq!(tracker.apply(&published(43)), Ok(Some(DealStatus::Published)));
+    assert_eq!(tracker.apply(&event("DealActivated", "{ deal_id: 43 }")), Ok(Some(DealStatus::Activated)));
+    assert_eq!(tracker.status_by_deal_id(42), Some(DealStatus::Published));
+    assert_eq!(tracker.status_by_deal_id(43), Some(DealStatus::Activated));
+    assert_eq!(tracker.status_by_piece_cid(&piece_cid), Some(DealStatus::Activated));
+  }
+
+  #[test]
+  fn test_illegal_transitions() {
+    let mut tracker = DealTracker::new();
+
+    let err = tracker.apply(&event("DealActivated", "{ deal_id: 7 }")).unwrap_err();
+    assert_eq!(err, ContractError::IllegalTransition{
+      deal: "7".to_string(),
+      from: None,
+      to: DealStatus::Activated,
+    });
+    assert_eq!(err.to_string(), "Deal 7 cannot be activated before it is created");
+    assert_eq!(tracker.status_by_deal_id(7), None);
+
+    tracker.advance(None, Some(7), DealStatus::ProposalCreated).unwrap();
+    let err = tracker.apply(&event("DealProposalCreated", "{ deal_id: 7 }")).unwrap_err();
+    assert_eq!(err.to_string(), "Deal 7 is already created");
+
+    tracker.advance(None, Some(7), DealStatus::Published).unwrap();
+    let err = tracker.apply(&event("DealTerminated", "{ deal_id: 7 }")).unwrap_err();
+    assert_eq!(err.to_string(), "Deal 7 cannot go from published to terminated");
+
+    tracker.advance(None, Some(7), DealStatus::Activated).unwrap();
+    tracker.advance(None, Some(7), DealStatus::Terminated).unwrap();
+    for name in ["DealProposalCreated", "DealPublished", "DealActivated", "DealTerminated"] {
+      let err = tracker.apply(&event(name, "{ deal_id: 7 }")).unwrap_err();
+      assert_eq!(err.to_string(), "Deal 7 has already been terminated", "{}", name);
+    }
+    assert_eq!(tracker.status_by_deal_id(7), Some(DealStatus::Terminated));
+  }
+
+  #[test]
+  fn test_apply_errors() {
+    let mut tracker = DealTracker::new();
+    assert_eq!(tracker.apply(&event("Deposit", "{}")), Ok(None));
+
+    let err = tracker.apply(&event("DealPublished", r#"{ piece_cid: 1, deal_id: "2" }"#)).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `DealPublished`:
+  `piece_cid` should be Expr::QuotedString, found Expr::Integer
+  `deal_id` should be Expr::Integer, found Expr::QuotedString");
+
+    let err = tracker.apply(&event("DealPublished", "{}")).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `DealPublished`:
+  missing `piece_cid` (expected Expr::QuotedString)
+  missing `deal_id` (expected Expr::Integer)");
+    assert_eq!(tracker, DealTracker::new());
+  }
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+use std::ops::Range;
+
+use combine::easy::{Error, Errors, Info};
+use combine::stream::position::SourcePosition;
+
+/// A problem at a place in source text: the file if known, the 1-based line
+/// and column, and the byte span of what was found there. Renders as a
+/// caret-underlined snippet of the offending line, and as JSON for editors.
+///
+/// Kept free of anything else in the crate so that `build.rs` can include
+/// it as well.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+  pub file: Option<String>,
+  pub line: usize,
+  pub column: usize,
+  pub span: Range<usize>,
+  /// Messages from the parser itself, e.g. `Unknown escape sequence`.
+  pub messages: Vec<String>,
+  /// What would have been accepted instead, deduplicated.
+  pub expected: Vec<String>,
+  pub found: Option<String>,
+  /// The source line the error is on, without its line break.
+  snippet: String,
+}
+
+impl Diagnostic {
+  /// Locates a parse error from `easy_parse` over a `position::Stream` of
+  /// `source`.
+  pub fn from_parse_error(source: &str, errors: Errors<char, &str, SourcePosition>) -> Self {
+    let line = errors.position.line.max(1) as usize;
+    let column = errors.position.column.max(1) as usize;
+
+    let mut messages = Vec::new();
+    let mut expected = Vec::new();
+    let mut found = None;
+    let mut found_len = None;
+    let mut found_char = None;
+    for error in &errors.errors {
+      match error {
+        // Alternatives may disagree on how much they read; the longest
+        // reading, e.g. a whole keyword over its first letter, wins.
+        Error::Unexpected(info) => {
+          let len = match info {
+            Info::Token(c) => Some(c.len_utf8()),
+            Info::Range(r) => Some(r.len()),
+            _ => None,
+          };
+          if found.is_none() || len > found_len {
+            found = Some(describe(info));
+            found_len = len;
+            found_char = match info {
+              Info::Token(c) => Some(*c),
+              _ => None,
+            };
+          }
+        },
+        Error::Expected(info) => {
+          let info = describe(info);
+          if !expected.contains(&info) {
+            expected.push(info);
+          }
+        },
+        Error::Message(info) => messages.push(info.to_string()),
+        Error::Other(err) => messages.push(err.to_string()),
+      }
+    }
+
+    let line_start: usize = source.split_inclusive('\n').take(line - 1).map(str::len).sum();
+    let snippet = source[line_start.min(source.len())..]
+      .split('\n')
+      .next()
+      .unwrap_or("")
+      .trim_end_matches('\r');
+    let offset = snippet
+      .char_indices()
+      .nth(column - 1)
+      .map_or(snippet.len(), |(i, _)| i);
+    // Without a token to measure, underline the character at the position.
+    let mut len = found_len.unwrap_or_else(|| snippet[offset..].chars().next().map_or(0, char::len_utf8));
+    // A parser that stops at the first letter of a word still means the
+    // word, e.g. a keyword where a name should be.
+    if found_char.is_some_and(is_word) {
+      let word = snippet[offset..].split(|c: char| !is_word(c)).next().unwrap_or("");
+      if word.len() > len {
+        len = word.len();
+        found = Some(format!("`{}`", word));
+      }
+    }
+    let start = line_start + offset;
+
+    Diagnostic{
+      file: None,
+      line,
+      column,
+      span: start..start + len,
+      messages,
+      expected,
+      found,
+      snippet: snippet.to_string(),
+    }
+  }
+
+  pub fn with_file(mut self, file: impl Into<String>) -> Self {
+    self.file = Some(file.into());
+    self
+  }
+
+  /// The one-line summary: the parser's own messages if it has any,
+  /// otherwise what was expected and what was found.
+  pub fn summary(&self) -> String {
+    if self.messages.is_empty() {
+      self.expected_found()
+    } else {
+      self.messages.join("; ")
+    }
+  }
+
+  fn expected_found(&self) -> String {
+    let found = self.found.as_deref().unwrap_or("something else");
+    match self.expected.split_last() {
+      None => format!("unexpected {}", found),
+      Some((last, [])) => format!("expected {}, found {}", last, found),
+      Some((last, rest)) => format!("expected {} or {}, found {}", rest.join(", "), last, found),
+    }
+  }
+
+  /// The diagnostic as a JSON object, with the span as `start` and `end`
+  /// byte offsets.
+  pub fn to_json(&self) -> String {
+    let list = |items: &[String]| items.iter().map(|s| json_string(s)).collect::<Vec<_>>().join(",");
+    format!(
+      r#"{{"file":{},"line":{},"column":{},"span":{{"start":{},"end":{}}},"message":{},"messages":[{}],"expected":[{}],"found":{}}}"#,
+      self.file.as_deref().map_or("null".to_string(), json_string),
+      self.line,
+      self.column,
+      self.span.start,
+      self.span.end,
+      json_string(&self.summary()),
+      list(&self.messages),
+      list(&self.expected),
+      self.found.as_deref().map_or("null".to_string(), json_string),
+    )
+  }
+}
+
+fn is_word(c: char) -> bool {
+  c.is_alphanumeric() || c == '_'
+}
+
+/// Tokens and ranges are quoted, with line breaks and other control
+/// characters escaped; descriptions such as `digit` or `end of input` are
+/// not.
+fn describe(info: &Info<char, &str>) -> String {
+  let visible = |c: char| match c.is_control() {
+    true => c.escape_debug().to_string(),
+    false => c.to_string(),
+  };
+  match info {
+    Info::Token(c) => format!("`{}`", visible(*c)),
+    Info::Range(r) => format!("`{}`", r.chars().map(visible).collect::<String>()),
+    Info::Owned(s) => s.clone(),
+    Info::Static(s) => s.to_string(),
+  }
+}
+
+fn json_string(s: &str) -> String {
+  let mut json = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => json.push_str("\\\""),
+      '\\' => json.push_str("\\\\"),
+      '\n' => json.push_str("\\n"),
+      '\r' => json.push_str("\\r"),
+      '\t' => json.push_str("\\t"),
+      c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+      c => json.push(c),
+    }
+  }
+  json.push('"');
+  json
+}
+
+impl fmt::Display for Diagnostic {
+  /// ```text
+  /// error: expected `}`, found `x`
+  ///  --> contract.mt:3:14
+  ///   |
+  /// 3 |   when Foo { x
+  ///   |              ^
+  /// ```
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let gutter = " ".repeat(self.line.to_string().len());
+    writeln!(f, "error: {}", self.summary())?;
+    match &self.file {
+      Some(file) => writeln!(f, "{}--> {}:{}:{}", gutter, file, self.line, self.column)?,
+      None => writeln!(f, "{}--> {}:{}", gutter, self.line, self.column)?,
+    }
+    writeln!(f, "{} |", gutter)?;
+    writeln!(f, "{} | {}", self.line, self.snippet)?;
+
+    // Carets line up under the snippet character for character, tabs kept.
+    let indent: String = self.snippet
+      .chars()
+      .take(self.column - 1)
+      .map(|c| if c == '\t' { '\t' } else { ' ' })
+      .collect();
+    let offset = self.snippet.char_indices().nth(self.column - 1).map_or(self.snippet.len(), |(i, _)| i);
+    let width = self.snippet
+      .get(offset..(offset + self.span.len()).min(self.snippet.len()))
+      .map_or(0, |s| s.chars().count())
+      .max(1);
+    write!(f, "{} | {}{}", gutter, indent, "^".repeat(width))?;
+    if !self.messages.is_empty() && !self.expected.is_empty() {
+      write!(f, "\n{} = {}", gutter, self.expected_found())?;
+    }
+    Ok(())
+  }
+}
+
+impl std::error::Error for Diagnostic {}
+
+#[cfg(test)]
+mod tests {
+  use combine::parser::char::{char, letter, spaces, string};
+  use combine::stream::position;
+  use combine::{eof, many1, sep_by, EasyParser, Parser};
+
+  use super::*;
+
+  fn diagnose(source: &str) -> Diagnostic {
+    let words = sep_by::<Vec<String>, _, _, _>(many1(letter()).skip(spaces()), char(',').skip(spaces()));
+    let err = (string("when "), words, char(';'), eof())
+      .easy_parse(position::Stream::new(source))
+      .unwrap_err();
+    Diagnostic::from_parse_error(source, err)
+  }
+
+  #[test]
+  fn test_location() {
+    let d = diagnose("when a,\n  b, c 1");
+    assert_eq!((d.line, d.column), (2, 8));
+    assert_eq!(d.span, 15..16);
+    assert_eq!(d.found.as_deref(), Some("`1`"));
+    assert_eq!(d.summary(), "expected `,`, whitespaces or `;`, found `1`");
+    assert_eq!(d.to_string(), "\
+error: expected `,`, whitespaces or `;`, found `1`
+ --> 2:8
+  |
+2 |   b, c 1
+  |        ^");
+
+    let d = diagnose("when é,").with_file("contract.mt");
+    assert_eq!((d.line, d.column), (1, 8));
+    assert_eq!(d.span, 8..8);
+    assert_eq!(d.found.as_deref(), Some("end of input"));
+    assert!(d.to_string().contains(" --> contract.mt:1:8\n"), "{}", d);
+    assert!(d.to_string().ends_with("1 | when é,\n  |        ^"), "{}", d);
+  }
+
+  #[test]
+  fn test_messages() {
+    let source = "\tlet x";
+    let err = (spaces(), string("let"), spaces(), many1::<String, _, _>(letter()))
+      .then(|_| combine::unexpected_any::<_, _, ()>("keyword").message("`x` is reserved"))
+      .easy_parse(position::Stream::new(source))
+      .map(|_| ())
+      .unwrap_err();
+    let d = Diagnostic::from_parse_error(source, err);
+    assert_eq!(d.messages, vec!["`x` is reserved".to_string()]);
+    assert_eq!(d.summary(), "`x` is reserved");
+  }
+
+  #[test]
+  fn test_json() {
+    let d = diagnose("when \"").with_file("dir/a \"b\".mt");
+    assert_eq!(
+      d.to_json(),
+      r#"{"file":"dir/a \"b\".mt","line":1,"column":6,"span":{"start":5,"end":6},"message":"expected letter, whitespaces or `;`, found `\"`","messages":[],"expected":["letter","whitespaces","`;`"],"found":"`\"`"}"#
+    );
+  }
+}
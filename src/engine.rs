@@ -0,0 +1,945 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::clock::{Clock, SimulatedClock};
+use crate::deal::DealTracker;
+use crate::error::ContractError;
+use crate::eval::{eval, holds, same_account, Scope};
+use crate::expr::{Bindings, Continuation, Contract, EventOp, Expr, Op, Stmt, Timeout};
+use crate::ledger::{Ledger, ESCROW};
+use crate::op::{Effect, Fields, Token};
+use crate::schema::{check_event, choice_bounds};
+
+/// Runs a parsed contract by matching incoming events against its `when`
+/// clauses. Top-level clauses wait from the start, and a clause nested
+/// after a `then` or `else` once the clause before it has gone that way.
+/// Every clause fires at most once, either on its event or on its timeout;
+/// the contract is finished once none is waiting, which a `close` brings
+/// about straight away.
+#[derive(Debug)]
+pub struct Engine {
+  contract: Contract,
+  /// Every clause by number, without what it continues with, which
+  /// `links` has instead.
+  clauses: Vec<Stmt>,
+  /// Where each clause goes on to, by clause number.
+  links: Vec<Links>,
+  fired: Vec<bool>,
+  /// The epoch each clause started waiting at, which its timeout counts
+  /// from, or `None` if it hasn't yet.
+  since: Vec<Option<u64>>,
+  /// What the clauses leading up to each clause bound.
+  scopes: Vec<Bindings>,
+  closed: bool,
+  ledger: Ledger,
+  deals: DealTracker,
+  plan: Vec<Effect>,
+  clock: Arc<dyn Clock>,
+}
+
+/// What a clause continues with once it fires, and once it times out.
+#[derive(Debug, Clone, Copy, Default)]
+struct Links {
+  on_event: Option<Next>,
+  on_timeout: Option<Next>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Next {
+  Clause(usize),
+  Close,
+}
+
+/// A clause that fired, each op it ran with its evaluated argument, and the
+/// effects those ops produced, in order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fired {
+  pub clause: usize,
+  pub ops: Vec<(String, Option<Expr>)>,
+  pub effects: Vec<Effect>,
+}
+
+impl Engine {
+  /// An engine on a simulated clock stopped at epoch 0, so that its
+  /// clauses never time out.
+  pub fn new(contract: Contract) -> Self {
+    Self::with_clock(contract, Arc::new(SimulatedClock::new(0)))
+  }
+
+  /// An engine whose timeouts are counted in `clock`'s epochs from now.
+  pub fn with_clock(contract: Contract, clock: Arc<dyn Clock>) -> Self {
+    let mut links = Vec::new();
+    let mut top_level = Vec::new();
+    for stmt in &contract.stmts {
+      top_level.push(links.len());
+      link(stmt, &mut links);
+    }
+    let mut since = vec![None; links.len()];
+    let start = clock.epoch();
+    for clause in top_level {
+      since[clause] = Some(start);
+    }
+    let clauses = contract.clauses().into_iter().map(unlinked).collect();
+    Self {
+      clauses,
+      fired: vec![false; links.len()],
+      scopes: vec![Bindings::new(); links.len()],
+      closed: false,
+      since,
+      links,
+      contract,
+      ledger: Ledger::new(),
+      deals: DealTracker::new(),
+      plan: Vec::new(),
+      clock,
+    }
+  }
+
+  /// Feeds an incoming `Expr::Event` to the contract. The first pending
+  /// clause (in source order) whose event matches, and whose guard holds if
+  /// it has one, has its ops run in order, and its index is returned. A
+  /// guard that can't be evaluated for the event, e.g. one comparing a field
+  /// the event doesn't have, doesn't hold, and later clauses are tried. Op
+  /// arguments are evaluated first, against the event's fields and the
+  /// variables the clause and those leading up to it bound; a variable
+  /// bound earlier has to match the same value again. Whatever the clause
+  /// continues with then starts waiting. Returns `Ok(None)` if nothing
+  /// matched.
+  /// A clause past its timeout no longer matches, whether or not `tick`
+  /// has run its timeout branch yet.
+  ///
+  /// Deal lifecycle events must follow on from what the engine has already
+  /// seen of their deal; any other is rejected before clauses are matched.
+  /// So is an event of a type the contract declares without all of its
+  /// declared fields.
+  /// A `Deposit` credits the escrow before clauses are matched, whether or
+  /// not one matches, since the host has received the funds either way. If
+  /// any op fails, the engine is left untouched and the clause stays
+  /// pending.
+  pub fn handle(&mut self, event: &Expr) -> Result<Option<usize>, ContractError> {
+    Ok(self.fire(event)?.map(|fired| fired.clause))
+  }
+
+  /// `handle`, also returning the ops that ran and the arguments they were
+  /// given.
+  pub fn fire(&mut self, event: &Expr) -> Result<Option<Fired>, ContractError> {
+    let (name, args) = match event {
+      Expr::Event{ name, args } => (name, args),
+      _ => return Ok(None),
+    };
+
+    if let Some(declared) = self.contract.events.iter().find(|declared| declared.name == *name) {
+      check_event(declared, args)?;
+    }
+    let mut deals = self.deals.clone();
+    deals.apply(event)?;
+    let mut ledger = self.ledger.clone();
+    if name == "Deposit" {
+      let mut fields = Fields::new(args);
+      let token = fields.nested::<Token>("token")?;
+      let token = fields.finish("Deposit", token)?;
+      ledger.credit(ESCROW, &token.ticker, token.amount)?;
+    }
+
+    let mut matched = None;
+    for (i, (event_op, _)) in self.clauses.iter().enumerate() {
+      if !self.is_pending(i) || self.expired(i) {
+        continue;
+      }
+      let bindings = match bind_from(self.scopes[i].clone(), &event_op.event, event) {
+        Some(bindings) => bindings,
+        None => continue,
+      };
+      if let Some(guard) = &event_op.guard {
+        if !holds(guard, &Scope{ args, bindings: &bindings }).unwrap_or(false) {
+          continue;
+        }
+      }
+      matched = Some((i, bindings));
+      break;
+    }
+    let (index, bindings) = match matched {
+      Some(matched) => matched,
+      None => {
+        self.ledger = ledger;
+        self.deals = deals;
+        return Ok(None);
+      },
+    };
+
+    let (_, ops) = &self.clauses[index];
+    let fired = run(index, ops, &Scope{ args, bindings: &bindings }, &mut ledger)?;
+
+    self.ledger = ledger;
+    self.deals = deals;
+    self.fired[index] = true;
+    self.plan.extend(fired.effects.iter().cloned());
+    self.follow(self.links[index].on_event, bindings);
+    Ok(Some(fired))
+  }
+
+  /// Runs the timeout branch of every pending clause whose timeout has
+  /// passed on the clock, in source order, and returns them. Hosts call it
+  /// as the clock moves on. The branch's ops run as a clause's would,
+  /// without an event, so they only see what the clauses leading up to it
+  /// bound. It is all or nothing, as `fire` is: if any branch fails, the
+  /// engine is left as it was and every clause stays pending.
+  pub fn tick(&mut self) -> Result<Vec<Fired>, ContractError> {
+    let saved = (self.ledger.clone(), self.fired.clone(), self.since.clone(), self.scopes.clone(), self.closed);
+    let planned = self.plan.len();
+    let expired = self.expire();
+    if expired.is_err() {
+      (self.ledger, self.fired, self.since, self.scopes, self.closed) = saved;
+      self.plan.truncate(planned);
+    }
+    expired
+  }
+
+  fn expire(&mut self) -> Result<Vec<Fired>, ContractError> {
+    let mut expired = Vec::new();
+    for index in 0..self.fired.len() {
+      if !self.is_pending(index) || !self.expired(index) {
+        continue;
+      }
+      let ops = self.clauses[index].0.timeout_ops();
+      let fired = run(index, ops, &Scope{ args: &HashMap::new(), bindings: &self.scopes[index] }, &mut self.ledger)?;
+      self.fired[index] = true;
+      self.plan.extend(fired.effects.iter().cloned());
+      self.follow(self.links[index].on_timeout, self.scopes[index].clone());
+      expired.push(fired);
+    }
+    Ok(expired)
+  }
+
+  /// Starts whatever a clause that just fired goes on to, with what it
+  /// bound.
+  fn follow(&mut self, next: Option<Next>, bindings: Bindings) {
+    match next {
+      Some(Next::Clause(clause)) => {
+        self.since[clause] = Some(self.clock.epoch());
+        self.scopes[clause] = bindings;
+      },
+      Some(Next::Close) => self.closed = true,
+      None => {},
+    }
+  }
+
+  /// The epoch at which `clause` times out, if it has a timeout and has
+  /// started waiting.
+  pub fn deadline(&self, clause: usize) -> Option<u64> {
+    let (event_op, _) = self.clauses.get(clause)?;
+    let since = self.since[clause]?;
+    event_op.timeout.as_ref().map(|timeout| since.saturating_add(timeout.epochs))
+  }
+
+  fn expired(&self, clause: usize) -> bool {
+    self.deadline(clause).is_some_and(|deadline| self.clock.epoch() >= deadline)
+  }
+
+  /// The effects of every clause fired so far, in order: the plan the host
+  /// has yet to carry out.
+  pub fn plan(&self) -> &[Effect] {
+    &self.plan
+  }
+
+  /// Hands the plan over to the host, leaving it empty.
+  pub fn take_plan(&mut self) -> Vec<Effect> {
+    std::mem::take(&mut self.plan)
+  }
+
+  pub fn ledger(&self) -> &Ledger {
+    &self.ledger
+  }
+
+  pub fn deals(&self) -> &DealTracker {
+    &self.deals
+  }
+
+  pub fn has_fired(&self, clause: usize) -> bool {
+    self.fired.get(clause).copied().unwrap_or(false)
+  }
+
+  /// Indices of the clauses waiting for an event: those that have started
+  /// waiting and not fired, unless the contract was closed.
+  pub fn pending(&self) -> Vec<usize> {
+    (0..self.fired.len()).filter(|&i| self.is_pending(i)).collect()
+  }
+
+  fn is_pending(&self, clause: usize) -> bool {
+    !self.closed && !self.fired[clause] && self.since[clause].is_some()
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.pending().is_empty()
+  }
+}
+
+/// Numbers `stmt` and the clauses nested in it, in the order of
+/// `Contract::clauses`, recording where each goes on to.
+fn link(stmt: &Stmt, links: &mut Vec<Links>) {
+  let index = links.len();
+  links.push(Links::default());
+  let (event_op, _) = stmt;
+  let on_event = event_op.next.as_ref().map(|next| follow_link(next, links));
+  let on_timeout = event_op.timeout_next().map(|next| follow_link(next, links));
+  links[index] = Links{ on_event, on_timeout };
+}
+
+/// A copy of a clause without the clauses it continues with, so that a
+/// nested clause isn't copied again with every clause around it.
+fn unlinked((event_op, ops): &Stmt) -> Stmt {
+  let timeout = event_op.timeout.as_ref().map(|timeout| Timeout{
+    epochs: timeout.epochs,
+    ops: timeout.ops.clone(),
+    next: None,
+  });
+  let event_op = EventOp{
+    name: event_op.name.clone(),
+    event: event_op.event.clone(),
+    guard: event_op.guard.clone(),
+    timeout,
+    next: None,
+  };
+  (event_op, ops.clone())
+}
+
+fn follow_link(next: &Continuation, links: &mut Vec<Links>) -> Next {
+  match next {
+    Continuation::When(stmt) => {
+      let clause = links.len();
+      link(stmt, links);
+      Next::Clause(clause)
+    },
+    Continuation::Close => Next::Close,
+  }
+}
+
+/// Runs the ops of `clause` in order against `ledger`, their arguments
+/// evaluated in `scope`.
+fn run(clause: usize, ops: &[Op], scope: &Scope, ledger: &mut Ledger) -> Result<Fired, ContractError> {
+  let mut ran = Vec::new();
+  let mut effects = Vec::new();
+  for op in ops {
+    let arg = op.arg.as_ref().map(|arg| eval(arg, scope)).transpose()?;
+    effects.extend(op.handler.run(ledger, arg.clone())?);
+    ran.push((op.name.clone(), arg));
+  }
+  Ok(Fired{ clause, ops: ran, effects })
+}
+
+/// Whether `value` satisfies `pattern`. Events match on name and arguments,
+/// dicts match when every key of the pattern is present in the value and
+/// matches in turn, so an incoming event may carry more fields than the
+/// clause mentions. An address matches the same account written in any of
+/// its forms, including as a string, and a token amount matches a plain
+/// integer of as many attoFIL. A variable matches anything, but one that
+/// appears twice must match the same value both times. A `Choice` pattern
+/// with `bounds` only matches a `value` within one of them. Everything else
+/// is compared for equality.
+pub(crate) fn matches(pattern: &Expr, value: &Expr) -> bool {
+  bind(pattern, value).is_some()
+}
+
+/// Like `matches`, returning what the pattern's variables were bound to.
+pub(crate) fn bind(pattern: &Expr, value: &Expr) -> Option<Bindings> {
+  bind_from(Bindings::new(), pattern, value)
+}
+
+/// Like `bind`, with some variables already bound.
+fn bind_from(mut bindings: Bindings, pattern: &Expr, value: &Expr) -> Option<Bindings> {
+  bind_into(pattern, value, &mut bindings).then_some(bindings)
+}
+
+fn bind_into(pattern: &Expr, value: &Expr, bindings: &mut Bindings) -> bool {
+  match (pattern, value) {
+    (Expr::Var(name), value) => match bindings.get(name) {
+      Some(bound) => bound == value,
+      None => {
+        bindings.insert(name.clone(), value.clone());
+        true
+      },
+    },
+    // The `bounds` of a `Choice` aren't a field: the number chosen has to
+    // be within one of them.
+    (
+      Expr::Event{ name: pattern_name, args: pattern_args },
+      Expr::Event{ name, args },
+    ) => pattern_name == name && pattern_args.iter().all(|(key, p)| match (name.as_str(), key.as_str()) {
+      ("Choice", "bounds") => chosen_within(p, args.get("value")),
+      _ => args.get(key).is_some_and(|v| bind_into(p, v, bindings)),
+    }),
+    (Expr::Dict(pattern), Expr::Dict(value)) => bind_dict(pattern, value, bindings),
+    (Expr::Array(pattern), Expr::Array(value)) => {
+      pattern.len() == value.len()
+        && pattern.iter().zip(value).all(|(p, v)| bind_into(p, v, bindings))
+    },
+    (Expr::Pair(p1, p2), Expr::Pair(v1, v2)) => {
+      bind_into(p1, v1, bindings) && bind_into(p2, v2, bindings)
+    },
+    (Expr::Address(_), _) => same_account(pattern, value),
+    (Expr::TokenAmount(a), Expr::Integer(n)) | (Expr::Integer(n), Expr::TokenAmount(a)) => {
+      a.atto() == *n as u128
+    },
+    _ => pattern == value,
+  }
+}
+
+fn chosen_within(bounds: &Expr, value: Option<&Expr>) -> bool {
+  match (choice_bounds(bounds), value) {
+    (Ok(bounds), Some(Expr::Integer(n))) => bounds.iter().any(|&(low, high)| (low..=high).contains(n)),
+    _ => false,
+  }
+}
+
+fn bind_dict(
+  pattern: &HashMap<String, Expr>,
+  value: &HashMap<String, Expr>,
+  bindings: &mut Bindings,
+) -> bool {
+  pattern.iter().all(|(key, p)| value.get(key).is_some_and(|v| bind_into(p, v, bindings)))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashMap;
+
+  use super::*;
+  use crate::deal::DealStatus;
+  use crate::expr::{decode, parse_contract};
+  use crate::op::{DealRequest, TokenAmount};
+
+  fn event(name: &str, args: &str) -> Expr {
+    match decode(args).unwrap() {
+      Expr::Dict(args) => Expr::Event{ name: name.to_string(), args },
+      e => panic!("Expected a dict, got {:?}", e),
+    }
+  }
+
+  fn contract() -> Contract {
+    parse_contract(r#"
+    when Deposit {
+      from: "addressA",
+      token: {
+        ticker: "WRLD",
+        amount: 123
+      }
+    } then
+      pay {
+        to: "addressB",
+        token: {
+          name: "world",
+          ticker: "WRLD",
+          amount: 100
+        }
+      }
+    when DealActivated {
+      piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy"
+    }
+    "#).unwrap()
+  }
+
+  #[test]
+  fn test_matches() {
+    let pattern = decode(r#"{ from: "addressA", token: { amount: 123 } }"#).unwrap();
+    let value = decode(r#"{ from: "addressA", token: { ticker: "WRLD", amount: 123 } }"#).unwrap();
+    assert!(matches(&pattern, &value));
+    assert!(!matches(&value, &pattern));
+
+    let value = decode(r#"{ from: "addressA", token: { amount: 12 } }"#).unwrap();
+    assert!(!matches(&pattern, &value));
+
+    let pattern = event("Deposit", "{}");
+    assert!(matches(&pattern, &event("Deposit", r#"{ from: "addressA" }"#)));
+    assert!(!matches(&pattern, &event("Pay", r#"{ from: "addressA" }"#)));
+    assert!(matches(&Expr::Integer(1), &Expr::Integer(1)));
+    assert!(!matches(&Expr::Integer(1), &Expr::Decimal(1.0)));
+  }
+
+  #[test]
+  fn test_bind() {
+    let pattern = event("Deposit", r#"{ from: $sender, token: { ticker: "WRLD", amount: $amt } }"#);
+    let value = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 123 }
+    }"#);
+    let bindings = bind(&pattern, &value).unwrap();
+    assert_eq!(bindings.len(), 2);
+    assert_eq!(bindings["sender"], Expr::QuotedString("addressA".to_string()));
+    assert_eq!(bindings["amt"], Expr::Integer(123));
+
+    let value = event("Deposit", r#"{ from: "addressA", token: { ticker: "MARS", amount: 123 } }"#);
+    assert_eq!(bind(&pattern, &value), None);
+
+    // A repeated variable must see the same value each time.
+    let pattern = decode("{ from: $a, to: $a }").unwrap();
+    assert!(matches(&pattern, &decode(r#"{ from: "addressA", to: "addressA" }"#).unwrap()));
+    assert!(!matches(&pattern, &decode(r#"{ from: "addressA", to: "addressB" }"#).unwrap()));
+    assert!(matches(&decode("[$a, 2]").unwrap(), &decode("[1, 2]").unwrap()));
+    assert!(!matches(&decode("[$a]").unwrap(), &decode("[1, 2]").unwrap()));
+  }
+
+  #[test]
+  fn test_handle_binds_variables() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: $sender, token: { ticker: "WRLD", amount: $amt } } then
+      pay {
+        to: $sender,
+        token: { name: "world", ticker: "WRLD", amount: $amt }
+      }
+    "#).unwrap());
+
+    let deposit = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 40 }
+    }"#);
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    assert_eq!(engine.ledger().balance("addressA", "WRLD").atto(), 40);
+    assert_eq!(engine.ledger().balance(ESCROW, "WRLD").atto(), 0);
+  }
+
+  #[test]
+  fn test_handle_guard() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: $sender } if token.amount >= 100 && token.ticker == "WRLD" then
+      pay { to: $sender, token: { name: "world", ticker: "WRLD", amount: 100 } }
+    "#).unwrap());
+
+    let deposit = |amount| event("Deposit", &format!(r#"{{
+      from: "addressA",
+      token: {{ name: "world", ticker: "WRLD", amount: {} }}
+    }}"#, amount));
+
+    // The clause keeps waiting until the condition holds, but the escrow
+    // keeps the deposit.
+    assert_eq!(engine.handle(&deposit(50)), Ok(None));
+    assert!(!engine.has_fired(0));
+    assert_eq!(engine.ledger().balance(ESCROW, "WRLD").atto(), 50);
+
+    assert_eq!(engine.handle(&deposit(150)), Ok(Some(0)));
+    assert_eq!(engine.ledger().balance("addressA", "WRLD").atto(), 100);
+    assert_eq!(engine.ledger().balance(ESCROW, "WRLD").atto(), 100);
+  }
+
+  #[test]
+  fn test_handle_guard_error() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: $sender } if $sender >= 100 then
+      pay { to: $sender, token: { name: "world", ticker: "WRLD", amount: 1 } }
+    when Deposit { from: $sender } then
+      pay { to: "addressB", token: { name: "world", ticker: "WRLD", amount: 1 } }
+    "#).unwrap());
+
+    // The first guard can't compare a string with 100, so the second clause
+    // fires instead.
+    let deposit = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 1 }
+    }"#);
+    assert_eq!(engine.handle(&deposit), Ok(Some(1)));
+    assert!(!engine.has_fired(0));
+    assert_eq!(engine.ledger().balance("addressB", "WRLD").atto(), 1);
+  }
+
+  #[test]
+  fn test_handle_evaluates_arguments() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: $sender, token: { amount: $amt } } then
+      pay {
+        to: $sender,
+        token: { name: "world", ticker: "WRLD", amount: $amt * 0.9 }
+      } then
+      pay {
+        to: "addressB",
+        token: { name: "world", ticker: "WRLD", amount: token.amount - $amt * 0.9 }
+      }
+    "#).unwrap());
+
+    let deposit = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 29 }
+    }"#);
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    assert_eq!(engine.ledger().balance("addressA", "WRLD").atto(), 26);
+    assert_eq!(engine.ledger().balance("addressB", "WRLD").atto(), 3);
+    assert_eq!(engine.ledger().balance(ESCROW, "WRLD").atto(), 0);
+  }
+
+  #[test]
+  fn test_handle_declared_event() {
+    let mut engine = Engine::new(parse_contract(r#"
+    event BountyClaimed { claimant: address, reward: amount }
+    when Deposit { token: { amount: $amt } }
+    when BountyClaimed { claimant: $who, reward: $reward }
+    then pay { to: $who, token: { name: "world", ticker: "WRLD", amount: $reward } }
+    "#).unwrap());
+    let deposit = event("Deposit", r#"{ from: "addressA", token: { name: "world", ticker: "WRLD", amount: 10 } }"#);
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+
+    // Incoming events of a declared type must match its declaration.
+    let err = engine.handle(&event("BountyClaimed", r#"{ claimant: "addressB" }"#)).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `BountyClaimed`:\n  missing `reward` (expected Expr::TokenAmount)");
+    assert!(!engine.has_fired(1));
+
+    let claimed = event("BountyClaimed", r#"{ claimant: "addressB", reward: 4 }"#);
+    assert_eq!(engine.handle(&claimed), Ok(Some(1)));
+    assert_eq!(engine.ledger().balance("addressB", "WRLD").atto(), 4);
+  }
+
+  #[test]
+  fn test_handle_token_amounts() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: $sender, token: { ticker: "FIL", amount: $amt } } if $amt >= 1 FIL then
+      pay {
+        to: $sender,
+        token: { name: "filecoin", ticker: "FIL", amount: $amt * 0.9 - 100 milliFIL }
+      }
+    "#).unwrap());
+
+    let deposit = |amount| event("Deposit", &format!(r#"{{
+      from: "addressA",
+      token: {{ name: "filecoin", ticker: "FIL", amount: {} }}
+    }}"#, amount));
+    assert_eq!(engine.handle(&deposit("999 milliFIL")), Ok(None));
+    assert_eq!(engine.handle(&deposit("2.5 FIL")), Ok(Some(0)));
+    let fil = |s: &str| s.parse::<TokenAmount>().unwrap();
+    assert_eq!(engine.ledger().balance("addressA", "FIL"), fil("2.15 FIL"));
+    assert_eq!(engine.ledger().balance(ESCROW, "FIL"), fil("1.349 FIL"));
+  }
+
+  #[test]
+  fn test_handle_addresses() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: 0xd388aB098ed3E84c0D808776440B48F685198498 } then
+      pay {
+        to: f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy,
+        token: { name: "world", ticker: "WRLD", amount: 10 }
+      }
+    "#).unwrap());
+
+    let deposit = |from| event("Deposit", &format!(r#"{{
+      from: {},
+      token: {{ name: "world", ticker: "WRLD", amount: 10 }}
+    }}"#, from));
+    assert_eq!(engine.handle(&deposit("f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy")), Ok(None));
+    assert_eq!(engine.handle(&deposit(r#""not an address""#)), Ok(None));
+    assert_eq!(engine.handle(&deposit(r#""t410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy""#)), Ok(Some(0)));
+    assert_eq!(engine.ledger().balance("f17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy", "WRLD").atto(), 10);
+  }
+
+  #[test]
+  fn test_handle() {
+    let mut engine = Engine::new(contract());
+    assert_eq!(engine.pending(), vec![0, 1]);
+    assert!(!engine.is_finished());
+
+    let deposit = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 123 }
+    }"#);
+    let published = event("DealPublished", r#"{
+      piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy",
+      deal_id: 7
+    }"#);
+    let activated = event("DealActivated", "{ deal_id: 7 }");
+    let activated_by_cid = event("DealActivated", r#"{
+      piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy"
+    }"#);
+
+    // A deposit has to say what was deposited, matched or not.
+    let err = engine.handle(&event("Deposit", r#"{ from: "addressB" }"#)).unwrap_err();
+    assert!(err.to_string().contains("missing `token`"), "{}", err);
+    assert_eq!(engine.handle(&Expr::Dict(HashMap::new())), Ok(None));
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    assert!(engine.has_fired(0));
+    assert!(!engine.has_fired(1));
+
+    // A clause fires only once.
+    assert_eq!(engine.handle(&deposit), Ok(None));
+
+    // The deal must be created and published before it can activate.
+    assert!(engine.handle(&activated_by_cid).is_err());
+    assert!(!engine.has_fired(1));
+    assert_eq!(engine.handle(&event("DealProposalCreated", "{ deal_id: 7 }")), Ok(None));
+    assert_eq!(engine.handle(&published), Ok(None));
+    assert_eq!(engine.deals().status_by_deal_id(7), Some(DealStatus::Published));
+
+    assert_eq!(engine.handle(&activated_by_cid), Ok(Some(1)));
+    assert_eq!(engine.pending(), Vec::<usize>::new());
+    assert!(engine.is_finished());
+    assert_eq!(
+      engine.handle(&activated).unwrap_err().to_string(),
+      "Deal 7 is already activated"
+    );
+  }
+
+  #[test]
+  fn test_balances() {
+    let mut engine = Engine::new(contract());
+    let deposit = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 123 }
+    }"#);
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    assert_eq!(engine.ledger().balance(ESCROW, "WRLD").atto(), 23);
+    assert_eq!(engine.ledger().balance("addressB", "WRLD").atto(), 100);
+    assert_eq!(engine.ledger().balance("addressA", "WRLD").atto(), 0);
+  }
+
+  #[test]
+  fn test_insufficient_funds() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit {
+      from: "addressA"
+    } then
+      pay {
+        to: "addressB",
+        token: { name: "world", ticker: "WRLD", amount: 50 }
+      } then
+      pay {
+        to: "addressC",
+        token: { name: "world", ticker: "WRLD", amount: 50 }
+      }
+    "#).unwrap());
+
+    let deposit = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 60 }
+    }"#);
+    let err = engine.handle(&deposit).unwrap_err();
+    assert_eq!(err, ContractError::InsufficientFunds{
+      address: ESCROW.to_string(),
+      ticker: "WRLD".to_string(),
+      balance: TokenAmount::from_atto(10),
+      amount: TokenAmount::from_atto(50),
+    });
+    assert!(!engine.has_fired(0));
+    assert_eq!(engine.ledger(), &Ledger::new());
+
+    let deposit = event("Deposit", r#"{
+      from: "addressA",
+      token: { name: "world", ticker: "WRLD", amount: 100 }
+    }"#);
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    assert_eq!(engine.ledger().balance(ESCROW, "WRLD").atto(), 0);
+    assert_eq!(engine.ledger().balance("addressB", "WRLD").atto(), 50);
+    assert_eq!(engine.ledger().balance("addressC", "WRLD").atto(), 50);
+  }
+
+  #[test]
+  fn test_plan() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: $sender, token: { amount: $amt } }
+    then pay { to: $sender, token: { name: "world", ticker: "WRLD", amount: $amt - 10 } }
+    then propose {
+      deal_request: {
+        piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy",
+        piece_size: 2048,
+        verified_deal: false,
+        label: "x",
+        start_epoch: 10,
+        end_epoch: 20,
+        storage_price_per_epoch: 1,
+        provider_collateral: 0,
+        extra_params_version: 1
+      }
+    }
+    "#).unwrap());
+
+    // Nothing is planned for an event whose ops fail.
+    let short = event("Deposit", r#"{ from: "addressA", token: { name: "world", ticker: "WRLD", amount: 5 } }"#);
+    assert!(engine.handle(&short).is_err());
+    assert_eq!(engine.plan(), &[]);
+
+    let deposit = event("Deposit", r#"{ from: "addressA", token: { name: "world", ticker: "WRLD", amount: 30 } }"#);
+    let fired = engine.fire(&deposit).unwrap().unwrap();
+    assert_eq!(fired.clause, 0);
+    let transfer = Effect::Transfer{
+      to: "addressA".to_string(),
+      token: Token{ name: "world".to_string(), ticker: "WRLD".to_string(), amount: TokenAmount::from_atto(20) },
+    };
+    assert_eq!(fired.effects[0], transfer);
+    let deal_request = decode(r#"{
+      piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy",
+      piece_size: 2048,
+      verified_deal: false,
+      label: "x",
+      start_epoch: 10,
+      end_epoch: 20,
+      storage_price_per_epoch: 1,
+      provider_collateral: 0,
+      extra_params_version: 1
+    }"#).unwrap();
+    assert_eq!(fired.effects[1], Effect::ProposeDeal(DealRequest::try_from(&deal_request).unwrap()));
+    assert_eq!(engine.plan(), &fired.effects[..]);
+
+    assert_eq!(engine.take_plan(), fired.effects);
+    assert_eq!(engine.plan(), &[]);
+  }
+
+  #[test]
+  fn test_choice_and_notify() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Deposit { from: "alice", token: { amount: $amt } }
+
+    when Choice { by: "alice", name: "tip", bounds: [(1, 3), (10, 10)], value: $n }
+    then pay { to: "bob", token: { name: "Filecoin", ticker: "FIL", amount: $n } }
+
+    when Notify { by: "oracle", name: "delivered" }
+    then pay { to: "bob", token: { name: "Filecoin", ticker: "FIL", amount: 1 } }
+    "#).unwrap());
+    let deposit = event("Deposit", r#"{ from: "alice", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }"#);
+    assert_eq!(engine.handle(&deposit).unwrap(), Some(0));
+
+    // Out of bounds, or by someone else: no clause waits for it.
+    assert_eq!(engine.handle(&event("Choice", r#"{ by: "alice", name: "tip", value: 5 }"#)).unwrap(), None);
+    assert_eq!(engine.handle(&event("Choice", r#"{ by: "bob", name: "tip", value: 2 }"#)).unwrap(), None);
+    assert_eq!(engine.handle(&event("Choice", r#"{ by: "alice", name: "tip" }"#)).unwrap(), None);
+    // The number chosen is bound for the ops.
+    assert_eq!(engine.handle(&event("Choice", r#"{ by: "alice", name: "tip", value: 10 }"#)).unwrap(), Some(1));
+    assert_eq!(engine.ledger().balance("bob", "FIL").atto(), 10);
+
+    assert_eq!(engine.handle(&event("Notify", r#"{ by: "oracle", name: "late" }"#)).unwrap(), None);
+    assert_eq!(engine.handle(&event("Notify", r#"{ by: "oracle", name: "delivered" }"#)).unwrap(), Some(2));
+    assert!(engine.is_finished());
+  }
+
+  #[test]
+  fn test_timeout() {
+    let contract = parse_contract(r#"
+    when Deposit { from: "alice", token: { amount: $amt } }
+
+    when Pay { to: "provider" }
+    then pay { to: "provider", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }
+    timeout 100 epochs
+    else pay { to: "alice", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }
+    "#).unwrap();
+    let clock = SimulatedClock::new(1000);
+    let mut engine = Engine::with_clock(contract, Arc::new(clock.clone()));
+    assert_eq!(engine.deadline(0), None);
+    assert_eq!(engine.deadline(1), Some(1100));
+
+    let deposit = event("Deposit", r#"{ from: "alice", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }"#);
+    assert_eq!(engine.handle(&deposit).unwrap(), Some(0));
+    clock.advance(99);
+    assert_eq!(engine.tick().unwrap(), vec![]);
+
+    // Once the deadline is reached, the event comes too late.
+    clock.advance(1);
+    assert_eq!(engine.handle(&event("Pay", r#"{ to: "provider" }"#)).unwrap(), None);
+    let expired = engine.tick().unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].clause, 1);
+    assert_eq!(expired[0].ops[0].0, "pay");
+    assert_eq!(engine.ledger().balance("alice", "FIL").to_string(), "1 FIL");
+    assert_eq!(engine.plan().len(), 1);
+    assert!(engine.is_finished());
+    assert_eq!(engine.tick().unwrap(), vec![]);
+  }
+
+  #[test]
+  fn test_timeout_failure() {
+    let contract = parse_contract(r#"
+    when Deposit { from: "alice" }
+    timeout 10 epochs
+    else pay { to: "alice", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }
+    "#).unwrap();
+    let clock = SimulatedClock::new(0);
+    let mut engine = Engine::with_clock(contract, Arc::new(clock.clone()));
+    clock.set(10);
+    // Nothing is held to pay with, so the clause stays pending.
+    assert!(engine.tick().is_err());
+    assert!(!engine.has_fired(0));
+    assert_eq!(engine.plan(), &[]);
+
+    // If one timeout fails, those before it are undone too.
+    let contract = parse_contract(r#"
+    when Deposit { from: "alice" }
+    when Notify { name: "a" } timeout 10 epochs else pay { to: "bob", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }
+    when Notify { name: "b" } timeout 10 epochs else pay { to: "carol", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }
+    "#).unwrap();
+    let clock = SimulatedClock::new(0);
+    let mut engine = Engine::with_clock(contract, Arc::new(clock.clone()));
+    let deposit = event("Deposit", r#"{ from: "alice", token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }"#);
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    let planned = engine.plan().len();
+    clock.set(10);
+    assert!(engine.tick().is_err());
+    assert_eq!(engine.pending(), vec![1, 2]);
+    assert_eq!(engine.ledger().balance(ESCROW, "FIL").to_string(), "1 FIL");
+    assert_eq!(engine.plan().len(), planned);
+  }
+
+  #[test]
+  fn test_nested_clauses() {
+    let source = r#"
+    when Deposit { from: $sender, token: { amount: $amt } }
+    then pay { to: "provider", token: { name: "Filecoin", ticker: "FIL", amount: $amt / 2 } }
+    then when Notify { by: $sender, name: "received" }
+      then pay { to: $sender, token: { name: "Filecoin", ticker: "FIL", amount: $amt / 2 } }
+      timeout 50 epochs
+      else pay { to: "provider", token: { name: "Filecoin", ticker: "FIL", amount: $amt / 2 } }
+    "#;
+    let deposit = event("Deposit", r#"{ from: "alice", token: { name: "Filecoin", ticker: "FIL", amount: 2 FIL } }"#);
+    let received = |by| event("Notify", &format!(r#"{{ by: "{}", name: "received" }}"#, by));
+
+    let clock = SimulatedClock::new(1000);
+    let mut engine = Engine::with_clock(parse_contract(source).unwrap(), Arc::new(clock.clone()));
+    assert_eq!(engine.pending(), vec![0]);
+    assert_eq!(engine.deadline(1), None);
+    // The nested clause waits for the one before it.
+    assert_eq!(engine.handle(&received("alice")), Ok(None));
+
+    clock.advance(30);
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    assert_eq!(engine.pending(), vec![1]);
+    assert!(!engine.is_finished());
+    // Its timeout counts from then, and `$sender` is still bound.
+    assert_eq!(engine.deadline(1), Some(1080));
+    assert_eq!(engine.handle(&received("bob")), Ok(None));
+    assert_eq!(engine.handle(&received("alice")), Ok(Some(1)));
+    assert_eq!(engine.ledger().balance("alice", "FIL").to_string(), "1 FIL");
+    assert!(engine.is_finished());
+
+    let mut engine = Engine::with_clock(parse_contract(source).unwrap(), Arc::new(clock.clone()));
+    assert_eq!(engine.handle(&deposit), Ok(Some(0)));
+    clock.advance(49);
+    assert_eq!(engine.tick().unwrap(), vec![]);
+    clock.advance(1);
+    let expired = engine.tick().unwrap();
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].clause, 1);
+    assert_eq!(engine.ledger().balance("provider", "FIL").to_string(), "2 FIL");
+    assert!(engine.is_finished());
+  }
+
+  #[test]
+  fn test_nested_close() {
+    let mut engine = Engine::new(parse_contract(r#"
+    when Notify { name: "cancel" } then close
+    when Notify { name: "start" } then when Notify { name: "finish" }
+    "#).unwrap());
+    let notify = |name| event("Notify", &format!(r#"{{ by: "alice", name: "{}" }}"#, name));
+    assert_eq!(engine.handle(&notify("start")), Ok(Some(1)));
+    assert_eq!(engine.pending(), vec![0, 2]);
+
+    // Closing leaves nothing waiting.
+    assert_eq!(engine.handle(&notify("cancel")), Ok(Some(0)));
+    assert_eq!(engine.pending(), Vec::<usize>::new());
+    assert!(engine.is_finished());
+    assert_eq!(engine.handle(&notify("finish")), Ok(None));
+  }
+
+  #[test]
+  fn test_deposit_without_token() {
+    let mut engine = Engine::new(parse_contract(r#"when Deposit { from: "addressA" }"#).unwrap());
+    let err = engine.handle(&event("Deposit", r#"{ from: "addressA" }"#)).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `Deposit`:\n  missing `token` (expected Expr::Dict)");
+    assert!(!engine.has_fired(0));
+  }
+
+  #[test]
+  fn test_empty_contract_is_finished() {
+    let engine = Engine::new(parse_contract("close").unwrap());
+    assert!(engine.is_finished());
+  }
+}
@@ -0,0 +1,159 @@
+use std::fmt;
+
+use crate::deal::DealStatus;
+use crate::diagnostic::Diagnostic;
+use crate::op::TokenAmount;
+
+/// A field of an op or event argument that couldn't be read. `field` is a
+/// dotted path relative to the argument, empty for the argument itself.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldError {
+  Missing { field: String, expected: &'static str },
+  WrongType { field: String, expected: &'static str, found: &'static str },
+  /// The field has the right type but a value that doesn't hold up.
+  Invalid { field: String, reason: String },
+  /// A field the schema doesn't have. With an empty `field`, the event
+  /// itself is unknown.
+  Unknown { field: String },
+}
+
+impl FieldError {
+  pub(crate) fn field(&self) -> &str {
+    match self {
+      FieldError::Missing{ field, .. } => field,
+      FieldError::WrongType{ field, .. } => field,
+      FieldError::Invalid{ field, .. } => field,
+      FieldError::Unknown{ field } => field,
+    }
+  }
+
+  /// Moves the error under `parent`, e.g. `amount` becomes `token.amount`.
+  pub(crate) fn nest(self, parent: &str) -> Self {
+    let join = |field: String| if field.is_empty() {
+      parent.to_string()
+    } else {
+      format!("{}.{}", parent, field)
+    };
+    match self {
+      FieldError::Missing{ field, expected } => FieldError::Missing{ field: join(field), expected },
+      FieldError::WrongType{ field, expected, found } => FieldError::WrongType{ field: join(field), expected, found },
+      FieldError::Invalid{ field, reason } => FieldError::Invalid{ field: join(field), reason },
+      FieldError::Unknown{ field } => FieldError::Unknown{ field: join(field) },
+    }
+  }
+}
+
+impl fmt::Display for FieldError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      FieldError::Missing{ field, expected } if field.is_empty() =>
+        write!(f, "missing argument (expected {})", expected),
+      FieldError::Missing{ field, expected } =>
+        write!(f, "missing `{}` (expected {})", field, expected),
+      FieldError::WrongType{ field, expected, found } if field.is_empty() =>
+        write!(f, "expected {}, found {}", expected, found),
+      FieldError::WrongType{ field, expected, found } =>
+        write!(f, "`{}` should be {}, found {}", field, expected, found),
+      FieldError::Invalid{ field, reason } =>
+        write!(f, "`{}` is invalid: {}", field, reason),
+      FieldError::Unknown{ field } if field.is_empty() => write!(f, "unknown event"),
+      FieldError::Unknown{ field } => write!(f, "unknown field `{}`", field),
+    }
+  }
+}
+
+/// A field error found by checking a contract against the schemas of its
+/// events and ops, in the `when` pattern or op argument `target` of the
+/// 0-based `clause`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaError {
+  pub clause: usize,
+  pub target: String,
+  pub error: FieldError,
+}
+
+impl fmt::Display for SchemaError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "clause {}, `{}`: {}", self.clause + 1, self.target, self.error)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ContractError {
+  /// The argument of an op or event, e.g. `pay` or `deal_request`, has
+  /// missing or wrongly typed fields. All of them are listed.
+  InvalidArguments { target: String, errors: Vec<FieldError> },
+  InsufficientFunds { address: String, ticker: String, balance: TokenAmount, amount: TokenAmount },
+  /// A credit that would take a balance past the largest token amount.
+  Overflow { address: String, ticker: String },
+  /// A deal lifecycle event out of order, repeated or after termination.
+  IllegalTransition { deal: String, from: Option<DealStatus>, to: DealStatus },
+  /// Event patterns and op arguments that don't fit their schema, all of
+  /// them rather than the first.
+  Schema(Vec<SchemaError>),
+  /// A `$name` used in op arguments that its `when` clause doesn't bind.
+  UnboundVariable(String),
+  /// A guard or argument expression that can't be evaluated against the
+  /// event, e.g. a missing field or a comparison between mismatched types.
+  Eval(String),
+  /// Bytes that couldn't be encoded to or decoded from DAG-CBOR.
+  Cbor(String),
+  /// Contract source that doesn't parse.
+  Parse(Box<Diagnostic>),
+  /// A JSON export that can't be read back, e.g. of another version.
+  Json(String),
+  /// A contract the Solidity backend has no translation for.
+  Solidity(String),
+  /// A name a host tried to register that the parser couldn't read as
+  /// `expected`, e.g. an op keyword that is one of the grammar's own.
+  InvalidName { name: String, expected: &'static str },
+  Io(String),
+}
+
+impl fmt::Display for ContractError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ContractError::InvalidArguments{ target, errors } => {
+        write!(f, "Invalid arguments for `{}`:", target)?;
+        for error in errors {
+          write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+      },
+      ContractError::InsufficientFunds{ address, ticker, balance, amount } => write!(
+        f,
+        "Insufficient funds: {} holds {} but {} is needed",
+        address, balance.with_ticker(ticker), amount.with_ticker(ticker)
+      ),
+      ContractError::Overflow{ address, ticker } => write!(
+        f,
+        "Balance overflow: {} would hold more {} than a token amount can",
+        address, ticker
+      ),
+      ContractError::IllegalTransition{ deal, from, to } => match from {
+        Some(DealStatus::Terminated) => write!(f, "Deal {} has already been terminated", deal),
+        Some(from) if from == to => write!(f, "Deal {} is already {}", deal, to),
+        Some(from) => write!(f, "Deal {} cannot go from {} to {}", deal, from, to),
+        None => write!(f, "Deal {} cannot be {} before it is created", deal, to),
+      },
+      ContractError::Schema(errors) => {
+        write!(f, "Contract does not match the schema:")?;
+        for error in errors {
+          write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+      },
+      ContractError::UnboundVariable(name) =>
+        write!(f, "Variable `${}` is not bound by its `when` clause", name),
+      ContractError::Eval(message) => write!(f, "{}", message),
+      ContractError::Cbor(message) => write!(f, "CBOR error: {}", message),
+      ContractError::Parse(diagnostic) => write!(f, "{}", diagnostic),
+      ContractError::Json(message) => write!(f, "JSON error: {}", message),
+      ContractError::Solidity(message) => write!(f, "Can't compile to Solidity: {}", message),
+      ContractError::InvalidName{ name, expected } => write!(f, "`{}` can't be {}", name, expected),
+      ContractError::Io(message) => write!(f, "{}", message),
+    }
+  }
+}
+
+impl std::error::Error for ContractError {}
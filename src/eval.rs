@@ -0,0 +1,427 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use crate::address::Address;
+use crate::error::ContractError;
+use crate::expr::{BinOp, Bindings, Expr};
+use crate::op::TokenAmount;
+
+/// What an expression can refer to while a clause runs: the arguments of
+/// the matched event by bare name, and its bound variables by `$name`.
+pub(crate) struct Scope<'a> {
+  pub(crate) args: &'a HashMap<String, Expr>,
+  pub(crate) bindings: &'a Bindings,
+}
+
+fn error(message: String) -> ContractError {
+  ContractError::Eval(message)
+}
+
+/// Whether a guard condition holds. Anything but a bool is an error.
+pub(crate) fn holds(guard: &Expr, scope: &Scope) -> Result<bool, ContractError> {
+  match eval(guard, scope)? {
+    Expr::Bool(b) => Ok(b),
+    e => Err(error(format!("Condition `{}` should be Expr::Bool, found {}", path(guard), e.variant_name()))),
+  }
+}
+
+/// Reduces an expression to a value. Dicts, arrays and pairs are evaluated
+/// element by element; literals evaluate to themselves.
+pub(crate) fn eval(expr: &Expr, scope: &Scope) -> Result<Expr, ContractError> {
+  Ok(match expr {
+    Expr::Id(name) => scope.args
+      .get(name)
+      .cloned()
+      .ok_or_else(|| error(format!("The event has no field `{}`", name)))?,
+    Expr::Var(name) => scope.bindings
+      .get(name)
+      .cloned()
+      .ok_or_else(|| ContractError::UnboundVariable(name.clone()))?,
+    Expr::Field(base, field) => match eval(base, scope)? {
+      Expr::Dict(hm) => hm
+        .get(field)
+        .cloned()
+        .ok_or_else(|| error(format!("`{}` has no field `{}`", path(base), field)))?,
+      e => return Err(error(format!(
+        "Cannot read `{}` of `{}`, which is {}", field, path(base), e.variant_name()
+      ))),
+    },
+    Expr::Not(e) => match eval(e, scope)? {
+      Expr::Bool(b) => Expr::Bool(!b),
+      v => return Err(error(format!("`!` expects Expr::Bool, found {}", v.variant_name()))),
+    },
+    Expr::BinOp{ op: op @ (BinOp::And | BinOp::Or), lhs, rhs } => {
+      // Short-circuits, so the right-hand side may rely on the left.
+      let lhs = boolean(*op, eval(lhs, scope)?)?;
+      match (op, lhs) {
+        (BinOp::And, false) => Expr::Bool(false),
+        (BinOp::Or, true) => Expr::Bool(true),
+        _ => Expr::Bool(boolean(*op, eval(rhs, scope)?)?),
+      }
+    },
+    Expr::BinOp{ op: op @ (BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div), lhs: l, rhs: r } => {
+      let (lhs, rhs) = (eval(l, scope)?, eval(r, scope)?);
+      arithmetic(*op, &lhs, &rhs)
+        .map_err(|reason| error(format!("Cannot evaluate `{}`: {}", path(expr), reason)))?
+    },
+    Expr::BinOp{ op, lhs, rhs } => {
+      let (lhs, rhs) = (eval(lhs, scope)?, eval(rhs, scope)?);
+      Expr::Bool(compare(*op, &lhs, &rhs)?)
+    },
+    Expr::Dict(hm) => Expr::Dict(
+      hm.iter()
+        .map(|(key, value)| Ok((key.clone(), eval(value, scope)?)))
+        .collect::<Result<_, ContractError>>()?
+    ),
+    Expr::Array(items) => Expr::Array(
+      items.iter().map(|item| eval(item, scope)).collect::<Result<_, _>>()?
+    ),
+    Expr::Pair(fst, snd) => Expr::Pair(Box::new(eval(fst, scope)?), Box::new(eval(snd, scope)?)),
+    e => e.clone(),
+  })
+}
+
+fn boolean(op: BinOp, value: Expr) -> Result<bool, ContractError> {
+  match value {
+    Expr::Bool(b) => Ok(b),
+    v => Err(error(format!("`{}` expects Expr::Bool, found {}", op, v.variant_name()))),
+  }
+}
+
+/// Integers stay integers, with overflow, underflow and division by zero
+/// reported, and decimals stay decimals. An integer combined with a decimal
+/// is almost always a token amount being scaled, e.g. `$amt * 0.9`, so the
+/// decimal is taken at its written value, the result is worked out exactly
+/// and rounded down to an integer. Token amounts follow `amount_arithmetic`.
+fn arithmetic(op: BinOp, lhs: &Expr, rhs: &Expr) -> Result<Expr, String> {
+  match (lhs, rhs) {
+    (Expr::TokenAmount(_), _) | (_, Expr::TokenAmount(_)) => {
+      amount_arithmetic(op, lhs, rhs).map(Expr::TokenAmount)
+    },
+    (Expr::Integer(a), Expr::Integer(b)) => {
+      let result = match op {
+        BinOp::Add => a.checked_add(*b),
+        BinOp::Sub => a.checked_sub(*b),
+        BinOp::Mul => a.checked_mul(*b),
+        _ => a.checked_div(*b),
+      };
+      result.map(Expr::Integer).ok_or_else(|| match op {
+        BinOp::Sub => "the result is negative".to_string(),
+        BinOp::Div => "division by zero".to_string(),
+        _ => "the result overflows".to_string(),
+      })
+    },
+    (Expr::Decimal(a), Expr::Decimal(b)) => Ok(Expr::Decimal(match op {
+      BinOp::Add => a + b,
+      BinOp::Sub => a - b,
+      BinOp::Mul => a * b,
+      _ => a / b,
+    })),
+    (Expr::Integer(_), Expr::Decimal(_)) | (Expr::Decimal(_), Expr::Integer(_)) => {
+      let (a, b) = (ratio(lhs)?, ratio(rhs)?);
+      let overflow = || "the result overflows".to_string();
+      let cross = || Some((a.0.checked_mul(b.1)?, b.0.checked_mul(a.1)?, a.1.checked_mul(b.1)?));
+      let (n, d) = match op {
+        BinOp::Add => {
+          let (x, y, d) = cross().ok_or_else(overflow)?;
+          (x.checked_add(y).ok_or_else(overflow)?, d)
+        },
+        BinOp::Sub => {
+          let (x, y, d) = cross().ok_or_else(overflow)?;
+          (x.checked_sub(y).ok_or("the result is negative")?, d)
+        },
+        BinOp::Mul => (
+          a.0.checked_mul(b.0).ok_or_else(overflow)?,
+          a.1.checked_mul(b.1).ok_or_else(overflow)?,
+        ),
+        _ if b.0 == 0 => return Err("division by zero".to_string()),
+        _ => (
+          a.0.checked_mul(b.1).ok_or_else(overflow)?,
+          a.1.checked_mul(b.0).ok_or_else(overflow)?,
+        ),
+      };
+      usize::try_from(n / d).map(Expr::Integer).map_err(|_| overflow())
+    },
+    _ => Err(format!("`{}` expects numbers, found {} and {}", op, lhs.variant_name(), rhs.variant_name())),
+  }
+}
+
+/// Amounts add and subtract with amounts, or with plain integers counted in
+/// attoFIL, and scale by integers and decimals, rounding down. The result
+/// is always an amount.
+fn amount_arithmetic(op: BinOp, lhs: &Expr, rhs: &Expr) -> Result<TokenAmount, String> {
+  let overflow = || "the result overflows".to_string();
+  let amount = |e: &Expr| match e {
+    Expr::TokenAmount(amount) => Ok(*amount),
+    Expr::Integer(n) => Ok(TokenAmount::from_atto(*n as u128)),
+    e => Err(format!("`{}` expects amounts, found {}", op, e.variant_name())),
+  };
+  match (op, lhs, rhs) {
+    (BinOp::Add, _, _) => amount(lhs)?.checked_add(amount(rhs)?).ok_or_else(overflow),
+    (BinOp::Sub, _, _) => amount(lhs)?.checked_sub(amount(rhs)?).ok_or_else(|| "the result is negative".to_string()),
+    (BinOp::Mul, Expr::TokenAmount(a), n) | (BinOp::Mul, n, Expr::TokenAmount(a)) => {
+      let (n, d) = ratio(n)?;
+      a.checked_scale(n, d).ok_or_else(overflow)
+    },
+    (BinOp::Div, Expr::TokenAmount(a), n) => match ratio(n)? {
+      (0, _) => Err("division by zero".to_string()),
+      (n, d) => a.checked_scale(d, n).ok_or_else(overflow),
+    },
+    _ => Err(format!("`{}` cannot divide {} by {}", op, lhs.variant_name(), rhs.variant_name())),
+  }
+}
+
+/// A number as an exact fraction, reading a decimal the way it's written
+/// (`0.9` is nine tenths, not the nearest double).
+pub(crate) fn ratio(n: &Expr) -> Result<(u128, u128), String> {
+  let d = match n {
+    Expr::Integer(n) => return Ok((*n as u128, 1)),
+    Expr::Decimal(d) => *d,
+    e => return Err(format!("expected a number, found {}", e.variant_name())),
+  };
+  // Negative, infinite or too many digits to hold exactly.
+  let inexact = || format!("{} can't be combined with an integer", d);
+  let s = d.to_string();
+  let (whole, fraction) = s.split_once('.').unwrap_or((&s, ""));
+  let n = format!("{}{}", whole, fraction).parse::<u128>().map_err(|_| inexact())?;
+  let d = 10u128.checked_pow(fraction.len() as u32).ok_or_else(inexact)?;
+  Ok((n, d))
+}
+
+/// Integers and decimals compare with each other numerically, token amounts
+/// with each other and with integers counted in attoFIL, and strings
+/// compare lexicographically. `==` and `!=` accept any two values.
+fn compare(op: BinOp, lhs: &Expr, rhs: &Expr) -> Result<bool, ContractError> {
+  let ordering = match (lhs, rhs) {
+    (Expr::TokenAmount(a), Expr::TokenAmount(b)) => Some(a.cmp(b)),
+    (Expr::TokenAmount(a), Expr::Integer(b)) => Some(a.atto().cmp(&(*b as u128))),
+    (Expr::Integer(a), Expr::TokenAmount(b)) => Some((*a as u128).cmp(&b.atto())),
+    (Expr::Integer(a), Expr::Integer(b)) => Some(a.cmp(b)),
+    (Expr::Integer(a), Expr::Decimal(b)) => (*a as f64).partial_cmp(b),
+    (Expr::Decimal(a), Expr::Integer(b)) => a.partial_cmp(&(*b as f64)),
+    (Expr::Decimal(a), Expr::Decimal(b)) => a.partial_cmp(b),
+    (Expr::QuotedString(a), Expr::QuotedString(b)) => Some(a.cmp(b)),
+    _ => None,
+  };
+
+  match (op, ordering) {
+    (BinOp::Eq, Some(ordering)) => Ok(ordering == Ordering::Equal),
+    (BinOp::Ne, Some(ordering)) => Ok(ordering != Ordering::Equal),
+    (BinOp::Eq, None) => Ok(same_account(lhs, rhs) || lhs == rhs),
+    (BinOp::Ne, None) => Ok(!(same_account(lhs, rhs) || lhs == rhs)),
+    (BinOp::Lt, Some(ordering)) => Ok(ordering == Ordering::Less),
+    (BinOp::Le, Some(ordering)) => Ok(ordering != Ordering::Greater),
+    (BinOp::Gt, Some(ordering)) => Ok(ordering == Ordering::Greater),
+    (BinOp::Ge, Some(ordering)) => Ok(ordering != Ordering::Less),
+    _ => Err(error(format!(
+      "Cannot compare {} with {} using `{}`", lhs.variant_name(), rhs.variant_name(), op
+    ))),
+  }
+}
+
+/// Whether both are addresses of the same account, either given as a
+/// string that parses as one.
+pub(crate) fn same_account(lhs: &Expr, rhs: &Expr) -> bool {
+  let address = |e: &Expr| match e {
+    Expr::Address(address) => Some(address.clone()),
+    Expr::QuotedString(s) => s.parse::<Address>().ok(),
+    _ => None,
+  };
+  match (lhs, rhs) {
+    (Expr::Address(_), _) | (_, Expr::Address(_)) => match (address(lhs), address(rhs)) {
+      (Some(a), Some(b)) => a.same_account(&b),
+      _ => false,
+    },
+    _ => false,
+  }
+}
+
+/// How an expression reads in source, for error messages.
+fn path(expr: &Expr) -> String {
+  match expr {
+    Expr::Id(name) => name.clone(),
+    Expr::Var(name) => format!("${}", name),
+    Expr::Field(base, field) => format!("{}.{}", path(base), field),
+    Expr::Not(e) => format!("!{}", path(e)),
+    Expr::BinOp{ op, lhs, rhs } => format!("{} {} {}", operand(lhs), op, operand(rhs)),
+    Expr::Integer(n) => n.to_string(),
+    Expr::Decimal(n) => n.to_string(),
+    Expr::Bool(b) => b.to_string(),
+    Expr::QuotedString(s) => format!("{:?}", s),
+    Expr::Address(address) => address.to_string(),
+    Expr::TokenAmount(amount) => amount.to_string(),
+    e => e.variant_name().to_string(),
+  }
+}
+
+fn operand(expr: &Expr) -> String {
+  match expr {
+    Expr::BinOp{ .. } => format!("({})", path(expr)),
+    e => path(e),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use combine::{eof, Parser};
+
+  use super::*;
+  use crate::expr::{condition as condition_parser, decode};
+  use crate::registry::Registry;
+
+  fn args() -> HashMap<String, Expr> {
+    match decode(r#"{
+      from: "addressA",
+      operator: "f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy",
+      token: { ticker: "WRLD", amount: 120 },
+      fee: 1.5 FIL,
+      verified: true
+    }"#) {
+      Ok(Expr::Dict(args)) => args,
+      e => panic!("Expected a dict, got {:?}", e),
+    }
+  }
+
+  fn check(condition: &str) -> Result<bool, ContractError> {
+    let args = args();
+    let mut bindings = Bindings::new();
+    bindings.insert("min".to_string(), Expr::Integer(100));
+    let guard = condition_parser(Registry::new()).skip(eof()).parse(condition).unwrap().0;
+    holds(&guard, &Scope{ args: &args, bindings: &bindings })
+  }
+
+  #[test]
+  fn test_comparisons() {
+    assert_eq!(check("token.amount >= 100"), Ok(true));
+    assert_eq!(check("token.amount > 120"), Ok(false));
+    assert_eq!(check("token.amount <= $min"), Ok(false));
+    assert_eq!(check("token.amount < 120.5"), Ok(true));
+    assert_eq!(check(r#"token.ticker == "WRLD""#), Ok(true));
+    assert_eq!(check(r#"from != "addressA""#), Ok(false));
+    assert_eq!(check("verified"), Ok(true));
+    assert_eq!(check("token.amount * 2 - 40 >= $min * 2"), Ok(true));
+    assert_eq!(check("from == f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy"), Ok(false));
+    assert_eq!(check("operator == 0xd388ab098ed3e84c0d808776440b48f685198498"), Ok(true));
+    assert_eq!(check("operator != t410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy"), Ok(false));
+  }
+
+  #[test]
+  fn test_boolean_ops() {
+    assert_eq!(check(r#"token.amount > 100 && token.ticker == "WRLD""#), Ok(true));
+    assert_eq!(check("!verified || token.amount < 10"), Ok(false));
+    assert_eq!(check("!(token.amount < 10) && verified"), Ok(true));
+    // The right-hand side is never looked at.
+    assert_eq!(check("verified || missing.field"), Ok(true));
+  }
+
+  fn evaluate(expr: &str) -> Result<Expr, ContractError> {
+    let args = args();
+    let mut bindings = Bindings::new();
+    bindings.insert("min".to_string(), Expr::Integer(100));
+    bindings.insert("sender".to_string(), Expr::QuotedString("addressA".to_string()));
+    eval(&decode(expr).unwrap(), &Scope{ args: &args, bindings: &bindings })
+  }
+
+  #[test]
+  fn test_variables() {
+    assert_eq!(
+      evaluate(r#"{ to: $sender, token: { ticker: "WRLD", amount: $min }, ids: [$min, 1] }"#),
+      decode(r#"{ to: "addressA", token: { ticker: "WRLD", amount: 100 }, ids: [100, 1] }"#)
+        .map_err(ContractError::Parse)
+    );
+    assert_eq!(evaluate("{ to: $receiver }"), Err(ContractError::UnboundVariable("receiver".to_string())));
+  }
+
+  #[test]
+  fn test_arithmetic() {
+    assert_eq!(evaluate("start_epoch + 518400"), Err(ContractError::Eval("The event has no field `start_epoch`".to_string())));
+    assert_eq!(evaluate("$min + 518400"), Ok(Expr::Integer(518500)));
+    assert_eq!(evaluate("$min - 1 + 2"), Ok(Expr::Integer(101)));
+    assert_eq!(evaluate("7 / 2"), Ok(Expr::Integer(3)));
+    assert_eq!(evaluate("1.5 * 2."), Ok(Expr::Decimal(3.0)));
+
+    // Mixed with decimals, integers are scaled exactly and rounded down.
+    assert_eq!(evaluate("$min * 0.9"), Ok(Expr::Integer(90)));
+    assert_eq!(evaluate("100 * 0.29"), Ok(Expr::Integer(29)));
+    assert_eq!(evaluate("10 / 0.3"), Ok(Expr::Integer(33)));
+    assert_eq!(evaluate("1 - 0.5"), Ok(Expr::Integer(0)));
+
+    assert_eq!(
+      evaluate("$min - 101").unwrap_err().to_string(),
+      "Cannot evaluate `$min - 101`: the result is negative"
+    );
+    assert_eq!(
+      evaluate("($min + 1) / (2 - 2)").unwrap_err().to_string(),
+      "Cannot evaluate `($min + 1) / (2 - 2)`: division by zero"
+    );
+    assert_eq!(
+      evaluate("$min / 0.0").unwrap_err().to_string(),
+      "Cannot evaluate `$min / 0`: division by zero"
+    );
+    assert!(evaluate("18446744073709551615 * 2").is_err());
+    assert_eq!(
+      evaluate("$sender * 2").unwrap_err().to_string(),
+      "Cannot evaluate `$sender * 2`: `*` expects numbers, found Expr::QuotedString and Expr::Integer"
+    );
+  }
+
+  #[test]
+  fn test_token_amounts() {
+    let amount = |s: &str| Ok(Expr::TokenAmount(s.parse().unwrap()));
+    assert_eq!(evaluate("fee + 500 milliFIL"), amount("2 FIL"));
+    assert_eq!(evaluate("fee - 1 attoFIL"), amount("1.499999999999999999 FIL"));
+    assert_eq!(evaluate("fee * 0.9"), amount("1.35 FIL"));
+    assert_eq!(evaluate("2 * fee / 3"), amount("1 FIL"));
+    assert_eq!(evaluate("1 nanoFIL / 0.3"), amount("3.333333333 nanoFIL"));
+    assert_eq!(evaluate("1 FIL + $min"), amount("1.0000000000000001 FIL"));
+
+    assert_eq!(check("fee > 1 FIL && fee <= 1500 milliFIL"), Ok(true));
+    assert_eq!(check("fee == 1.5 FIL"), Ok(true));
+    assert_eq!(check("1 attoFIL < 2"), Ok(true));
+
+    assert_eq!(
+      evaluate("fee - 2 FIL").unwrap_err().to_string(),
+      "Cannot evaluate `fee - 2 FIL`: the result is negative"
+    );
+    assert_eq!(
+      evaluate("fee / 0").unwrap_err().to_string(),
+      "Cannot evaluate `fee / 0`: division by zero"
+    );
+    assert_eq!(
+      evaluate("fee * fee").unwrap_err().to_string(),
+      "Cannot evaluate `fee * fee`: expected a number, found Expr::TokenAmount"
+    );
+    assert_eq!(
+      evaluate("1 / fee").unwrap_err().to_string(),
+      "Cannot evaluate `1 / fee`: `/` cannot divide Expr::Integer by Expr::TokenAmount"
+    );
+    assert_eq!(
+      evaluate(r#"fee + "1""#).unwrap_err().to_string(),
+      "Cannot evaluate `fee + \"1\"`: `+` expects amounts, found Expr::QuotedString"
+    );
+    assert!(evaluate("1000000000 FIL * 1000000000000000000").is_err());
+    assert!(check("fee > 1.0").is_err());
+  }
+
+  #[test]
+  fn test_errors() {
+    assert_eq!(
+      check("token.amount").unwrap_err().to_string(),
+      "Condition `token.amount` should be Expr::Bool, found Expr::Integer"
+    );
+    assert_eq!(check("token.decimals > 1").unwrap_err().to_string(), "`token` has no field `decimals`");
+    assert_eq!(check("to == 1").unwrap_err().to_string(), "The event has no field `to`");
+    assert_eq!(
+      check("from.name == 1").unwrap_err().to_string(),
+      "Cannot read `name` of `from`, which is Expr::QuotedString"
+    );
+    assert_eq!(
+      check(r#"token.amount > "100""#).unwrap_err().to_string(),
+      "Cannot compare Expr::Integer with Expr::QuotedString using `>`"
+    );
+    assert_eq!(
+      check("verified && token.amount").unwrap_err().to_string(),
+      "`&&` expects Expr::Bool, found Expr::Integer"
+    );
+  }
+}
@@ -1,27 +1,36 @@
 #[macro_use]
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
 use combine::many;
 use combine::parser;
 use combine::attempt;
 use combine::parser::char::string;
 use combine::parser::choice::or;
-use combine::{not_followed_by, optional};
-use combine::parser::char::{spaces, digit, char, letter};
+use combine::{eof, look_ahead, not_followed_by, optional, unexpected_any, value};
+use combine::parser::char::{digit, char, letter, alpha_num};
+use combine::parser::repeat::chainl1;
 use combine::{between, choice, many1, sep_by, ParseError, Parser};
-use combine::stream::Stream;
-
+use combine::stream::{position, Stream};
+use combine::EasyParser;
+use combine::error::{Commit, StreamError, Tracked};
+use combine::stream::StreamErrorFor;
+use combine::{one_of, position, satisfy, Positioned, StreamOnce};
+use serde::{Deserialize, Serialize};
+
+use crate::address::Address;
+use crate::diagnostic::Diagnostic;
+use crate::error::ContractError;
+use crate::ledger::Ledger;
 use crate::op::*;
+use crate::registry::{OpHandler, Registry};
+use crate::schema::{check_schema, EventType, Field, Type};
 
 // #[derive(Debug, PartialEq)]
 // pub struct Token(String, String, usize);
 
-// #[derive(Debug, PartialEq)]
-// pub enum Contract {
-//   Stmt(Vec<(Vec<Event>, Vec<Op>)>),
-//   Close,
-// }
-
 // #[derive(Debug, PartialEq)]
 // pub struct Op {
 //   f: fn(Option<Box<Expr>>) -> (),
@@ -46,21 +55,114 @@ use crate::op::*;
 
 pub type Stmt = (EventOp, Ops);
 
-#[derive(Debug, PartialEq)]
+/// A whole contract source file: the event types it declares, its `when`
+/// clauses in source order, optionally terminated by `close`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contract {
+  pub(crate) events: Vec<EventType>,
+  pub(crate) stmts: Vec<Stmt>,
+  pub(crate) close: bool,
+}
+
+impl Contract {
+  /// Every clause, nested ones included, in source order: each clause
+  /// before the one it continues with, and that before the one its timeout
+  /// continues with. Clauses are numbered by their place in this list.
+  pub(crate) fn clauses(&self) -> Vec<&Stmt> {
+    let mut clauses = Vec::new();
+    for stmt in &self.stmts {
+      collect_clauses(stmt, &mut clauses);
+    }
+    clauses
+  }
+}
+
+fn collect_clauses<'a>(stmt: &'a Stmt, clauses: &mut Vec<&'a Stmt>) {
+  clauses.push(stmt);
+  let (event_op, _) = stmt;
+  for next in [event_op.next.as_ref(), event_op.timeout_next()].into_iter().flatten() {
+    if let Continuation::When(stmt) = next {
+      collect_clauses(stmt, clauses);
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct EventOp {
-  name: String,
-  event: Expr,
+  pub(crate) name: String,
+  pub(crate) event: Expr,
+  /// The `if` condition that must also hold for the clause to fire.
+  #[serde(default)]
+  pub(crate) guard: Option<Expr>,
+  /// What to do instead if the event doesn't come in time. Like `next`,
+  /// it is written to JSON by `json` itself, which can look its ops up
+  /// again.
+  #[serde(skip)]
+  pub(crate) timeout: Option<Timeout>,
+  /// What the clause goes on to once its ops have run, if anything.
+  #[serde(skip)]
+  pub(crate) next: Option<Continuation>,
+}
+
+impl EventOp {
+  /// The ops of the clause's timeout branch, if it has one.
+  pub(crate) fn timeout_ops(&self) -> &[Op] {
+    self.timeout.as_ref().map_or(&[], |timeout| &timeout.ops)
+  }
+
+  /// What the clause's timeout branch goes on to, if anything.
+  pub(crate) fn timeout_next(&self) -> Option<&Continuation> {
+    self.timeout.as_ref().and_then(|timeout| timeout.next.as_ref())
+  }
+}
+
+/// `timeout 1000 epochs else ...`: a clause still pending `epochs` epochs
+/// after it started waiting expires, and runs `ops` instead, going on to
+/// `next` if there is one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeout {
+  pub(crate) epochs: u64,
+  pub(crate) ops: Ops,
+  pub(crate) next: Option<Continuation>,
+}
+
+/// What a clause goes on to once its ops have run, which makes a contract
+/// a tree of states rather than a set of triggers: another clause, which
+/// only starts waiting then, or `close`, which ends the contract.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Continuation {
+  When(Box<Stmt>),
+  Close,
 }
 
 pub type Ops = Vec<Op>;
 
-#[derive(Debug, PartialEq)]
+/// An op invocation: the keyword, the handler registered for it and the
+/// argument as written. Ops compare by keyword and argument.
+#[derive(Clone)]
 pub struct Op {
-  f: fn(Option<Expr>) -> (),
-  arg: Option<Expr>,
+  /// The keyword the op was written with, e.g. `pay`.
+  pub(crate) name: String,
+  pub(crate) handler: Arc<dyn OpHandler>,
+  pub(crate) arg: Option<Expr>,
 }
 
-#[derive(Debug, PartialEq)]
+impl fmt::Debug for Op {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Op").field("name", &self.name).field("arg", &self.arg).finish()
+  }
+}
+
+impl PartialEq for Op {
+  fn eq(&self, other: &Self) -> bool {
+    self.name == other.name && self.arg == other.arg
+  }
+}
+
+/// Serializes with its variant as `type` and its contents as `value`, e.g.
+/// `{"type":"Integer","value":10}`; dicts are written with sorted keys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
 pub enum Expr {
   Id(String),
   Decimal(f64),
@@ -68,17 +170,34 @@ pub enum Expr {
   QuotedString(String),
   Bool(bool),
   Atom(String),
-  Dict(HashMap<String, Expr>),
+  Dict(#[serde(serialize_with = "crate::json::sorted")] HashMap<String, Expr>),
   Array(Vec<Expr>),
   Pair(Box<Expr>, Box<Expr>),
+  /// A Filecoin or Ethereum address literal such as `f01234` or `0x52...`,
+  /// checked when the contract is parsed.
+  Address(Address),
+  /// A token amount written with its unit, e.g. `1.5 FIL` or `250 nanoFIL`.
+  TokenAmount(TokenAmount),
+  /// A field of an event argument or of a bound dict, e.g. `token.amount`.
+  Field(Box<Expr>, String),
+  BinOp {
+    op: BinOp,
+    lhs: Box<Expr>,
+    rhs: Box<Expr>,
+  },
+  Not(Box<Expr>),
+  /// A pattern variable such as `$sender`. In a `when` clause it matches
+  /// any value and binds it; in op arguments it stands for that value.
+  Var(String),
   Event{
     name: String,
+    #[serde(serialize_with = "crate::json::sorted")]
     args: HashMap<String, Expr>
   },
   Token{
     name: String,
     ticker: String,
-    amount: usize
+    amount: TokenAmount
   },
   DealRequest {
     piece_cid: String,
@@ -87,12 +206,105 @@ pub enum Expr {
     label: String,
     start_epoch: i64,
     end_epoch: i64,
-    storage_price_per_epoch: usize,
-    provider_collateral: usize,
+    storage_price_per_epoch: TokenAmount,
+    provider_collateral: TokenAmount,
     extra_params_version: u64,
   }
 }
 
+impl Expr {
+  /// The variant's name, as used in error messages.
+  pub(crate) fn variant_name(&self) -> &'static str {
+    match self {
+      Expr::Id(_) => "Expr::Id",
+      Expr::Decimal(_) => "Expr::Decimal",
+      Expr::Integer(_) => "Expr::Integer",
+      Expr::QuotedString(_) => "Expr::QuotedString",
+      Expr::Bool(_) => "Expr::Bool",
+      Expr::Atom(_) => "Expr::Atom",
+      Expr::Dict(_) => "Expr::Dict",
+      Expr::Array(_) => "Expr::Array",
+      Expr::Pair(_, _) => "Expr::Pair",
+      Expr::Var(_) => "Expr::Var",
+      Expr::Address(_) => "Expr::Address",
+      Expr::TokenAmount(_) => "Expr::TokenAmount",
+      Expr::Field(_, _) => "Expr::Field",
+      Expr::BinOp{ .. } => "Expr::BinOp",
+      Expr::Not(_) => "Expr::Not",
+      Expr::Event{ .. } => "Expr::Event",
+      Expr::Token{ .. } => "Expr::Token",
+      Expr::DealRequest{ .. } => "Expr::DealRequest",
+    }
+  }
+
+  /// Names of the variables in the expression, in the order they appear.
+  pub(crate) fn vars(&self) -> Vec<&str> {
+    let mut vars = Vec::new();
+    self.collect_vars(&mut vars);
+    vars
+  }
+
+  fn collect_vars<'a>(&'a self, vars: &mut Vec<&'a str>) {
+    match self {
+      Expr::Var(name) => vars.push(name),
+      Expr::Dict(hm) | Expr::Event{ args: hm, .. } => {
+        let mut keys: Vec<_> = hm.keys().collect();
+        keys.sort();
+        for key in keys {
+          hm[key].collect_vars(vars);
+        }
+      },
+      Expr::Array(items) => items.iter().for_each(|item| item.collect_vars(vars)),
+      Expr::Pair(fst, snd) | Expr::BinOp{ lhs: fst, rhs: snd, .. } => {
+        fst.collect_vars(vars);
+        snd.collect_vars(vars);
+      },
+      Expr::Field(base, _) | Expr::Not(base) => base.collect_vars(vars),
+      _ => {},
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BinOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  And,
+  Or,
+}
+
+impl fmt::Display for BinOp {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let s = match self {
+      BinOp::Add => "+",
+      BinOp::Sub => "-",
+      BinOp::Mul => "*",
+      BinOp::Div => "/",
+      BinOp::Eq => "==",
+      BinOp::Ne => "!=",
+      BinOp::Lt => "<",
+      BinOp::Le => "<=",
+      BinOp::Gt => ">",
+      BinOp::Ge => ">=",
+      BinOp::And => "&&",
+      BinOp::Or => "||",
+    };
+    write!(f, "{}", s)
+  }
+}
+
+/// Values bound to pattern variables by a matched `when` clause, keyed by
+/// name without the `$`.
+pub type Bindings = HashMap<String, Expr>;
+
 parser!{
     fn expr[I]()(I) -> Expr
     where [I: Stream<Token = char>]
@@ -101,78 +313,384 @@ parser!{
   }
 }
 
-// fn close<I>() -> impl Parser<I, Output = Contract>
-//   where I: Stream<Token = char>,
-//         I::Error: ParseError<I::Token, I::Range, I::Position>,
-// {
-//   spaces().with(string("close")).map(|_| Contract::Close)
-// }
-fn ops<I>() -> impl Parser<I, Output = Ops>
+/// Whitespace, including `//` comments to the end of the line. The parser
+/// drops comments; `trivia` finds them again for the formatter.
+fn spaces<I>() -> impl Parser<I, Output = ()>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  parser(|input: &mut I| {
+    let mut consumed = false;
+    loop {
+      let checkpoint = input.checkpoint();
+      match input.uncons() {
+        Ok(c) if c.is_whitespace() => consumed = true,
+        Ok('/') if input.uncons().is_ok_and(|c| c == '/') => {
+          consumed = true;
+          loop {
+            let checkpoint = input.checkpoint();
+            match input.uncons() {
+              Ok('\n') => {
+                input.reset(checkpoint).ok();
+                break;
+              },
+              Ok(_) => {},
+              Err(_) => break,
+            }
+          }
+        },
+        _ => {
+          input.reset(checkpoint).ok();
+          return Ok(((), if consumed { Commit::Commit(()) } else { Commit::Peek(()) }));
+        },
+      }
+    }
+  }).expected("whitespaces")
+}
+
+/// The `close` keyword, as a whole word: `closed` is a name, not `close`
+/// followed by `d`.
+fn close<I>() -> impl Parser<I, Output = ()>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  attempt(word().and_then(|word: String| match word.as_str() {
+    "close" => Ok(()),
+    _ => Err(StreamErrorFor::<I>::expected_static_message("close")),
+  }))
+}
+
+fn contract<I>(registry: &Registry) -> impl Parser<I, Output = Contract>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  let known = registry.clone();
+  (
+    spaces(),
+    many(event_decl().skip(spaces())).then(move |decls| declared(decls, &known)),
+    many(stmt(registry).skip(spaces())),
+    optional(close().skip(spaces())),
+    eof(),
+  ).map(|(_, events, stmts, close, _)| Contract{ events, stmts, close: close.is_some() })
+}
+
+/// An event type declaration, e.g. `event BountyClaimed { claimant: address }`,
+/// and where its name is.
+fn event_decl<I>() -> impl Parser<I, Output = (I::Position, EventType)>
   where I: Stream<Token = char>,
         I::Error: ParseError<I::Token, I::Range, I::Position>,
 {
-  many(((
+  (
+    attempt(string("event").skip(not_followed_by(alpha_num()))),
     spaces(),
-    optional(string("then")),
+    position(),
+    event_name(),
     spaces(),
-    op(),
-  )).map(|(_, _, _, op)| op))
+    field_types(),
+  ).map(|(_, _, at, name, _, fields)| (at, EventType{ name, fields }))
+}
+
+/// The declared event types, unless one is already known to `registry` or
+/// declared twice.
+fn declared<I>(decls: Vec<(I::Position, EventType)>, registry: &Registry) -> impl Parser<I, Output = Vec<EventType>>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  let mut seen = HashSet::new();
+  let mut duplicate = decls.iter()
+    .find(|(_, event)| registry.event_fields(&event.name).is_some() || !seen.insert(event.name.clone()))
+    .map(|(at, event)| (at.clone(), format!("Event `{}` is already defined", event.name)));
+  let mut events = Some(decls.into_iter().map(|(_, event)| event).collect());
+  parser(move |_: &mut I| match duplicate.take() {
+    Some((at, message)) => Err(string_error::<I>(at, message)),
+    None => Ok((events.take().unwrap_or_default(), Commit::Peek(()))),
+  })
+}
+
+/// A capitalized word, which is how events are named.
+fn event_name<I>() -> impl Parser<I, Output = String>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  (
+    satisfy(char::is_uppercase).expected("an event name"),
+    many(choice((letter(), digit(), char('_')))).silent(),
+  ).map(|(first, rest): (char, String)| format!("{}{}", first, rest))
+}
+
+parser!{
+  fn field_types[I]()(I) -> Vec<Field>
+  where [I: Stream<Token = char>]
+  {
+    field_types_()
+  }
+}
+
+/// The fields of a declared event, e.g. `{ piece: cid, token: { amount: amount } }`.
+fn field_types_<I>() -> impl Parser<I, Output = Vec<Field>>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  let lex_char = |c| char(c).skip(spaces().silent());
+  let ty = or(
+    field_types().map(|fields| Type::Dict(Cow::Owned(fields))),
+    (position(), word()).then(|(at, name): (I::Position, String)| {
+      parser(move |_: &mut I| match Type::named(&name) {
+        Some(ty) => Ok((ty, Commit::Peek(()))),
+        None => Err(string_error::<I>(at.clone(), format!(
+          "`{}` is not a type, expected string, address, int, amount, bool, cid or a dict",
+          name
+        ))),
+      })
+    }),
+  );
+  let field = (word().skip(spaces()).skip(lex_char(':')), ty.skip(spaces()))
+    .map(|(name, ty): (String, Type)| Field::new(&name, ty));
+  between(lex_char('{'), char('}'), sep_by(field, lex_char(',')))
 }
 
-fn stmt<T>() -> impl Parser<T, Output = Stmt>
+parser!{
+  fn chain[I](registry: Registry)(I) -> (Ops, Option<Continuation>)
+  where [I: Stream<Token = char>]
+  {
+    chain_(registry)
+  }
+}
+
+/// Ops, each after `then` or on its own, up to the end of the clause or
+/// to what it continues with: a `when` after `then`, which takes every
+/// `then` and `timeout` after it, or `close`.
+fn chain_<I>(registry: &Registry) -> impl Parser<I, Output = (Ops, Option<Continuation>)>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  // Only commit to another step once `then` or an op keyword is in sight,
+  // so that the whitespace before a following `when` or `close` is left
+  // for the caller. After `then`, anything else is an error.
+  let then = attempt((spaces(), string("then"), not_followed_by(alpha_num()))).with(spaces());
+  let bare = attempt(spaces().skip(look_ahead(op_keyword(registry))));
+  choice((
+    bare.with(op(registry)).and(chain(registry.clone())).map(prepend),
+    then.with(step(registry)),
+    value((Vec::new(), None)),
+  ))
+}
+
+/// What follows `then` or `else`: an op and the rest of the chain, or the
+/// clause or `close` to continue with.
+fn step<I>(registry: &Registry) -> impl Parser<I, Output = (Ops, Option<Continuation>)>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  choice((
+    op(registry).and(chain(registry.clone())).map(prepend),
+    nested(registry.clone()).map(|stmt| (Vec::new(), Some(Continuation::When(Box::new(stmt))))),
+    close().map(|_| (Vec::new(), Some(Continuation::Close))),
+  ))
+}
+
+parser!{
+  fn nested[I](registry: Registry)(I) -> Stmt
+  where [I: Stream<Token = char>]
+  {
+    stmt(registry)
+  }
+}
+
+fn prepend((op, (mut ops, next)): (Op, (Ops, Option<Continuation>))) -> (Ops, Option<Continuation>) {
+  ops.insert(0, op);
+  (ops, next)
+}
+
+fn stmt<T>(registry: &Registry) -> impl Parser<T, Output = Stmt>
+  where T: Stream<Token = char>,
+        T::Error: ParseError<T::Token, T::Range, T::Position>,
+{
+  (when(), optional(guard(registry)), chain(registry.clone()), optional(timeout(registry)))
+    .map(|(event_op, guard, (ops, next), timeout)| (EventOp{ guard, timeout, next, ..event_op }, ops))
+}
+
+/// `timeout`, a number of epochs and, after `else`, what to do if the
+/// clause hasn't fired by then: ops, a clause to wait for or `close`. Once
+/// the keyword is read, anything missing is an error. A `timeout` belongs
+/// to the innermost clause without one, as `else` does to `if` elsewhere.
+fn timeout<T>(registry: &Registry) -> impl Parser<T, Output = Timeout>
   where T: Stream<Token = char>,
         T::Error: ParseError<T::Token, T::Range, T::Position>,
 {
-  (attempt(when()), ops())
+  (
+    attempt((spaces(), string("timeout"), not_followed_by(alpha_num()))),
+    integer(),
+    spaces().with(string("epoch")).skip(optional(char('s'))).skip(not_followed_by(alpha_num())),
+    spaces().with(string("else")).skip(not_followed_by(alpha_num())),
+    spaces().with(step(registry)),
+  ).map(|(_, epochs, _, _, (ops, next))| Timeout{ epochs: epochs as u64, ops, next })
 }
 
-fn op<T>() -> impl Parser<T, Output = Op>
+/// `if` followed by a condition. Once the keyword is read, a malformed
+/// condition is an error rather than the end of the clause.
+fn guard<T>(registry: &Registry) -> impl Parser<T, Output = Expr>
   where T: Stream<Token = char>,
         T::Error: ParseError<T::Token, T::Range, T::Position>,
 {
-  let kw = choice((
-    attempt(string("pay")),
-    attempt(string("propose")),
+  attempt((spaces(), string("if"), not_followed_by(alpha_num())))
+    .with(spaces())
+    .with(condition(registry.clone()))
+}
+
+fn binop_node(op: BinOp) -> impl Fn(Expr, Expr) -> Expr {
+  move |lhs, rhs| Expr::BinOp{ op, lhs: Box::new(lhs), rhs: Box::new(rhs) }
+}
+
+/// `+`, `-`, `*` and `/` over `factor`: `*` and `/` bind tighter than `+`
+/// and `-`, and operators of one level group to the left, so `$amt * 9 / 10`
+/// is `($amt * 9) / 10`, as in `parser::expression_parser`.
+fn arithmetic<I, P>(factor: P) -> impl Parser<I, Output = Expr>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+        P: Parser<I, Output = Expr>,
+{
+  let op = |c, op| char(c).skip(spaces().silent()).map(move |_| binop_node(op));
+
+  let term = chainl1(factor, op('*', BinOp::Mul).or(op('/', BinOp::Div)));
+  chainl1(term, op('+', BinOp::Add).or(op('-', BinOp::Sub)))
+}
+
+parser!{
+  pub(crate) fn condition[I](registry: Registry)(I) -> Expr
+  where [I: Stream<Token = char>]
+  {
+    condition_(registry)
+  }
+}
+
+/// Boolean conditions: `||` binds loosest, then `&&`, then a single
+/// comparison between arithmetic expressions. The ops of `registry` are
+/// keywords, so they can't name a field.
+fn condition_<I>(registry: &Registry) -> impl Parser<I, Output = Expr>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  let lex = |s: &'static str| attempt(string(s)).skip(spaces().silent());
+
+  let comparison_op = choice((
+    lex("==").map(|_| BinOp::Eq),
+    lex("!=").map(|_| BinOp::Ne),
+    lex("<=").map(|_| BinOp::Le),
+    lex(">=").map(|_| BinOp::Ge),
+    lex("<").map(|_| BinOp::Lt),
+    lex(">").map(|_| BinOp::Gt),
   ));
+  let comparison = (arithmetic(operand(registry.clone())), optional((comparison_op, arithmetic(operand(registry.clone())))))
+    .map(|(lhs, rhs)| match rhs {
+      Some((op, rhs)) => binop_node(op)(lhs, rhs),
+      None => lhs,
+    });
+
+  let and = chainl1(comparison, lex("&&").map(|_| binop_node(BinOp::And)));
+  chainl1(and, lex("||").map(|_| binop_node(BinOp::Or)))
+}
 
-  (kw, optional(spaces()), dict())
-    .map(|(op, _, args)| {
-      match op {
-        "pay" => Op{ f: pay, arg: Some(Expr::Dict(args)) },
-        "propose" => Op{ f: propose, arg: Some(Expr::Dict(args)) },
-        _ => todo!("Don't know what to do"),
-      }
-    })
+parser!{
+  fn operand[I](registry: Registry)(I) -> Expr
+  where [I: Stream<Token = char>]
+  {
+    operand_(registry)
+  }
+}
+
+/// A literal, a negated operand, a parenthesized condition or a path.
+fn operand_<I>(registry: &Registry) -> impl Parser<I, Output = Expr>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  let skip_spaces = || spaces().silent();
+
+  choice((
+    address().map(Expr::Address),
+    token_amount().map(Expr::TokenAmount),
+    integer().map(Expr::Integer),
+    attempt(decimal().map(Expr::Decimal)),
+    attempt(boolean().skip(not_followed_by(alpha_num())).map(Expr::Bool)),
+    quoted_string().map(Expr::QuotedString),
+    char('!').skip(skip_spaces()).with(operand(registry.clone())).map(|e| Expr::Not(Box::new(e))),
+    between(char('(').skip(skip_spaces()), char(')'), condition(registry.clone())),
+    path(registry),
+  ))
+    .skip(skip_spaces())
+}
+
+/// The words of the grammar itself. Op keywords come from the registry a
+/// contract is parsed against.
+pub(crate) const KEYWORDS: &[&str] = &["when", "then", "if", "timeout", "else", "close", "event"];
+
+/// A name or variable followed by any number of `.field` accesses, e.g.
+/// `token.amount` or `$deal.label`.
+fn path<I>(registry: &Registry) -> impl Parser<I, Output = Expr>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  // Keywords and the ops of `registry` can't name a field, so that
+  // `if a >= then` is an error rather than a comparison with a field called
+  // `then`. Checked before the name is read, so the error points at the
+  // keyword.
+  let registry = registry.clone();
+  let keyword = word().and_then(move |name: String| match KEYWORDS.contains(&name.as_str()) || registry.contains(&name) {
+    true => Ok(name),
+    false => Err(StreamErrorFor::<I>::expected_static_message("a keyword")),
+  });
+  let name = not_followed_by(attempt(keyword).map(|_| "keyword")).with(word());
+  (
+    or(var().map(Expr::Var), name.map(Expr::Id)),
+    many(char('.').with(word())),
+  ).map(|(base, fields): (Expr, Vec<String>)| {
+    fields.into_iter().fold(base, |base, field| Expr::Field(Box::new(base), field))
+  })
+}
+
+/// A keyword of an op in `registry`.
+fn op_keyword<T>(registry: &Registry) -> impl Parser<T, Output = String>
+  where T: Stream<Token = char>,
+        T::Error: ParseError<T::Token, T::Range, T::Position>,
+{
+  let registry = registry.clone();
+  attempt(word().silent().and_then(move |name: String| match registry.contains(&name) {
+    true => Ok(name),
+    false => Err(StreamErrorFor::<T>::expected_static_message("an op")),
+  }))
+}
+
+fn op<T>(registry: &Registry) -> impl Parser<T, Output = Op>
+  where T: Stream<Token = char>,
+        T::Error: ParseError<T::Token, T::Range, T::Position>,
+{
+  let registry = registry.clone();
+  (op_keyword(&registry), optional(spaces()), dict())
+    .and_then(move |(name, _, args)| registry.op(&name, Some(Expr::Dict(args))).ok_or_else(|| {
+      StreamErrorFor::<T>::message_format(format_args!("`{}` is not a registered op", name))
+    }))
 }
 
 fn when<T>() -> impl Parser<T, Output = EventOp>
   where T: Stream<Token = char>,
         T::Error: ParseError<T::Token, T::Range, T::Position>,
 {
+  // Past the keyword, an error inside the clause is reported where it is
+  // rather than as a clause that isn't there.
   (
-    string("when"),
+    attempt(string("when")),
     spaces(),
     event(),
-  ).map(|(name, _, event)| EventOp{ name: name.to_string(), event })
+  ).map(|(name, _, event)| EventOp{ name: name.to_string(), event, guard: None, timeout: None, next: None })
 }
 
 fn event<I>() -> impl Parser<I, Output = Expr>
   where I: Stream<Token = char>,
         I::Error: ParseError<I::Token, I::Range, I::Position>,
 {
-  let ps = choice(
-    (
-      string("Deposit"),
-      string("Pay"),
-      string("DealProposalCreated"),
-      string("DealPublished"),
-      string("DealActivated"),
-      string("DealTerminated"),
-    )
-  );
-
-  (ps, optional(spaces()), dict()).map(|(evt, _, args)|
-      Expr::Event{ name: evt.to_string(), args })
+  // Any event name will parse; whether the event type exists is checked
+  // with its schema.
+  (event_name(), optional(spaces()), dict()).map(|(name, _, args)| Expr::Event{ name, args })
 }
 
 fn atom<I>() -> impl Parser<I, Output = Expr>
@@ -182,14 +700,16 @@ fn atom<I>() -> impl Parser<I, Output = Expr>
   char(':').with(many1(or(letter(), digit())).map(Expr::Atom))
 }
 
+/// Digits not followed by a `.`. Once they are read, a number too large
+/// for a `usize` is an error rather than something else to try.
 fn integer<I>() -> impl Parser<I, Output = usize>
   where I: Stream<Token = char>,
         I::Error: ParseError<I::Token, I::Range, I::Position>,
 {
-  spaces()
-    .with(many1(digit()))
-    .skip(not_followed_by(char('.')))
-    .map(|string: String| string.parse::<usize>().unwrap())
+  attempt(spaces().with(many1(digit())).skip(not_followed_by(char('.'))))
+    .and_then(|digits: String| digits.parse::<usize>().map_err(|_| {
+      StreamErrorFor::<I>::message_format(format_args!("`{}` is too large for an integer", digits))
+    }))
 }
 
 fn integer_part<I>() -> impl Parser<I, Output = f64>
@@ -210,12 +730,135 @@ fn decimal_part<I>() -> impl Parser<I, Output = f64>
   })
 }
 
+/// A string literal: `"..."` with `\"`, `\\`, `\n`, `\r`, `\t`, `\0` and
+/// `\u{...}` escapes, or a raw `r"..."` / `r#"..."#` taken as written. An
+/// unterminated string is reported at its opening quote and a bad escape at
+/// its backslash.
 fn quoted_string<I>() -> impl Parser<I, Output = String>
   where I: Stream<Token = char>,
         I::Error: ParseError<I::Token, I::Range, I::Position>,
 {
-  between(char('"'), char('"'), many1(or(letter(), digit())))
-    .map(|chars: Vec<char>| chars.into_iter().collect())
+  let escaped = (position(), char('"')).then(|(start, _): (I::Position, _)| parser(move |input: &mut I| {
+    let mut s = String::new();
+    loop {
+      let at = input.position();
+      match input.uncons() {
+        Ok('"') => return Ok((s, Commit::Commit(()))),
+        Ok('\\') => match escape(input) {
+          Ok(c) => s.push(c),
+          Err(None) => return Err(string_error::<I>(start.clone(), "Unterminated string literal".to_string())),
+          Err(Some(message)) => return Err(string_error::<I>(at, message)),
+        },
+        Ok(c) => s.push(c),
+        Err(_) => return Err(string_error::<I>(start.clone(), "Unterminated string literal".to_string())),
+      }
+    }
+  }));
+
+  let raw = attempt((position(), char('r'), many(char('#')), char('"')))
+    .then(|(start, _, hashes, _): (I::Position, _, String, _)| parser(move |input: &mut I| {
+      let terminator = format!("\"{}", hashes);
+      let mut s = String::new();
+      loop {
+        match input.uncons() {
+          Ok(c) => s.push(c),
+          Err(_) => return Err(string_error::<I>(start.clone(), "Unterminated raw string literal".to_string())),
+        }
+        if s.ends_with(&terminator) {
+          s.truncate(s.len() - terminator.len());
+          return Ok((s, Commit::Commit(())));
+        }
+      }
+    }));
+
+  or(escaped, raw)
+}
+
+/// An unquoted address: `0x` or `f`/`t` and a protocol digit, then letters
+/// and digits up to the end of the word. No name starts with `0x`, so
+/// after it the literal has to be a valid address, and a misspelled one
+/// fails to parse; an `f` or `t` word that isn't an address is left for the
+/// caller to read as a name, e.g. `t1` or `f0count`.
+fn address<I>() -> impl Parser<I, Output = Address>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  let literal = |prefix: String, rest: String| format!("{}{}", prefix, rest)
+    .parse::<Address>()
+    .map_err(StreamErrorFor::<I>::message_format);
+  let word = || many(alpha_num()).skip(not_followed_by(char('_')));
+  let ethereum = (attempt(string("0x")).map(str::to_string), word())
+    .and_then(move |(prefix, rest)| literal(prefix, rest));
+  let filecoin = attempt(
+    ((one_of("ft".chars()), one_of("01234".chars())).map(|(n, p)| format!("{}{}", n, p)), word())
+      .and_then(move |(prefix, rest)| literal(prefix, rest)),
+  );
+  ethereum.or(filecoin)
+}
+
+/// A number followed by a unit, e.g. `1.5 FIL` or `10 attoFIL`. Only a
+/// known unit makes it an amount; once one is seen the amount has to be
+/// exact and in range, or parsing fails at the number.
+fn token_amount<I>() -> impl Parser<I, Output = TokenAmount>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  let number = (many1(digit()), optional((char('.'), many(digit()))))
+    .map(|(whole, fraction): (String, Option<(char, String)>)| match fraction {
+      Some((_, fraction)) => format!("{}.{}", whole, fraction),
+      None => whole,
+    });
+  let unit = many1(letter()).and_then(|unit: String| match unit.ends_with("FIL") {
+    true => Ok(unit),
+    false => Err(StreamErrorFor::<I>::expected_static_message("a unit")),
+  });
+  attempt((position(), number, spaces().silent(), unit))
+    .then(|(start, number, _, unit): (I::Position, String, _, String)| {
+      parser(move |_: &mut I| match TokenAmount::parse(&number, &unit) {
+        Ok(amount) => Ok((amount, Commit::Commit(()))),
+        Err(message) => Err(string_error::<I>(start.clone(), message)),
+      })
+    })
+}
+
+/// Reads what follows a backslash. `Err(None)` means the input ran out.
+fn escape<I>(input: &mut I) -> Result<char, Option<String>>
+  where I: Stream<Token = char>,
+{
+  let c = input.uncons().map_err(|_| None)?;
+  match c {
+    '"' => Ok('"'),
+    '\\' => Ok('\\'),
+    'n' => Ok('\n'),
+    'r' => Ok('\r'),
+    't' => Ok('\t'),
+    '0' => Ok('\0'),
+    'u' => {
+      let mut digits = String::new();
+      if input.uncons().map_err(|_| None)? != '{' {
+        return Err(Some("Expected `{` after `\\u`".to_string()));
+      }
+      loop {
+        match input.uncons().map_err(|_| None)? {
+          '}' => break,
+          c if c.is_ascii_hexdigit() && digits.len() < 6 => digits.push(c),
+          _ => return Err(Some("Expected up to 6 hex digits and `}` in `\\u{...}`".to_string())),
+        }
+      }
+      u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+        .ok_or_else(|| Some(format!("`\\u{{{}}}` is not a unicode character", digits)))
+    },
+    c => Err(Some(format!("Unknown escape sequence `\\{}`", c))),
+  }
+}
+
+fn string_error<I>(at: I::Position, message: String) -> Commit<Tracked<I::Error>>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  Commit::Commit(I::Error::from_error(at, StreamErrorFor::<I>::message_format(message)).into())
 }
 
 pub(crate) fn decimal<I>() -> impl Parser<I, Output = f64>
@@ -258,6 +901,13 @@ fn dict<I>() -> impl Parser<I, Output = HashMap<String, Expr>>
   })
 }
 
+fn var<I>() -> impl Parser<I, Output = String>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
+  char('$').with(word())
+}
+
 fn boolean<I>() -> impl Parser<I, Output = bool>
   where I: Stream<Token = char>,
         I::Error: ParseError<I::Token, I::Range, I::Position>,
@@ -275,11 +925,17 @@ fn word<I>() -> impl Parser<I, Output = String>
   many1(choice((letter(), digit(), char('_')))).map(|chars: String| chars)
 }
 
-fn expr_<'a, I>() -> impl Parser<I, Output = Expr>
+fn expr_<I>() -> impl Parser<I, Output = Expr>
   where I: Stream<Token = char>,
         I::Error: ParseError<I::Token, I::Range, I::Position>,
 {
+  arithmetic(primary())
+}
 
+fn primary<I>() -> impl Parser<I, Output = Expr>
+  where I: Stream<Token = char>,
+        I::Error: ParseError<I::Token, I::Range, I::Position>,
+{
   let skip_spaces = || spaces().silent();
   let lex_char = |c| char(c).skip(skip_spaces());
 
@@ -288,34 +944,371 @@ fn expr_<'a, I>() -> impl Parser<I, Output = Expr>
 
   let array = between(lex_char('['), lex_char(']'), comma_list);
 
+  // A parenthesized expression is a pair if it has a second element.
   let pair = (lex_char('('),
               expr(),
-              lex_char(','),
-              expr(),
+              optional(lex_char(',').with(expr())),
               lex_char(')'))
-    .map(|(_, fst, _, snd, _)| Expr::Pair(Box::new(fst), Box::new(snd)));
+    .map(|(_, fst, snd, _)| match snd {
+      Some(snd) => Expr::Pair(Box::new(fst), Box::new(snd)),
+      None => fst,
+    });
 
   choice((
-    attempt(integer().map(Expr::Integer)),
+    address().map(Expr::Address),
+    token_amount().map(Expr::TokenAmount),
+    integer().map(Expr::Integer),
     decimal().map(Expr::Decimal),
-    event(),
+    // A capitalized name is only an event if a dict follows it.
+    attempt(look_ahead((event_name(), optional(spaces()), char('{')))).with(event()),
     attempt(boolean().map(Expr::Bool)),
-    word().map(Expr::Id),
-    dict().map(Expr::Dict),
     quoted_string().map(Expr::QuotedString),
+    // A value is followed by `,` or a closing bracket, never an op, so only
+    // the grammar's own keywords are reserved here.
+    path(&Registry::empty()),
+    dict().map(Expr::Dict),
     array.map(Expr::Array),
     pair,
   ))
       .skip(skip_spaces())
 }
 
-fn decode(input: &str) -> Result<Expr, String> {
-  match expr().parse(input) {
+pub(crate) fn decode(input: &str) -> Result<Expr, Box<Diagnostic>> {
+  match expr().easy_parse(position::Stream::new(input)) {
     Ok((expr, _)) => Ok(expr),
-    Err(err) => Err(format!("{} in `{}`", err, input)),
+    Err(err) => Err(Box::new(Diagnostic::from_parse_error(input, err))),
+  }
+}
+
+/// Parses a whole contract source, reporting the first syntax error as a
+/// diagnostic with its line, column and source snippet. Literal piece CIDs
+/// are validated as well, every variable an op uses must be bound by its
+/// `when` clause, and events and op arguments must fit their schemas.
+pub fn parse_contract(input: &str) -> Result<Contract, ContractError> {
+  parse_contract_with(input, &Registry::new())
+}
+
+/// `parse_contract` with the ops and events of `registry` rather than the
+/// builtin ones.
+pub fn parse_contract_with(input: &str, registry: &Registry) -> Result<Contract, ContractError> {
+  let contract = parse_syntax(input, registry)?;
+  check(&contract, registry)?;
+  Ok(contract)
+}
+
+/// Parses a contract without `check`ing it, for tools such as the formatter
+/// that only care about its syntax.
+pub(crate) fn parse_syntax(input: &str, registry: &Registry) -> Result<Contract, ContractError> {
+  match contract(registry).easy_parse(position::Stream::new(input)) {
+    Ok((contract, _)) => Ok(contract),
+    Err(err) => Err(ContractError::Parse(Box::new(Diagnostic::from_parse_error(input, err)))),
   }
 }
 
+/// The checks a contract has to pass beyond its syntax, however it was
+/// read.
+pub(crate) fn check(contract: &Contract, registry: &Registry) -> Result<(), ContractError> {
+  check_piece_cids(contract)?;
+  check_vars(contract)?;
+  check_schema(contract, registry)
+}
+
+fn check_vars(contract: &Contract) -> Result<(), ContractError> {
+  for stmt in &contract.stmts {
+    check_clause_vars(stmt, &[])?;
+  }
+  Ok(())
+}
+
+/// A nested clause sees the variables of the clauses it continues from as
+/// well as its own. A timeout runs when no event came, so its branch only
+/// sees the former.
+fn check_clause_vars<'a>((event_op, ops): &'a Stmt, outer: &[&'a str]) -> Result<(), ContractError> {
+  let bound: Vec<&str> = outer.iter().copied().chain(event_op.event.vars()).collect();
+  let args = |ops: &'a [Op]| ops.iter().filter_map(|op| op.arg.as_ref());
+  let unbound = event_op.guard
+    .iter()
+    .chain(args(ops))
+    .flat_map(|expr| expr.vars())
+    .find(|var| !bound.contains(var))
+    .or_else(|| args(event_op.timeout_ops()).flat_map(|expr| expr.vars()).find(|var| !outer.contains(var)));
+  if let Some(var) = unbound {
+    return Err(ContractError::UnboundVariable(var.to_string()));
+  }
+  if let Some(Continuation::When(stmt)) = &event_op.next {
+    check_clause_vars(stmt, &bound)?;
+  }
+  if let Some(Continuation::When(stmt)) = event_op.timeout_next() {
+    check_clause_vars(stmt, outer)?;
+  }
+  Ok(())
+}
+
+pub fn read_contract_file(path: &str) -> Result<Contract, ContractError> {
+  let contents = std::fs::read_to_string(path)
+    .map_err(|err| ContractError::Io(format!("Could not read `{}`: {}", path, err)))?;
+  parse_contract(&contents).map_err(|err| match err {
+    ContractError::Parse(diagnostic) => ContractError::Parse(Box::new((*diagnostic).with_file(path))),
+    err => err,
+  })
+}
+
+/// A `//` comment, kept out of the syntax tree and found again by `trivia`
+/// so that the formatter can put it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Comment {
+  /// The comment including its `//`, without trailing whitespace.
+  pub text: String,
+  pub anchor: Anchor,
+  /// Whether code precedes the comment on its line. A trailing comment
+  /// belongs to that code, any other to the code after it.
+  pub trailing: bool,
+}
+
+/// The part of a contract a comment is attached to. Clauses and the ops of
+/// a clause are counted from zero in source order.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Anchor {
+  /// An `event` declaration.
+  Event(usize),
+  /// A `when` line, with the `then` or `else` before it if it is nested.
+  Clause(usize),
+  /// The `if` condition of a clause.
+  Guard(usize),
+  /// The `n`th op of a clause. The ops of its timeout branch are counted
+  /// on from its others, and a `close` it goes on to counts as an op.
+  Op(usize, usize),
+  /// The `timeout` line of a clause.
+  Timeout(usize),
+  /// An entry of the event pattern (`op` is `None`) or of an op argument,
+  /// by its key path from the outermost dict.
+  Entry { clause: usize, op: Option<usize>, path: Vec<String> },
+  /// The closing brace of a dict, for comments after its last entry.
+  DictEnd { clause: usize, op: Option<usize>, path: Vec<String> },
+  Close,
+  /// The end of the file.
+  End,
+}
+
+#[derive(Debug, PartialEq)]
+enum Lexeme {
+  /// A name, number or keyword, with any `$` or `.field`s.
+  Word(String),
+  Str(String),
+  Punct(char),
+  Comment(String),
+}
+
+/// Splits source into just enough tokens to place its comments. Strings are
+/// read with the parser's own `quoted_string` so that quoted dict keys come
+/// out as the parser sees them.
+fn lex(source: &str) -> Vec<(usize, Lexeme)> {
+  let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '$' || c == '.';
+  let mut lexemes = Vec::new();
+  let mut line = 0;
+  let mut rest = source;
+  while let Some(c) = rest.chars().next() {
+    let len = if c == '\n' {
+      line += 1;
+      1
+    } else if c.is_whitespace() {
+      c.len_utf8()
+    } else if rest.starts_with("//") {
+      let text = rest.split('\n').next().unwrap_or(rest);
+      lexemes.push((line, Lexeme::Comment(text.trim_end().to_string())));
+      text.len()
+    } else if c == '"' || rest.starts_with("r\"") || rest.starts_with("r#") {
+      match quoted_string::<&str>().parse(rest) {
+        Ok((s, after)) => {
+          lexemes.push((line, Lexeme::Str(s)));
+          let len = rest.len() - after.len();
+          line += rest[..len].matches('\n').count();
+          len
+        },
+        Err(_) => rest.len(),
+      }
+    } else if is_word(c) {
+      let word = rest.split(|c| !is_word(c)).next().unwrap_or(rest);
+      lexemes.push((line, Lexeme::Word(word.to_string())));
+      word.len()
+    } else {
+      lexemes.push((line, Lexeme::Punct(c)));
+      c.len_utf8()
+    };
+    rest = &rest[len..];
+  }
+  lexemes
+}
+
+/// A `{`, `[` or `(` being read. Dicts track the key of the entry being
+/// read; everything inside anything else belongs to `anchor`.
+struct Frame {
+  dict: bool,
+  path: Vec<String>,
+  key: Option<String>,
+  anchor: Anchor,
+}
+
+/// The comments of a contract that parses against `registry`, each with
+/// what it belongs to.
+pub fn trivia(source: &str, registry: &Registry) -> Vec<Comment> {
+  let lexemes = lex(source);
+
+  // The anchor of every token, in order.
+  let mut anchors: Vec<(usize, Anchor)> = Vec::new();
+  let mut clause = 0;
+  let mut clauses = 0;
+  let mut op = None;
+  let mut ops = 0;
+  let mut in_guard = false;
+  let mut in_timeout = false;
+  // The clauses the current one is nested in, innermost last, each with
+  // its op count and whether it has a timeout yet.
+  let mut outer: Vec<(usize, usize, bool)> = Vec::new();
+  let mut after_branch = false;
+  let mut decl = None;
+  let mut decls = 0;
+  let mut frames: Vec<Frame> = Vec::new();
+  for (i, (line, lexeme)) in lexemes.iter().enumerate() {
+    if let Lexeme::Comment(_) = lexeme {
+      continue;
+    }
+    let next = lexemes[i + 1..].iter().find(|(_, l)| !matches!(l, Lexeme::Comment(_))).map(|(_, l)| l);
+    let next_is_colon = next == Some(&Lexeme::Punct(':'));
+    let branch = std::mem::take(&mut after_branch);
+    let owner = match (decl, op) {
+      (Some(d), _) => Anchor::Event(d),
+      (None, Some(k)) => Anchor::Op(clause, k),
+      (None, None) if in_guard => Anchor::Guard(clause),
+      (None, None) if in_timeout => Anchor::Timeout(clause),
+      (None, None) => Anchor::Clause(clause),
+    };
+    let entry = |path: &[String], key: Option<&String>| Anchor::Entry {
+      clause,
+      op,
+      path: path.iter().chain(key).cloned().collect(),
+    };
+
+    let anchor = match frames.last_mut() {
+      None => match lexeme {
+        Lexeme::Word(w) if w == "event" => {
+          decl = Some(decls);
+          decls += 1;
+          Anchor::Event(decls - 1)
+        },
+        Lexeme::Word(w) if w == "when" => {
+          match branch {
+            true => outer.push((clause, ops, in_timeout)),
+            false => outer.clear(),
+          }
+          decl = None;
+          clause = clauses;
+          clauses += 1;
+          op = None;
+          ops = 0;
+          in_guard = false;
+          in_timeout = false;
+          Anchor::Clause(clause)
+        },
+        Lexeme::Word(w) if w == "if" => {
+          in_guard = true;
+          Anchor::Guard(clause)
+        },
+        Lexeme::Word(w) if w == "then" || w == "else" => {
+          after_branch = true;
+          match next {
+            Some(Lexeme::Word(w)) if w == "when" => Anchor::Clause(clauses),
+            _ => Anchor::Op(clause, ops),
+          }
+        },
+        // A clause's timeout comes after those of the clauses nested in it.
+        Lexeme::Word(w) if w == "timeout" => {
+          while in_timeout {
+            match outer.pop() {
+              Some(enclosing) => (clause, ops, in_timeout) = enclosing,
+              None => break,
+            }
+          }
+          op = None;
+          in_guard = false;
+          in_timeout = true;
+          Anchor::Timeout(clause)
+        },
+        Lexeme::Word(w) if registry.contains(w) => {
+          op = Some(ops);
+          ops += 1;
+          in_guard = false;
+          Anchor::Op(clause, ops - 1)
+        },
+        Lexeme::Word(w) if w == "close" && branch => {
+          op = Some(ops);
+          ops += 1;
+          Anchor::Op(clause, ops - 1)
+        },
+        Lexeme::Word(w) if w == "close" => Anchor::Close,
+        Lexeme::Punct('{') if !in_guard && decl.is_none() => {
+          frames.push(Frame{ dict: true, path: vec![], key: None, anchor: owner.clone() });
+          owner
+        },
+        Lexeme::Punct('(' | '[' | '{') => {
+          frames.push(Frame{ dict: false, path: vec![], key: None, anchor: owner.clone() });
+          owner
+        },
+        _ => owner,
+      },
+      Some(frame) if frame.dict => match lexeme {
+        Lexeme::Punct('}') => {
+          let frame = frames.pop().unwrap();
+          Anchor::DictEnd{ clause, op, path: frame.path }
+        },
+        Lexeme::Word(key) | Lexeme::Str(key) if frame.key.is_none() && next_is_colon => {
+          frame.key = Some(key.clone());
+          entry(&frame.path, frame.key.as_ref())
+        },
+        Lexeme::Punct(',') => {
+          let anchor = entry(&frame.path, frame.key.as_ref());
+          frame.key = None;
+          anchor
+        },
+        Lexeme::Punct(c @ ('{' | '[' | '(')) => {
+          let anchor = entry(&frame.path, frame.key.as_ref());
+          let path: Vec<String> = frame.path.iter().chain(&frame.key).cloned().collect();
+          frames.push(Frame{ dict: *c == '{', path, key: None, anchor: anchor.clone() });
+          anchor
+        },
+        _ => entry(&frame.path, frame.key.as_ref()),
+      },
+      Some(frame) => match lexeme {
+        Lexeme::Punct(']' | ')' | '}') => frames.pop().unwrap().anchor,
+        Lexeme::Punct('{' | '[' | '(') => {
+          let anchor = frame.anchor.clone();
+          frames.push(Frame{ dict: false, path: vec![], key: None, anchor: anchor.clone() });
+          anchor
+        },
+        _ => frame.anchor.clone(),
+      },
+    };
+    anchors.push((*line, anchor));
+  }
+
+  let mut comments = Vec::new();
+  let mut seen: usize = 0;
+  for (line, lexeme) in &lexemes {
+    match lexeme {
+      Lexeme::Comment(text) => {
+        let previous = seen.checked_sub(1).map(|i| &anchors[i]);
+        let (anchor, trailing) = match previous {
+          Some((prev_line, anchor)) if prev_line == line => (anchor.clone(), true),
+          _ => (anchors.get(seen).map_or(Anchor::End, |(_, a)| a.clone()), false),
+        };
+        comments.push(Comment{ text: text.clone(), anchor, trailing });
+      },
+      _ => seen += 1,
+    }
+  }
+  comments
+}
+
 #[cfg(test)]
 mod tests {
   use std::hash::Hash;
@@ -323,6 +1316,13 @@ mod tests {
 use super::*;
   use crate::op::pay;
 
+  fn parse_error(input: &str) -> Diagnostic {
+    match parse_contract(input) {
+      Err(ContractError::Parse(diagnostic)) => *diagnostic,
+      result => panic!("Expected a parse error, got {:?}", result),
+    }
+  }
+
   #[test]
   fn test_word() {
     let result = word().parse("hello").unwrap().0;
@@ -454,7 +1454,7 @@ use super::*;
 
   #[test]
   fn test_pay_op() {
-    let e = op().parse(r#"pay {
+    let e = op(&Registry::new()).parse(r#"pay {
       to: "addressA",
       token: {
         name: "world",
@@ -471,12 +1471,12 @@ use super::*;
     inner.insert("token".to_string(), Expr::Dict(inner_token));
 
     let arg = Expr::Dict(inner);
-    assert_eq!(e, Op{ f: pay, arg: Some(arg) });
+    assert_eq!(e, Registry::new().op("pay", Some(arg)).unwrap());
   }
 
   #[test]
   fn test_propose_op() {
-    let e = op().parse(r#"propose {
+    let e = op(&Registry::new()).parse(r#"propose {
       deal_request: {
         piece_cid: "QmX",
         piece_size: 123,
@@ -505,12 +1505,12 @@ use super::*;
     inner.insert("deal_request".to_string(), Expr::Dict(deal_request));
 
     let arg = Expr::Dict(inner);
-    assert_eq!(e, Op{ f: propose, arg: Some(arg) });
+    assert_eq!(e, Registry::new().op("propose", Some(arg)).unwrap());
   }
 
   #[test]
   fn test_ops() {
-    let e = ops().parse(r#"pay {
+    let (e, next) = chain(Registry::new()).parse(r#"pay {
       to: "addressA",
       token: {
         name: "world",
@@ -543,7 +1543,7 @@ use super::*;
     inner.insert("token".to_string(), Expr::Dict(inner_token));
 
     // let arg = Expr::Dict(inner);
-    let op1 = Op{ f: pay, arg: Some(Expr::Dict(inner)) };
+    let op1 = Registry::new().op("pay", Some(Expr::Dict(inner))).unwrap();
 
     let mut inner = HashMap::new();
     inner.insert("to".to_string(), Expr::QuotedString("addressB".to_string()));
@@ -553,7 +1553,7 @@ use super::*;
     inner_token.insert("amount".to_string(), Expr::Integer(100));
     inner.insert("token".to_string(), Expr::Dict(inner_token));
 
-    let op2 = Op{ f: pay, arg: Some(Expr::Dict(inner)) };
+    let op2 = Registry::new().op("pay", Some(Expr::Dict(inner))).unwrap();
 
     let mut inner = HashMap::new();
     inner.insert("to".to_string(), Expr::QuotedString("addressC".to_string()));
@@ -563,9 +1563,10 @@ use super::*;
     inner_token.insert("amount".to_string(), Expr::Integer(20));
     inner.insert("token".to_string(), Expr::Dict(inner_token));
 
-    let op3 = Op{ f: pay, arg: Some(Expr::Dict(inner)) };
+    let op3 = Registry::new().op("pay", Some(Expr::Dict(inner))).unwrap();
 
     assert_eq!(e, vec![op1, op2, op3]);
+    assert_eq!(next, None);
   }
 
 
@@ -587,13 +1588,13 @@ use super::*;
     token.insert("amount".to_string(), Expr::Integer(123));
     args.insert("token".to_string(), Expr::Dict(token));
     let event = Expr::Event{ name: "Deposit".to_string(), args };
-    let event_op = EventOp{ name: "when".to_string(), event };
+    let event_op = EventOp{ name: "when".to_string(), event, guard: None, timeout: None, next: None };
     assert_eq!(e, event_op);
   }
 
   #[test]
   fn test_stmt() {
-    let e = stmt().parse(r#"when Deposit {
+    let e = stmt(&Registry::new()).parse(r#"when Deposit {
       from: "addressA",
       token: {
         name: "world",
@@ -640,7 +1641,7 @@ use super::*;
     args.insert("token".to_string(), Expr::Dict(token));
 
     let event = Expr::Event{ name: "Deposit".to_string(), args };
-    let event_op = EventOp{ name: "when".to_string(), event };
+    let event_op = EventOp{ name: "when".to_string(), event, guard: None, timeout: None, next: None };
 
     let mut pargs = HashMap::new();
     pargs.insert("to".to_string(), Expr::QuotedString("addressB".to_string()));
@@ -651,7 +1652,7 @@ use super::*;
 
     pargs.insert("token".to_string(), Expr::Dict(token));
 
-    let op1 = Op{ f: pay, arg: Some(Expr::Dict(pargs)) };
+    let op1 = Registry::new().op("pay", Some(Expr::Dict(pargs))).unwrap();
 
     let mut pargs = HashMap::new();
     pargs.insert("to".to_string(), Expr::QuotedString("addressC".to_string()));
@@ -662,7 +1663,7 @@ use super::*;
 
     pargs.insert("token".to_string(), Expr::Dict(token));
 
-    let op2 = Op{ f: pay, arg: Some(Expr::Dict(pargs)) };
+    let op2 = Registry::new().op("pay", Some(Expr::Dict(pargs))).unwrap();
 
     let mut pargs = HashMap::new();
     let mut deal_request = HashMap::new();
@@ -677,7 +1678,7 @@ use super::*;
     deal_request.insert("extra_params_version".to_string(), Expr::Integer(123));
     pargs.insert("deal_request".to_string(), Expr::Dict(deal_request));
 
-    let op3 = Op{ f: propose, arg: Some(Expr::Dict(pargs)) };
+    let op3 = Registry::new().op("propose", Some(Expr::Dict(pargs))).unwrap();
 
     let expected = (event_op, vec![op1, op2, op3]);
 
@@ -685,6 +1686,121 @@ use super::*;
     assert_eq!(e, expected);
   }
 
+  #[test]
+  fn test_stmt_with_guard() {
+    let (event_op, ops) = stmt(&Registry::new()).parse(r#"when Deposit { from: $sender } if token.amount >= 100 && !(from == "addressB") then
+      pay { to: $sender }"#).unwrap().0;
+    let amount = Expr::Field(Box::new(Expr::Id("token".to_string())), "amount".to_string());
+    let from = Expr::BinOp{
+      op: BinOp::Eq,
+      lhs: Box::new(Expr::Id("from".to_string())),
+      rhs: Box::new(Expr::QuotedString("addressB".to_string())),
+    };
+    assert_eq!(event_op.guard, Some(Expr::BinOp{
+      op: BinOp::And,
+      lhs: Box::new(Expr::BinOp{ op: BinOp::Ge, lhs: Box::new(amount), rhs: Box::new(Expr::Integer(100)) }),
+      rhs: Box::new(Expr::Not(Box::new(from))),
+    }));
+    assert_eq!(ops.len(), 1);
+
+    let (event_op, _) = stmt(&Registry::new()).parse("when Pay {} pay {}").unwrap().0;
+    assert_eq!(event_op.guard, None);
+
+    let (event_op, _) = stmt(&Registry::new()).parse("when Pay {} if $a.b || c < .5 then pay {}").unwrap().0;
+    let field = Expr::Field(Box::new(Expr::Var("a".to_string())), "b".to_string());
+    match event_op.guard {
+      Some(Expr::BinOp{ op: BinOp::Or, lhs, .. }) => assert_eq!(*lhs, field),
+      guard => panic!("Expected `||`, got {:?}", guard),
+    }
+  }
+
+  #[test]
+  fn test_stmt_with_timeout() {
+    let registry = Registry::new();
+    let (event_op, ops) = stmt(&registry).parse(r#"when Deposit { from: $sender } if $sender == "a"
+    then pay { to: $sender }
+    timeout 1000 epochs else pay { to: "b" } then propose {}"#).unwrap().0;
+    assert!(event_op.guard.is_some());
+    assert_eq!(ops.len(), 1);
+    let timeout = event_op.timeout.unwrap();
+    assert_eq!(timeout.epochs, 1000);
+    assert_eq!(timeout.ops.iter().map(|op| op.name.as_str()).collect::<Vec<_>>(), vec!["pay", "propose"]);
+
+    let (event_op, ops) = stmt(&registry).parse("when Pay {} timeout 1 epoch else pay {}").unwrap().0;
+    assert!(ops.is_empty());
+    assert_eq!(event_op.timeout_ops().len(), 1);
+  }
+
+  #[test]
+  fn test_timeout_errors() {
+    let err = parse_error("when Pay {} timeout soon else pay {}");
+    assert_eq!((err.line, err.column), (1, 21));
+    let err = parse_error("when Pay {} timeout 10 epochs pay {}");
+    assert_eq!(err.found.as_deref(), Some("`pay`"), "{}", err);
+    let err = parse_error("when Pay {} timeout 10 epochs else\nwhen");
+    assert_eq!((err.line, err.column), (2, 5));
+    // The branch runs without an event, so it has nothing to bind from.
+    assert_eq!(
+      parse_contract("when Pay { to: $to } timeout 10 epochs else pay { to: $to }").unwrap_err(),
+      ContractError::UnboundVariable("to".to_string())
+    );
+  }
+
+  #[test]
+  fn test_stmt_nested() {
+    let registry = Registry::new();
+    let (event_op, ops) = stmt(&registry).parse(r#"when Deposit { from: $sender }
+    then pay { to: "a" }
+    then when DealActivated {} then pay { to: $sender } timeout 10 epochs else close
+    timeout 20 epochs else when Pay {} then close"#).unwrap().0;
+    assert_eq!(ops.len(), 1);
+    let (nested, nested_ops) = match event_op.next {
+      Some(Continuation::When(stmt)) => *stmt,
+      next => panic!("Expected a nested clause, got {:?}", next),
+    };
+    assert_eq!(nested.event.vars(), Vec::<&str>::new());
+    assert_eq!(nested_ops.len(), 1);
+    // The first timeout is the nested clause's, the second its parent's.
+    assert_eq!(nested.timeout.as_ref().map(|timeout| timeout.epochs), Some(10));
+    assert_eq!(nested.timeout_next(), Some(&Continuation::Close));
+    let timeout = event_op.timeout.unwrap();
+    assert_eq!(timeout.epochs, 20);
+    assert!(timeout.ops.is_empty());
+    match timeout.next {
+      Some(Continuation::When(stmt)) => assert_eq!(stmt.0.next, Some(Continuation::Close)),
+      next => panic!("Expected a nested clause, got {:?}", next),
+    }
+
+    let contract = parse_syntax("when Pay {} then pay {} then close close", &registry).unwrap();
+    assert_eq!(contract.stmts[0].0.next, Some(Continuation::Close));
+    assert!(contract.close);
+    let contract = parse_syntax("when Pay {} then when Pay {} then when Pay {} when Deposit {}", &registry).unwrap();
+    assert_eq!(contract.stmts.len(), 2);
+    assert_eq!(contract.clauses().len(), 4);
+  }
+
+  #[test]
+  fn test_nested_errors() {
+    let err = parse_error("when Pay {} then when");
+    assert_eq!((err.line, err.column), (1, 22));
+    let err = parse_error("when Pay {} then when Pay {}\nthen");
+    assert_eq!((err.line, err.column), (2, 5));
+
+    // A nested clause sees what the clauses before it bound, but not the
+    // other way round, and a timeout only what came before its clause.
+    let vars = |source| check_vars(&parse_syntax(source, &Registry::new()).unwrap());
+    assert_eq!(vars("when Pay { to: $to } then when Pay { amount: $n } then pay { to: $to, amount: $n }"), Ok(()));
+    assert_eq!(vars("when Pay { to: $to } then when Pay {} timeout 1 epoch else pay { to: $to }"), Ok(()));
+    assert_eq!(
+      vars("when Pay {} then when Pay { to: $to } timeout 1 epoch else pay { to: $to }"),
+      Err(ContractError::UnboundVariable("to".to_string()))
+    );
+    assert_eq!(
+      vars("when Pay { to: $to } timeout 1 epoch else when Pay {} then pay { to: $to }"),
+      Err(ContractError::UnboundVariable("to".to_string()))
+    );
+  }
+
   #[test]
   fn test_event() {
     let e = event().parse(r#"Deposit {
@@ -772,6 +1888,66 @@ use super::*;
     assert_eq!(result, "world");
     let result = quoted_string().parse("\"hey12\"").unwrap().0;
     assert_eq!(result, "hey12");
+
+    let parse = |input: &str| quoted_string().parse(input).map(|(s, rest)| (s, rest.to_string()));
+    assert_eq!(parse(r#""""#), Ok((String::new(), String::new())));
+    assert_eq!(parse(r#""my deal - v2/final 🚀", x"#), Ok(("my deal - v2/final 🚀".to_string(), ", x".to_string())));
+    assert_eq!(parse(r#""https://example.com/a?b=c""#).unwrap().0, "https://example.com/a?b=c");
+    assert_eq!(parse(r#""say \"hi\"\n\tand \\ leave\0""#).unwrap().0, "say \"hi\"\n\tand \\ leave\0");
+    assert_eq!(parse(r#""\u{1F680} \u{e9}""#).unwrap().0, "🚀 é");
+    assert_eq!(parse("\"two\nlines\"").unwrap().0, "two\nlines");
+  }
+
+  #[test]
+  fn test_raw_string() {
+    let parse = |input: &str| quoted_string().parse(input).map(|(s, _)| s);
+    assert_eq!(parse(r#"r"C:\path\n""#), Ok(r"C:\path\n".to_string()));
+    assert_eq!(parse(r###"r#"say "hi""#"###), Ok(r#"say "hi""#.to_string()));
+    assert_eq!(parse(r###"r##"a "# b"##"###), Ok(r##"a "# b"##.to_string()));
+    assert_eq!(parse(r#"r"""#), Ok(String::new()));
+
+    // A bare `r` is still a name.
+    assert_eq!(decode("rust"), Ok(Expr::Id("rust".to_string())));
+    assert_eq!(decode(r#"[r"\d", "\\d"]"#), Ok(Expr::Array(vec![
+      Expr::QuotedString(r"\d".to_string()),
+      Expr::QuotedString(r"\d".to_string()),
+    ])));
+  }
+
+  #[test]
+  fn test_string_errors() {
+    let error = |input: &str| {
+      let err = quoted_string().easy_parse(position::Stream::new(input)).unwrap_err();
+      Diagnostic::from_parse_error(input, err)
+    };
+
+    let err = error(r#""abc\q""#);
+    assert_eq!(err.column, 5);
+    assert_eq!(err.summary(), "Unknown escape sequence `\\q`");
+
+    let err = error(r#""\u{110000}""#);
+    assert_eq!(err.column, 2);
+    assert_eq!(err.summary(), "`\\u{110000}` is not a unicode character");
+    assert!(error(r#""\u{12x}""#).summary().contains("Expected up to 6 hex digits"));
+    assert!(error(r#""\u12""#).summary().contains("Expected `{` after `\\u`"));
+
+    let err = error(r#""unterminated"#);
+    assert_eq!(err.column, 1);
+    assert_eq!(err.summary(), "Unterminated string literal");
+    assert!(error(r##"r#"raw""##).summary().contains("Unterminated raw string literal"));
+
+    let err = parse_error(r#"when Deposit {
+      from: "addressA",
+      label: "a \x"
+    }"#);
+    assert_eq!((err.line, err.column), (3, 17));
+
+    let err = parse_error(r#"when Deposit {
+      from: "addressA,
+      label: "x"
+    }"#);
+    assert_eq!(err.line, 3);
+    assert!(err.found.is_some(), "{}", err);
   }
 
   #[test]
@@ -843,4 +2019,343 @@ use super::*;
       ])),
     )));
   }
-}
\ No newline at end of file
+  #[test]
+  fn test_var() {
+    assert_eq!(var().parse("$sender").unwrap().0, "sender".to_string());
+    assert!(var().parse("sender").is_err());
+    assert_eq!(decode("$amt"), Ok(Expr::Var("amt".to_string())));
+
+    let e = decode(r#"{ from: $sender, token: { amount: $amt } }"#).unwrap();
+    assert_eq!(e.vars(), vec!["sender", "amt"]);
+  }
+
+  #[test]
+  fn test_arithmetic() {
+    let binop = |op, lhs, rhs| Expr::BinOp{ op, lhs: Box::new(lhs), rhs: Box::new(rhs) };
+    let var = |name: &str| Expr::Var(name.to_string());
+    let id = |name: &str| Expr::Id(name.to_string());
+
+    assert_eq!(decode("$deposit * 0.9"), Ok(binop(BinOp::Mul, var("deposit"), Expr::Decimal(0.9))));
+    assert_eq!(decode("start + 518400"), Ok(binop(BinOp::Add, id("start"), Expr::Integer(518400))));
+
+    // `*` and `/` bind tighter than `+` and `-`, and each level groups to
+    // the left.
+    assert_eq!(decode("10 + 2 - 3"), Ok(binop(
+      BinOp::Sub,
+      binop(BinOp::Add, Expr::Integer(10), Expr::Integer(2)),
+      Expr::Integer(3),
+    )));
+    assert_eq!(decode("$amt * 9 / 10"), Ok(binop(
+      BinOp::Div,
+      binop(BinOp::Mul, var("amt"), Expr::Integer(9)),
+      Expr::Integer(10),
+    )));
+    assert_eq!(decode("1 + 4 * 2 / 2 - 1"), Ok(binop(
+      BinOp::Sub,
+      binop(
+        BinOp::Add,
+        Expr::Integer(1),
+        binop(BinOp::Div, binop(BinOp::Mul, Expr::Integer(4), Expr::Integer(2)), Expr::Integer(2)),
+      ),
+      Expr::Integer(1),
+    )));
+    assert_eq!(decode("($a + 1) * 2"), Ok(binop(
+      BinOp::Mul,
+      binop(BinOp::Add, var("a"), Expr::Integer(1)),
+      Expr::Integer(2),
+    )));
+
+    assert_eq!(decode("token.amount * 2"), Ok(binop(
+      BinOp::Mul,
+      Expr::Field(Box::new(id("token")), "amount".to_string()),
+      Expr::Integer(2),
+    )));
+
+    let e = decode("{ amount: $amt * 2, to: $sender }").unwrap();
+    assert_eq!(e.vars(), vec!["amt", "sender"]);
+  }
+
+  #[test]
+  fn test_address() {
+    let address = |s: &str| Expr::Address(s.parse().unwrap());
+    assert_eq!(decode("f01234"), Ok(address("f01234")));
+    assert_eq!(
+      decode("[t17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy, 0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed]"),
+      Ok(Expr::Array(vec![
+        address("t17uoq6tp427uzv7fztkbsnn64iwotfrristwpryy"),
+        address("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"),
+      ]))
+    );
+    // Names that only start like an address are still names.
+    assert_eq!(decode("from"), Ok(Expr::Id("from".to_string())));
+    assert_eq!(decode("t"), Ok(Expr::Id("t".to_string())));
+    assert_eq!(decode("0"), Ok(Expr::Integer(0)));
+  }
+
+  #[test]
+  fn test_token_amount() {
+    let amount = |s: &str| Expr::TokenAmount(s.parse().unwrap());
+    let binop = |op, lhs, rhs| Expr::BinOp{ op, lhs: Box::new(lhs), rhs: Box::new(rhs) };
+    assert_eq!(decode("1.5 FIL"), Ok(amount("1.5 FIL")));
+    assert_eq!(decode("250nanoFIL"), Ok(amount("250 nanoFIL")));
+    assert_eq!(
+      decode("{ amount: 10 attoFIL, fee: 1. milliFIL }"),
+      decode("{ amount: 10 attoFIL, fee: 1000 microFIL }")
+    );
+    assert_eq!(decode("$amt * 0.9 + 1 FIL"), Ok(binop(
+      BinOp::Add,
+      binop(BinOp::Mul, Expr::Var("amt".to_string()), Expr::Decimal(0.9)),
+      amount("1 FIL"),
+    )));
+
+    let err = parse_error(r#"when Deposit { token: { amount: $amt } } if $amt >= 1 FIL then
+      pay {
+        to: "addressA",
+        token: { name: "filecoin", ticker: "FIL", amount: 0.0000000000000000001 FIL }
+      }"#);
+    assert_eq!((err.line, err.column), (4, 59));
+    assert_eq!(err.summary(), "`0.0000000000000000001 FIL` is finer than an attoFIL");
+
+    let err = parse_error("when Deposit { token: { amount: 2 kiloFIL } }");
+    assert!(err.summary().starts_with("`kiloFIL` is not a unit"), "{}", err);
+  }
+
+  #[test]
+  fn test_address_errors() {
+    let err = parse_error(r#"when Deposit { from: f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy } then
+      pay {
+        to: 0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAeD,
+        token: { name: "world", ticker: "WRLD", amount: 1 }
+      }"#);
+    assert_eq!((err.line, err.column), (3, 13));
+    assert!(err.summary().contains("has an invalid EIP-55 checksum"), "{}", err);
+
+    // An `f` or `t` word that isn't an address is a name, but not one that
+    // can stand for an account.
+    let err = parse_contract("when Deposit { from: f17uoq6tp427uzv7fztkbsnn64iwotfrristwqryy }").unwrap_err();
+    assert_eq!(err.to_string(), "\
+Contract does not match the schema:
+  clause 1, `Deposit`: `from` is invalid: `f17uoq6tp427uzv7fztkbsnn64iwotfrristwqryy` has an invalid checksum");
+    assert_eq!(decode("t1"), Ok(Expr::Id("t1".to_string())));
+    assert_eq!(decode("f0count.total"), Ok(Expr::Field(Box::new(Expr::Id("f0count".to_string())), "total".to_string())));
+    assert_eq!(decode("[f01_x]"), Ok(Expr::Array(vec![Expr::Id("f01_x".to_string())])));
+    assert!(parse_syntax("when Pay {} if t1 > f0count then pay {}", &Registry::new()).is_ok());
+  }
+
+  #[test]
+  fn test_close() {
+    assert_eq!(close().parse("close"), Ok(((), "")));
+    assert_eq!(close().parse("close }"), Ok(((), " }")));
+    assert!(close().parse("closed").is_err());
+    assert!(close().parse("close_").is_err());
+    assert!(close().parse("open").is_err());
+
+    let err = parse_error("when Pay {} then closed");
+    assert_eq!((err.line, err.column), (1, 18), "{}", err);
+    assert_eq!(err.found.as_deref(), Some("`closed`"), "{}", err);
+    assert!(parse_contract("closed").is_err());
+  }
+
+  #[test]
+  fn test_parse_contract() {
+    let contract = parse_contract(r#"
+    when Deposit {
+      from: "addressA",
+      token: {
+        name: "world",
+        ticker: "WRLD",
+        amount: 123
+      }
+    } then
+      pay {
+        to: "addressB",
+        token: {
+          name: "world",
+          ticker: "WRLD",
+          amount: 100
+        }
+      }
+
+    when DealActivated {
+      piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy"
+    } then
+      pay {
+        to: "addressC",
+        token: {
+          name: "world",
+          ticker: "WRLD",
+          amount: 23
+        }
+      } then
+      pay {
+        to: "addressD",
+        token: {
+          name: "world",
+          ticker: "WRLD",
+          amount: 0
+        }
+      }
+
+    close
+    "#).unwrap();
+
+    assert!(contract.close);
+    assert_eq!(contract.stmts.len(), 2);
+
+    let (event_op, ops) = &contract.stmts[0];
+    match &event_op.event {
+      Expr::Event{ name, .. } => assert_eq!(name, "Deposit"),
+      e => panic!("Expected an event, got {:?}", e),
+    }
+    assert_eq!(ops.len(), 1);
+
+    let (event_op, ops) = &contract.stmts[1];
+    let mut args = HashMap::new();
+    args.insert(
+      "piece_cid".to_string(),
+      Expr::QuotedString("baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy".to_string()),
+    );
+    assert_eq!(event_op.event, Expr::Event{ name: "DealActivated".to_string(), args });
+    assert_eq!(ops.len(), 2);
+  }
+
+  #[test]
+  fn test_parse_contract_without_close() {
+    let contract = parse_contract(r#"when Pay {
+      to: "addressA"
+    }
+    when DealPublished {
+      piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy"
+    }"#).unwrap();
+    assert!(!contract.close);
+    assert_eq!(contract.stmts.len(), 2);
+    assert!(contract.stmts.iter().all(|(_, ops)| ops.is_empty()));
+
+    let contract = parse_contract("  ").unwrap();
+    assert_eq!(contract, Contract{ events: vec![], stmts: vec![], close: false });
+  }
+
+  #[test]
+  fn test_parse_contract_error() {
+    let err = parse_error(r#"when Deposit {
+      from: "addressA"
+    } then
+      pay {
+        to: "addressB",
+    close"#);
+    assert_eq!(err.line, 6);
+    // `close` is read as the next key of the dict.
+    assert_eq!(err.to_string(), "\
+error: expected whitespaces or `:`, found end of input
+ --> 6:10
+  |
+6 |     close
+  |          ^");
+
+    let err = parse_error("when Deposit {} close when Pay {}");
+    assert_eq!(err.summary(), "expected end of input, found `when`");
+    assert_eq!(err.span, 22..26);
+  }
+
+  #[test]
+  fn test_event_declarations() {
+    let contract = parse_contract(r#"
+    event BountyClaimed { claimant: address, piece: cid, reward: { amount: amount } }
+    event Expired {}
+    when BountyClaimed { claimant: $who } then pay { to: $who, token: { name: "world", ticker: "WRLD", amount: 1 } }
+    when Expired {}
+    "#).unwrap();
+    assert_eq!(contract.events, vec![
+      EventType{
+        name: "BountyClaimed".to_string(),
+        fields: vec![
+          Field::new("claimant", Type::Account),
+          Field::new("piece", Type::Cid),
+          Field::new("reward", Type::Dict(Cow::Owned(vec![Field::new("amount", Type::Amount)]))),
+        ],
+      },
+      EventType{ name: "Expired".to_string(), fields: vec![] },
+    ]);
+    assert_eq!(contract.stmts.len(), 2);
+
+    let err = parse_error("event Deposit { from: address }");
+    assert_eq!(err.summary(), "Event `Deposit` is already defined");
+    assert_eq!(err.span, 6..7);
+    let err = parse_error("event A {}\nevent A {}");
+    assert_eq!(err.summary(), "Event `A` is already defined");
+    assert_eq!(err.line, 2);
+    let err = parse_error("event A { at: time }");
+    assert_eq!(err.summary(), "`time` is not a type, expected string, address, int, amount, bool, cid or a dict");
+    assert_eq!(parse_error("event a {}").summary(), "expected an event name, found `a`");
+    // Declarations come first.
+    assert!(parse_contract("when Deposit {} event A {}").is_err());
+  }
+
+  #[test]
+  fn test_parse_contract_unbound_variable() {
+    let contract = parse_contract(r#"
+    when Deposit { from: $sender, token: { amount: $amt } } then
+      pay { to: $sender, token: { name: "world", ticker: "WRLD", amount: $amt } }
+    "#).unwrap();
+    assert_eq!(contract.stmts[0].0.event.vars(), vec!["sender", "amt"]);
+
+    // Bindings don't carry over from one clause to the next.
+    let err = parse_contract(r#"
+    when Deposit { from: $sender }
+    when Pay { to: "addressA" } then
+      pay { to: $sender }
+    "#).unwrap_err();
+    assert_eq!(err.to_string(), "Variable `$sender` is not bound by its `when` clause");
+
+    let err = parse_contract("when Deposit {} if $amt > 1").unwrap_err();
+    assert_eq!(err, ContractError::UnboundVariable("amt".to_string()));
+  }
+
+  #[test]
+  fn test_integer_overflow() {
+    let err = parse_error("when Pay {} timeout 99999999999999999999 epochs else close");
+    assert_eq!(err.messages, vec!["`99999999999999999999` is too large for an integer".to_string()]);
+    let err = parse_error("when Pay { amount: 99999999999999999999 }");
+    assert_eq!(err.messages, vec!["`99999999999999999999` is too large for an integer".to_string()]);
+    assert!(decode("[1, 99999999999999999999]").is_err());
+    assert!(decode("99999999999999999999.5").is_ok());
+  }
+
+  #[test]
+  fn test_parse_contract_guard_error() {
+    let err = parse_error(r#"when Deposit {
+      from: "addressA"
+    } if token.amount >=
+    close"#);
+    assert_eq!((err.line, err.column), (4, 5));
+    assert_eq!(err.found.as_deref(), Some("`close`"), "{}", err);
+  }
+
+  #[test]
+  fn test_comments() {
+    let source = r#"// header
+when Deposit { from: "a//b", // sender
+  token: { amount: 2 } // list
+} then pay { to: "b", token: { name: "world", ticker: "WRLD", amount: 1 } }
+// tail"#;
+    let with_comments = parse_contract(source).unwrap();
+    let without = parse_contract(r#"when Deposit { from: "a//b", token: { amount: 2 } } then pay { to: "b", token: { name: "world", ticker: "WRLD", amount: 1 } }"#).unwrap();
+    assert_eq!(with_comments, without);
+
+    let anchors: Vec<(String, Anchor, bool)> = trivia(source, &Registry::new())
+      .into_iter()
+      .map(|c| (c.text, c.anchor, c.trailing))
+      .collect();
+    assert_eq!(anchors, vec![
+      ("// header".to_string(), Anchor::Clause(0), false),
+      ("// sender".to_string(), Anchor::Entry{ clause: 0, op: None, path: vec!["from".to_string()] }, true),
+      // After the closing brace of the nested dict.
+      ("// list".to_string(), Anchor::DictEnd{ clause: 0, op: None, path: vec!["token".to_string()] }, true),
+      ("// tail".to_string(), Anchor::End, false),
+    ]);
+
+    let source = "// bounties\nevent A { x: { y: int } } // nested\nwhen A {}";
+    let anchors: Vec<(Anchor, bool)> = trivia(source, &Registry::new()).into_iter().map(|c| (c.anchor, c.trailing)).collect();
+    assert_eq!(anchors, vec![(Anchor::Event(0), false), (Anchor::Event(0), true)]);
+  }
+}
@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+
+use crate::error::ContractError;
+use crate::expr::{parse_syntax, trivia, Anchor, BinOp, Comment, Continuation, Contract, Expr, Op, Stmt};
+use crate::registry::Registry;
+
+const INDENT: &str = "  ";
+
+/// Parses `source` and prints it back in the canonical layout, keeping its
+/// comments. Only the syntax has to be right.
+pub fn format_source(source: &str) -> Result<String, ContractError> {
+  format_source_with(source, &Registry::new())
+}
+
+/// `format_source` with the ops of `registry` rather than the builtin ones.
+pub fn format_source_with(source: &str, registry: &Registry) -> Result<String, ContractError> {
+  let contract = parse_syntax(source, registry)?;
+  Ok(format_contract(&contract, &trivia(source, registry)))
+}
+
+/// Prints a contract in the canonical layout: event declarations one per
+/// line ahead of the clauses, clauses separated by a blank line, each `when` pattern and op argument as a dict with one entry per
+/// line, keys sorted, two spaces per level of nesting, the guard on its own
+/// line and every op on its own line after `then`, then any timeout: its
+/// `timeout` line and the ops of its branch, the first after `else`. A
+/// clause a `then` or `else` continues with starts on that line, and the
+/// rest of it is indented a level further.
+pub fn format_contract(contract: &Contract, comments: &[Comment]) -> String {
+  let mut printer = Printer{ out: String::new(), comments: HashMap::new() };
+  for comment in comments {
+    printer.comments
+      .entry((comment.anchor.clone(), comment.trailing))
+      .or_default()
+      .push(comment.text.as_str());
+  }
+
+  for (i, event) in contract.events.iter().enumerate() {
+    printer.leading(&Anchor::Event(i), 0);
+    printer.out.push_str(&event.to_string());
+    printer.trailing(&[Anchor::Event(i)]);
+    printer.out.push('\n');
+  }
+
+  let mut clauses = 0;
+  for (i, stmt) in contract.stmts.iter().enumerate() {
+    if i > 0 || !contract.events.is_empty() {
+      printer.out.push('\n');
+    }
+    printer.leading(&Anchor::Clause(clauses), 0);
+    printer.clause(stmt, &mut clauses);
+  }
+
+  if contract.close {
+    if !contract.stmts.is_empty() || !contract.events.is_empty() {
+      printer.out.push('\n');
+    }
+    printer.leading(&Anchor::Close, 0);
+    printer.out.push_str("close");
+    printer.trailing(&[Anchor::Close]);
+    printer.out.push('\n');
+  }
+
+  if let Some(end) = printer.comments.remove(&(Anchor::End, false)) {
+    if !printer.out.is_empty() {
+      printer.out.push('\n');
+    }
+    for text in end {
+      printer.out.push_str(text);
+      printer.out.push('\n');
+    }
+  }
+  // Comments whose code is gone, e.g. after a `then` that anchors nothing,
+  // are kept at the end rather than lost.
+  let mut rest: Vec<_> = printer.comments.into_values().flatten().collect();
+  rest.sort();
+  for text in rest {
+    printer.out.push_str(text);
+    printer.out.push('\n');
+  }
+  printer.out
+}
+
+struct Printer<'a> {
+  out: String,
+  comments: HashMap<(Anchor, bool), Vec<&'a str>>,
+}
+
+impl<'a> Printer<'a> {
+  /// A clause and those nested in it, starting at the cursor with `when`.
+  /// `clauses` counts the clauses printed so far, nested ones included.
+  fn clause(&mut self, (event_op, ops): &Stmt, clauses: &mut usize) {
+    let i = *clauses;
+    *clauses += 1;
+    self.out.push_str(&event_op.name);
+    self.out.push(' ');
+    match &event_op.event {
+      Expr::Event{ name, args } => {
+        self.out.push_str(name);
+        self.out.push(' ');
+        self.dict(args, 0, i, None, &[], Some(&Anchor::Clause(i)));
+      },
+      event => self.out.push_str(&expr(event)),
+    }
+    self.trailing(&[Anchor::DictEnd{ clause: i, op: None, path: vec![] }]);
+    self.out.push('\n');
+
+    if let Some(guard) = &event_op.guard {
+      self.leading(&Anchor::Guard(i), 0);
+      self.out.push_str("if ");
+      self.out.push_str(&expr(guard));
+      self.trailing(&[Anchor::Guard(i)]);
+      self.out.push('\n');
+    }
+
+    // A `close` takes an op's place in the numbering.
+    let mut k = 0;
+    for op in ops {
+      self.op("then", op, i, k);
+      k += 1;
+    }
+    if let Some(next) = &event_op.next {
+      self.continuation("then", next, i, k, clauses);
+      k += matches!(next, Continuation::Close) as usize;
+    }
+
+    if let Some(timeout) = &event_op.timeout {
+      self.leading(&Anchor::Timeout(i), 0);
+      self.out.push_str(&format!("timeout {} epochs", timeout.epochs));
+      self.trailing(&[Anchor::Timeout(i)]);
+      self.out.push('\n');
+      for (n, op) in timeout.ops.iter().enumerate() {
+        self.op(if n == 0 { "else" } else { "then" }, op, i, k);
+        k += 1;
+      }
+      if let Some(next) = &timeout.next {
+        self.continuation(if timeout.ops.is_empty() { "else" } else { "then" }, next, i, k, clauses);
+      }
+    }
+  }
+
+  /// The `k`th op of clause `clause`, on its own line after `keyword`.
+  fn op(&mut self, keyword: &str, op: &Op, clause: usize, k: usize) {
+    let anchor = Anchor::Op(clause, k);
+    self.leading(&anchor, 0);
+    self.out.push_str(keyword);
+    self.out.push(' ');
+    self.out.push_str(&op.name);
+    self.out.push(' ');
+    match &op.arg {
+      Some(Expr::Dict(args)) => self.dict(args, 0, clause, Some(k), &[], Some(&anchor)),
+      Some(arg) => self.out.push_str(&expr(arg)),
+      None => self.out.push_str("{}"),
+    }
+    self.trailing(&[Anchor::DictEnd{ clause, op: Some(k), path: vec![] }]);
+    self.out.push('\n');
+  }
+
+  /// What clause `clause` goes on to after `keyword`. A `close` is
+  /// anchored as its `k`th op would be.
+  fn continuation(&mut self, keyword: &str, next: &Continuation, clause: usize, k: usize, clauses: &mut usize) {
+    match next {
+      Continuation::Close => {
+        let anchor = Anchor::Op(clause, k);
+        self.leading(&anchor, 0);
+        self.out.push_str(keyword);
+        self.out.push_str(" close");
+        self.trailing(&[anchor]);
+        self.out.push('\n');
+      },
+      Continuation::When(stmt) => {
+        self.leading(&Anchor::Clause(*clauses), 0);
+        self.out.push_str(keyword);
+        self.out.push(' ');
+        let start = self.out.len();
+        self.clause(stmt, clauses);
+        // Strings are printed escaped, so every newline starts a line.
+        let nested = self.out.split_off(start);
+        let (first, rest) = nested.split_once('\n').unwrap_or((&nested, ""));
+        self.out.push_str(first);
+        self.out.push('\n');
+        for line in rest.lines() {
+          self.out.push_str(INDENT);
+          self.out.push_str(line);
+          self.out.push('\n');
+        }
+      },
+    }
+  }
+
+  /// Comments on their own lines before `anchor`.
+  fn leading(&mut self, anchor: &Anchor, depth: usize) {
+    for text in self.comments.remove(&(anchor.clone(), false)).unwrap_or_default() {
+      self.out.push_str(&INDENT.repeat(depth));
+      self.out.push_str(text);
+      self.out.push('\n');
+    }
+  }
+
+  /// Comments at the end of the line just printed, from any of `anchors`.
+  fn trailing(&mut self, anchors: &[Anchor]) {
+    for anchor in anchors {
+      for text in self.comments.remove(&(anchor.clone(), true)).unwrap_or_default() {
+        self.out.push(' ');
+        self.out.push_str(text);
+      }
+    }
+  }
+
+  /// A dict with one entry per line, starting at the cursor and ending
+  /// after its closing brace. `opener` collects comments trailing whatever
+  /// precedes the opening brace on its line.
+  fn dict(
+    &mut self,
+    dict: &HashMap<String, Expr>,
+    depth: usize,
+    clause: usize,
+    op: Option<usize>,
+    path: &[String],
+    opener: Option<&Anchor>,
+  ) {
+    if dict.is_empty() && !self.has_comments_in(clause, op, path) {
+      self.out.push_str("{}");
+      return;
+    }
+    self.out.push('{');
+    if let Some(opener) = opener {
+      self.trailing(std::slice::from_ref(opener));
+    }
+    self.out.push('\n');
+
+    let mut keys: Vec<&String> = dict.keys().collect();
+    keys.sort();
+    for (n, key) in keys.iter().enumerate() {
+      let path: Vec<String> = path.iter().chain([*key]).cloned().collect();
+      let entry = Anchor::Entry{ clause, op, path: path.clone() };
+      self.leading(&entry, depth + 1);
+      self.out.push_str(&INDENT.repeat(depth + 1));
+      self.out.push_str(&dict_key(key));
+      self.out.push_str(": ");
+      self.value(&dict[*key], depth + 1, clause, op, &path);
+      if n + 1 < keys.len() {
+        self.out.push(',');
+      }
+      self.trailing(&[entry, Anchor::DictEnd{ clause, op, path }]);
+      self.out.push('\n');
+    }
+
+    self.leading(&Anchor::DictEnd{ clause, op, path: path.to_vec() }, depth + 1);
+    self.out.push_str(&INDENT.repeat(depth));
+    self.out.push('}');
+  }
+
+  fn has_comments_in(&self, clause: usize, op: Option<usize>, path: &[String]) -> bool {
+    self.comments.contains_key(&(Anchor::DictEnd{ clause, op, path: path.to_vec() }, false))
+  }
+
+  /// A dict entry's value. Dicts and events nest on their own lines, as do
+  /// arrays holding them; everything else stays on the entry's line.
+  fn value(&mut self, value: &Expr, depth: usize, clause: usize, op: Option<usize>, path: &[String]) {
+    match value {
+      Expr::Dict(dict) => self.dict(dict, depth, clause, op, path, None),
+      Expr::Event{ name, args } => {
+        self.out.push_str(name);
+        self.out.push(' ');
+        self.dict(args, depth, clause, op, path, None);
+      },
+      Expr::Array(items) if items.iter().any(is_block) => {
+        self.out.push_str("[\n");
+        for (n, item) in items.iter().enumerate() {
+          self.out.push_str(&INDENT.repeat(depth + 1));
+          self.value(item, depth + 1, clause, op, path);
+          if n + 1 < items.len() {
+            self.out.push(',');
+          }
+          self.out.push('\n');
+        }
+        self.out.push_str(&INDENT.repeat(depth));
+        self.out.push(']');
+      },
+      value => self.out.push_str(&expr(value)),
+    }
+  }
+}
+
+fn is_block(value: &Expr) -> bool {
+  match value {
+    Expr::Dict(dict) | Expr::Event{ args: dict, .. } => !dict.is_empty(),
+    Expr::Array(items) => items.iter().any(is_block),
+    _ => false,
+  }
+}
+
+fn dict_key(key: &str) -> String {
+  let bare = !key.is_empty() && key.chars().all(|c| c.is_alphabetic() || c.is_ascii_digit() || c == '_');
+  match bare {
+    true => key.to_string(),
+    false => quote(key),
+  }
+}
+
+fn quote(s: &str) -> String {
+  let mut quoted = String::from("\"");
+  for c in s.chars() {
+    match c {
+      '"' => quoted.push_str("\\\""),
+      '\\' => quoted.push_str("\\\\"),
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      '\0' => quoted.push_str("\\0"),
+      c if c.is_control() => quoted.push_str(&format!("\\u{{{:x}}}", c as u32)),
+      c => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// How tightly an operator binds, as `expr::condition` parses them. `+`
+/// and `-` share a level, as do `*` and `/`, so `a - b + c` needs no parens
+/// but `a - (b + c)` does.
+fn precedence(op: BinOp) -> u8 {
+  match op {
+    BinOp::Or => 1,
+    BinOp::And => 2,
+    BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => 3,
+    BinOp::Add | BinOp::Sub => 4,
+    BinOp::Mul | BinOp::Div => 5,
+  }
+}
+
+/// An expression on one line, with nested dicts and events inline.
+pub(crate) fn expr(expr: &Expr) -> String {
+  operand(expr, 0)
+}
+
+/// `expr` as the operand of an operator of precedence `outer`, in parens if
+/// it binds more loosely.
+fn operand(expr: &Expr, outer: u8) -> String {
+  match expr {
+    Expr::BinOp{ op, lhs, rhs } => {
+      let p = precedence(*op);
+      // Comparisons don't chain, and operators of one level group to the
+      // left, so a right operand of the same level needs parens.
+      let left = if p == 3 { p + 1 } else { p };
+      let s = format!("{} {} {}", operand(lhs, left), op, operand(rhs, p + 1));
+      match p < outer {
+        true => format!("({})", s),
+        false => s,
+      }
+    },
+    Expr::Not(inner) => format!("!{}", operand(inner, u8::MAX)),
+    Expr::Id(name) => name.clone(),
+    Expr::Var(name) => format!("${}", name),
+    Expr::Field(base, field) => format!("{}.{}", operand(base, u8::MAX), field),
+    Expr::Integer(n) => n.to_string(),
+    Expr::Decimal(d) => {
+      let s = d.to_string();
+      match s.contains('.') {
+        true => s,
+        false => format!("{}.0", s),
+      }
+    },
+    Expr::QuotedString(s) => quote(s),
+    Expr::Bool(b) => b.to_string(),
+    Expr::Atom(name) => format!(":{}", name),
+    Expr::Address(address) => address.to_string(),
+    Expr::TokenAmount(amount) => amount.to_string(),
+    Expr::Array(items) => format!("[{}]", items.iter().map(|item| operand(item, 0)).collect::<Vec<_>>().join(", ")),
+    Expr::Pair(fst, snd) => format!("({}, {})", operand(fst, 0), operand(snd, 0)),
+    Expr::Dict(dict) => inline_dict(dict.iter().map(|(k, v)| (k.clone(), v.clone()))),
+    Expr::Event{ name, args } => format!("{} {}", name, inline_dict(args.iter().map(|(k, v)| (k.clone(), v.clone())))),
+    Expr::Token{ name, ticker, amount } => inline_dict([
+      ("amount".to_string(), Expr::TokenAmount(*amount)),
+      ("name".to_string(), Expr::QuotedString(name.clone())),
+      ("ticker".to_string(), Expr::QuotedString(ticker.clone())),
+    ]),
+    Expr::DealRequest{
+      piece_cid,
+      piece_size,
+      verified_deal,
+      label,
+      start_epoch,
+      end_epoch,
+      storage_price_per_epoch,
+      provider_collateral,
+      extra_params_version,
+    } => inline_dict([
+      ("piece_cid".to_string(), Expr::QuotedString(piece_cid.clone())),
+      ("piece_size".to_string(), Expr::Integer(*piece_size as usize)),
+      ("verified_deal".to_string(), Expr::Bool(*verified_deal)),
+      ("label".to_string(), Expr::QuotedString(label.clone())),
+      ("start_epoch".to_string(), Expr::Integer(*start_epoch as usize)),
+      ("end_epoch".to_string(), Expr::Integer(*end_epoch as usize)),
+      ("storage_price_per_epoch".to_string(), Expr::TokenAmount(*storage_price_per_epoch)),
+      ("provider_collateral".to_string(), Expr::TokenAmount(*provider_collateral)),
+      ("extra_params_version".to_string(), Expr::Integer(*extra_params_version as usize)),
+    ]),
+  }
+}
+
+fn inline_dict(entries: impl IntoIterator<Item = (String, Expr)>) -> String {
+  let mut entries: Vec<(String, Expr)> = entries.into_iter().collect();
+  entries.sort_by(|a, b| a.0.cmp(&b.0));
+  match entries.is_empty() {
+    true => "{}".to_string(),
+    false => format!(
+      "{{ {} }}",
+      entries.iter().map(|(k, v)| format!("{}: {}", dict_key(k), expr(v))).collect::<Vec<_>>().join(", ")
+    ),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_layout() {
+    let source = r#"when Deposit{from:$sender,amount:10,token:{ticker:"FIL",amount:1.5 FIL}}
+      if $sender=="a"&&(1+2)*3>=4 then pay{to:$sender,"odd key":[1,2],token:{}} propose {piece:{size:2048}}
+    when Pay {} close"#;
+    assert_eq!(format_source(source).unwrap(), r#"when Deposit {
+  amount: 10,
+  from: $sender,
+  token: {
+    amount: 1.5 FIL,
+    ticker: "FIL"
+  }
+}
+if $sender == "a" && (1 + 2) * 3 >= 4
+then pay {
+  "odd key": [1, 2],
+  to: $sender,
+  token: {}
+}
+then propose {
+  piece: {
+    size: 2048
+  }
+}
+
+when Pay {}
+
+close
+"#);
+  }
+
+  #[test]
+  fn test_format_round_trip() {
+    let sources = [
+      r#"when Deposit { from: $a, amount: $n } if !($n < 10 || $a == "b") then pay { to: $a, amount: $n - (1 + 2) / 2 }"#,
+      r#"when DealPublished { deal: { label: "tab\there \"q\"", price: 1.0, ids: [(1, 2), { x: 1 }] } }"#,
+      "when Pay { to: f01234, amount: 10 attoFIL } then pay { to: 0x52908400098527886E0F7030069857D2E4169EE7, amount: 3 - 2 - 1 }",
+      "when Pay { amount: $a } then pay { to: f01, amount: $a * 9 / 10 - (1 - 2) + 3 / (4 * 5) - 6 / 3 * 2 }",
+      "event A { b: { c: cid }, d: int } event B {} when A { b: { c: $c } } close",
+      "when Choice { by: $a, bounds: [(0, 1), (3, 3)], value: $v } then pay { to: $a, amount: $v }",
+      "when Pay {} then pay { to: \"a\" } timeout 10 epoch else pay { to: \"b\" } pay { to: \"c\" }",
+      "when Pay {} then pay {} then when Pay { to: $a } then close timeout 5 epochs else pay {} then when Pay {} when Pay {}",
+      "when Pay {} then when Pay {} timeout 1 epoch else close timeout 2 epochs else when Pay {} then close close",
+      "close",
+      "",
+    ];
+    for source in sources {
+      let formatted = format_source(source).unwrap();
+      let registry = Registry::new();
+      assert_eq!(parse_syntax(&formatted, &registry).unwrap(), parse_syntax(source, &registry).unwrap(), "{}", formatted);
+      assert_eq!(format_source(&formatted).unwrap(), formatted);
+    }
+  }
+
+  #[test]
+  fn test_format_comments() {
+    let source = "// Refund deposits
+when Deposit { // from anyone
+  // who sent it
+  from: $sender, amount: $amount // the whole amount
+  // nothing else
+} if $amount > 0 // not empty
+then pay { to: $sender, amount: $amount }
+// done
+close // for good
+// end";
+    let formatted = format_source(source).unwrap();
+    assert_eq!(formatted, "// Refund deposits
+when Deposit { // from anyone
+  amount: $amount, // the whole amount
+  // who sent it
+  from: $sender
+  // nothing else
+}
+if $amount > 0 // not empty
+then pay {
+  amount: $amount,
+  to: $sender
+}
+
+// done
+close // for good
+
+// end
+");
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+  }
+
+  #[test]
+  fn test_format_timeout() {
+    let source = "when Pay {} then pay {} // on time
+// or else
+timeout 10 epoch else pay { to: \"a\" } // late
+then pay {}";
+    let formatted = format_source(source).unwrap();
+    assert_eq!(formatted, "\
+when Pay {}
+then pay {} // on time
+// or else
+timeout 10 epochs
+else pay {
+  to: \"a\"
+} // late
+then pay {}
+");
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+  }
+
+  #[test]
+  fn test_format_nested() {
+    let source = "when Pay {} then pay {} // first
+// then wait
+then when Pay { to: $a // to them
+} then close // done
+timeout 5 epochs // not too long
+else pay {}
+// then the last
+timeout 10 epochs else when Pay {} // still
+then pay { to: $a }";
+    let formatted = format_source(source).unwrap();
+    assert_eq!(formatted, "\
+when Pay {}
+then pay {} // first
+// then wait
+then when Pay {
+    to: $a // to them
+  }
+  then close // done
+  timeout 5 epochs // not too long
+  else pay {}
+// then the last
+timeout 10 epochs
+else when Pay {} // still
+  then pay {
+    to: $a
+  }
+");
+    assert_eq!(format_source(&formatted).unwrap(), formatted);
+  }
+
+  #[test]
+  fn test_format_declarations() {
+    let source = "// types\nevent Claimed{who:address,   reward:{amount:amount}} // claims\nevent Expired { }\nclose";
+    assert_eq!(format_source(source).unwrap(), "\
+// types
+event Claimed { who: address, reward: { amount: amount } } // claims
+event Expired {}
+
+close
+");
+  }
+}
@@ -0,0 +1,321 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::error::ContractError;
+use crate::expr::{check, Continuation, Contract, EventOp, Expr, Op, Ops, Stmt, Timeout};
+use crate::registry::{is_event_name, Registry};
+use crate::schema::EventType;
+
+/// The version of the JSON representation, written into every export and
+/// checked on import. Bumped whenever a change would make older readers
+/// misread a contract. Version 2 added timeouts and version 3 nested
+/// clauses; contracts of earlier versions are still read.
+pub const VERSION: u32 = 3;
+
+/// Writes a dict with its keys in order, so that the same contract always
+/// exports to the same JSON.
+pub(crate) fn sorted<S: Serializer>(map: &HashMap<String, Expr>, serializer: S) -> Result<S::Ok, S::Error> {
+  map.iter().collect::<BTreeMap<_, _>>().serialize(serializer)
+}
+
+#[derive(Serialize)]
+struct ContractRef<'a> {
+  version: u32,
+  #[serde(skip_serializing_if = "<[_]>::is_empty")]
+  events: &'a [EventType],
+  clauses: Vec<ClauseRef<'a>>,
+  close: bool,
+}
+
+#[derive(Serialize)]
+struct ClauseRef<'a> {
+  #[serde(flatten)]
+  when: &'a EventOp,
+  ops: &'a Ops,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  next: Option<NextRef<'a>>,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  timeout: Option<TimeoutRef<'a>>,
+}
+
+#[derive(Serialize)]
+struct TimeoutRef<'a> {
+  epochs: u64,
+  ops: &'a Ops,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  next: Option<NextRef<'a>>,
+}
+
+/// A continuation, as `{"when":{...}}` with the clause or as `"close"`.
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum NextRef<'a> {
+  When(Box<ClauseRef<'a>>),
+  Close,
+}
+
+fn clause_ref((when, ops): &Stmt) -> ClauseRef<'_> {
+  ClauseRef{
+    when,
+    ops,
+    next: when.next.as_ref().map(next_ref),
+    timeout: when.timeout.as_ref().map(|Timeout{ epochs, ops, next }| TimeoutRef{
+      epochs: *epochs,
+      ops,
+      next: next.as_ref().map(next_ref),
+    }),
+  }
+}
+
+fn next_ref(next: &Continuation) -> NextRef<'_> {
+  match next {
+    Continuation::When(stmt) => NextRef::When(Box::new(clause_ref(stmt))),
+    Continuation::Close => NextRef::Close,
+  }
+}
+
+#[derive(Deserialize)]
+struct ContractJson {
+  version: u32,
+  #[serde(default)]
+  events: Vec<EventType>,
+  clauses: Vec<ClauseJson>,
+  #[serde(default)]
+  close: bool,
+}
+
+#[derive(Deserialize)]
+struct ClauseJson {
+  #[serde(flatten)]
+  when: EventOp,
+  #[serde(default)]
+  ops: Vec<OpJson>,
+  #[serde(default)]
+  next: Option<NextJson>,
+  #[serde(default)]
+  timeout: Option<TimeoutJson>,
+}
+
+#[derive(Deserialize)]
+struct TimeoutJson {
+  epochs: u64,
+  #[serde(default)]
+  ops: Vec<OpJson>,
+  #[serde(default)]
+  next: Option<NextJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum NextJson {
+  When(Box<ClauseJson>),
+  Close,
+}
+
+/// Ops are written by name, e.g. `{"op":"pay","arg":{...}}`, and looked up
+/// in a registry again when read.
+#[derive(Deserialize)]
+struct OpJson {
+  op: String,
+  #[serde(default)]
+  arg: Option<Expr>,
+}
+
+impl Serialize for Op {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct OpRef<'a> {
+      op: &'a str,
+      arg: &'a Option<Expr>,
+    }
+    OpRef{ op: &self.name, arg: &self.arg }.serialize(serializer)
+  }
+}
+
+/// The contract as pretty-printed JSON: its format version, the event types
+/// it declares if any, its clauses in order, each with what it continues
+/// with and its timeout if it has them, and whether it closes.
+pub fn to_json(contract: &Contract) -> String {
+  let json = ContractRef{
+    version: VERSION,
+    events: &contract.events,
+    clauses: contract.stmts.iter().map(clause_ref).collect(),
+    close: contract.close,
+  };
+  serde_json::to_string_pretty(&json).expect("contracts serialize to JSON")
+}
+
+/// Reads a contract exported by `to_json`, with the same checks as parsing
+/// its source.
+pub fn from_json(json: &str) -> Result<Contract, ContractError> {
+  from_json_with(json, &Registry::new())
+}
+
+/// `from_json` with the ops and events of `registry` rather than the builtin
+/// ones.
+pub fn from_json_with(json: &str, registry: &Registry) -> Result<Contract, ContractError> {
+  let json: ContractJson = serde_json::from_str(json).map_err(|err| ContractError::Json(err.to_string()))?;
+  if json.version == 0 || json.version > VERSION {
+    return Err(ContractError::Json(format!(
+      "unsupported version {}, expected {} or earlier",
+      json.version, VERSION
+    )));
+  }
+  for (i, event) in json.events.iter().enumerate() {
+    if !is_event_name(&event.name) {
+      return Err(ContractError::Json(format!("`{}` can't be an event name", event.name)));
+    }
+    if registry.event_fields(&event.name).is_some() || json.events[..i].iter().any(|e| e.name == event.name) {
+      return Err(ContractError::Json(format!("event `{}` is already defined", event.name)));
+    }
+  }
+  let stmts = json.clauses
+    .into_iter()
+    .map(|clause| stmt(clause, registry))
+    .collect::<Result<Vec<_>, _>>()?;
+  let contract = Contract{ events: json.events, stmts, close: json.close };
+  check(&contract, registry)?;
+  Ok(contract)
+}
+
+fn stmt(clause: ClauseJson, registry: &Registry) -> Result<Stmt, ContractError> {
+  let ops = |ops: Vec<OpJson>| ops
+    .into_iter()
+    .map(|OpJson{ op, arg }| {
+      registry.op(&op, arg).ok_or_else(|| ContractError::Json(format!("unknown op `{}`", op)))
+    })
+    .collect::<Result<Ops, _>>();
+  let next = |next: Option<NextJson>| match next {
+    Some(NextJson::When(clause)) => stmt(*clause, registry).map(|stmt| Some(Continuation::When(Box::new(stmt)))),
+    Some(NextJson::Close) => Ok(Some(Continuation::Close)),
+    None => Ok(None),
+  };
+  let timeout = match clause.timeout {
+    Some(TimeoutJson{ ops: timeout, next: None, .. }) if timeout.is_empty() => {
+      return Err(ContractError::Json("a timeout needs an op or a continuation".to_string()));
+    },
+    Some(timeout) => Some(Timeout{ epochs: timeout.epochs, ops: ops(timeout.ops)?, next: next(timeout.next)? }),
+    None => None,
+  };
+  let when = EventOp{ timeout, next: next(clause.next)?, ..clause.when };
+  Ok((when, ops(clause.ops)?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::expr::parse_contract;
+
+  const SOURCE: &str = r#"
+    event BountyClaimed { claimant: address, reward: { amount: amount } }
+
+    when Deposit { from: $sender, token: { name: "a \"b\"", amount: $amount } }
+    if $amount >= 1.5 FIL && !($sender == f01234)
+    then pay { to: $sender, token: { name: "Filecoin", ticker: "FIL", amount: $amount - 10 attoFIL } }
+    then propose {
+      deal_request: {
+        piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy",
+        piece_size: 2048,
+        verified_deal: false,
+        label: "x",
+        start_epoch: 10,
+        end_epoch: 20,
+        storage_price_per_epoch: 0.25 FIL,
+        provider_collateral: 0,
+        extra_params_version: 1
+      }
+    }
+
+    when Pay { to: 0x52908400098527886E0F7030069857D2E4169EE7 }
+    timeout 2880 epochs
+    else pay { to: f01234, token: { name: "Filecoin", ticker: "FIL", amount: 1 FIL } }
+
+    when BountyClaimed { claimant: $who }
+
+    close
+  "#;
+
+  #[test]
+  fn test_round_trip() {
+    let contract = parse_contract(SOURCE).unwrap();
+    let json = to_json(&contract);
+    assert_eq!(from_json(&json).unwrap(), contract);
+    // Stable: the same contract exports the same, dicts in key order.
+    assert_eq!(to_json(&parse_contract(SOURCE).unwrap()), json);
+    let from = json.find("\"from\"").unwrap();
+    let token = json.find("\"token\"").unwrap();
+    let amount = json[token..].find("\"amount\"").unwrap();
+    let name = json[token..].find("\"name\"").unwrap();
+    assert!(from < token && amount < name, "{}", json);
+    assert!(json.contains(r#""type": "address""#), "{}", json);
+    assert!(json.contains(r#""epochs": 2880"#), "{}", json);
+  }
+
+  #[test]
+  fn test_nested_round_trip() {
+    let contract = parse_contract(r#"
+    when Deposit { from: $sender }
+    then when Notify { by: $sender, name: "done" } then close
+    timeout 10 epochs else when Notify { name: "late" }
+    "#).unwrap();
+    let json = to_json(&contract);
+    assert_eq!(from_json(&json).unwrap(), contract);
+    assert!(json.contains(r#""next": "close""#), "{}", json);
+    assert_eq!(json.matches(r#""when": {"#).count(), 2, "{}", json);
+  }
+
+  #[test]
+  fn test_shape() {
+    let contract = parse_contract("when Deposit { token: { amount: 1 FIL } } close").unwrap();
+    let json: serde_json::Value = serde_json::from_str(&to_json(&contract)).unwrap();
+    assert_eq!(json, serde_json::json!({
+      "version": 3,
+      "clauses": [{
+        "name": "when",
+        "event": {
+          "type": "Event",
+          "value": {
+            "name": "Deposit",
+            "args": {
+              "token": {
+                "type": "Dict",
+                "value": { "amount": { "type": "TokenAmount", "value": "1000000000000000000" } },
+              },
+            },
+          },
+        },
+        "guard": null,
+        "ops": [],
+      }],
+      "close": true,
+    }));
+  }
+
+  #[test]
+  fn test_import_errors() {
+    let error = |json: &str| from_json(json).unwrap_err().to_string();
+
+    let json = r#"{"version":1,"clauses":[{"name":"when","event":{"type":"Event","value":{"name":"Deposit","args":{}}},"ops":[{"op":"burn"}]}]}"#;
+    assert!(error(json).contains("unknown op `burn`"), "{}", error(json));
+
+    let json = r#"{"version":1,"events":[{"name":"Pay","fields":[]}],"clauses":[]}"#;
+    assert_eq!(error(json), "JSON error: event `Pay` is already defined");
+    let json = r#"{"version":1,"events":[{"name":"A","fields":[{"name":"at","type":"time"}]}],"clauses":[]}"#;
+    assert!(error(json).starts_with("JSON error: unknown variant `time`"), "{}", error(json));
+
+    let json = r#"{"version":1,"clauses":[{"name":"when","event":{"type":"Event","value":{"name":"Pay","args":{}}},"timeout":{"epochs":1,"ops":[]}}]}"#;
+    assert_eq!(error(json), "JSON error: a timeout needs an op or a continuation");
+    // Contracts exported before timeouts are still read.
+    assert!(from_json(r#"{"version":1,"clauses":[]}"#).is_ok());
+    let json = r#"{"version":4,"clauses":[]}"#;
+    assert_eq!(error(json), "JSON error: unsupported version 4, expected 3 or earlier");
+
+    // Imports are checked like parsed sources.
+    let json = r#"{"version":1,"clauses":[{"name":"when","event":{"type":"Event","value":{"name":"Deposit","args":{}}},"ops":[{"op":"pay","arg":{"type":"Var","value":"who"}}]}]}"#;
+    assert_eq!(from_json(json).unwrap_err(), ContractError::UnboundVariable("who".to_string()));
+
+    let json = r#"{"version":1,"clauses":[{"name":"when","event":{"type":"Address","value":"f19"}}]}"#;
+    assert!(error(json).starts_with("JSON error: "), "{}", error(json));
+  }
+}
@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+
+use crate::address::Address;
+use crate::error::ContractError;
+use crate::op::TokenAmount;
+
+/// The address contract escrow is held under.
+pub const ESCROW: &str = "escrow";
+
+/// In-memory balances, per address and per token ticker. An address is
+/// kept in its canonical form, so that an account has one balance whichever
+/// network or, for an Ethereum address, form it is named in.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Ledger {
+  balances: HashMap<String, HashMap<String, TokenAmount>>,
+}
+
+impl Ledger {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn balance(&self, address: &str, ticker: &str) -> TokenAmount {
+    self.balances
+      .get(&account(address))
+      .and_then(|tokens| tokens.get(ticker))
+      .copied()
+      .unwrap_or_default()
+  }
+
+  /// Every non-zero balance as `(address, ticker, amount)`, sorted by
+  /// address and then ticker.
+  pub fn balances(&self) -> Vec<(&str, &str, TokenAmount)> {
+    let mut balances: Vec<(&str, &str, TokenAmount)> = self.balances
+      .iter()
+      .flat_map(|(address, tokens)| tokens.iter().map(move |(ticker, amount)| (address.as_str(), ticker.as_str(), *amount)))
+      .filter(|(_, _, amount)| amount.atto() > 0)
+      .collect();
+    balances.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+    balances
+  }
+
+  fn set_balance(&mut self, address: &str, ticker: &str, amount: TokenAmount) {
+    self.balances
+      .entry(account(address))
+      .or_default()
+      .insert(ticker.to_string(), amount);
+  }
+
+  /// Adds `amount` to `address`, failing without any change if the balance
+  /// would overflow.
+  pub fn credit(&mut self, address: &str, ticker: &str, amount: TokenAmount) -> Result<(), ContractError> {
+    let balance = self.balance(address, ticker)
+      .checked_add(amount)
+      .ok_or_else(|| ContractError::Overflow{ address: address.to_string(), ticker: ticker.to_string() })?;
+    self.set_balance(address, ticker, balance);
+    Ok(())
+  }
+
+  /// Takes `amount` away from `address`, failing without any change if the
+  /// balance doesn't cover it.
+  pub fn debit(&mut self, address: &str, ticker: &str, amount: TokenAmount) -> Result<(), ContractError> {
+    let balance = self.balance(address, ticker);
+    let remaining = balance.checked_sub(amount).ok_or_else(|| ContractError::InsufficientFunds{
+      address: address.to_string(),
+      ticker: ticker.to_string(),
+      balance,
+      amount,
+    })?;
+    self.set_balance(address, ticker, remaining);
+    Ok(())
+  }
+
+  /// Moves `amount` from one address to another, all or nothing.
+  pub fn transfer(&mut self, from: &str, to: &str, ticker: &str, amount: TokenAmount) -> Result<(), ContractError> {
+    if account(from) != account(to) && self.balance(to, ticker).checked_add(amount).is_none() {
+      return Err(ContractError::Overflow{ address: to.to_string(), ticker: ticker.to_string() });
+    }
+    self.debit(from, ticker, amount)?;
+    self.credit(to, ticker, amount)
+  }
+}
+
+/// The key `address`'s balances are kept under: the canonical form if it is
+/// an address, and the name as it is otherwise.
+fn account(address: &str) -> String {
+  address.parse::<Address>().map_or_else(|_| address.to_string(), |address| address.canonical().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn atto(n: u128) -> TokenAmount {
+    TokenAmount::from_atto(n)
+  }
+
+  #[test]
+  fn test_credit_and_debit() {
+    let mut ledger = Ledger::new();
+    assert_eq!(ledger.balance(ESCROW, "WRLD"), atto(0));
+
+    ledger.credit(ESCROW, "WRLD", atto(100)).unwrap();
+    ledger.credit(ESCROW, "WRLD", atto(23)).unwrap();
+    ledger.credit(ESCROW, "MARS", atto(5)).unwrap();
+    assert_eq!(ledger.balance(ESCROW, "WRLD"), atto(123));
+    assert_eq!(ledger.balance(ESCROW, "MARS"), atto(5));
+
+    assert!(ledger.debit(ESCROW, "WRLD", atto(100)).is_ok());
+    assert_eq!(ledger.balance(ESCROW, "WRLD"), atto(23));
+  }
+
+  #[test]
+  fn test_address_forms() {
+    let mut ledger = Ledger::new();
+    ledger.credit("0xd388aB098ed3E84c0D808776440B48F685198498", "FIL", atto(3)).unwrap();
+    ledger.credit("t410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy", "FIL", atto(4)).unwrap();
+    assert_eq!(ledger.balance("f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy", "FIL"), atto(7));
+    assert_eq!(ledger.balance("0xd388ab098ed3e84c0d808776440b48f685198498", "FIL"), atto(7));
+
+    ledger.transfer("f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy", "t01234", "FIL", atto(5)).unwrap();
+    assert_eq!(ledger.balance("f01234", "FIL"), atto(5));
+    assert_eq!(ledger.balances(), vec![
+      ("f01234", "FIL", atto(5)),
+      ("f410f2oekwcmo2pueydmaq53eic2i62crtbeyuzx2gmy", "FIL", atto(2)),
+    ]);
+  }
+
+  #[test]
+  fn test_insufficient_funds() {
+    let mut ledger = Ledger::new();
+    ledger.credit(ESCROW, "WRLD", atto(10)).unwrap();
+
+    let err = ledger.transfer(ESCROW, "addressA", "WRLD", atto(11)).unwrap_err();
+    assert_eq!(err.to_string(), "Insufficient funds: escrow holds 10 WRLD but 11 WRLD is needed");
+    assert_eq!(ledger.balance(ESCROW, "WRLD"), atto(10));
+    assert_eq!(ledger.balance("addressA", "WRLD"), atto(0));
+    assert!(ledger.debit("addressA", "MARS", atto(1)).is_err());
+
+    assert!(ledger.transfer(ESCROW, "addressA", "WRLD", atto(10)).is_ok());
+    assert_eq!(ledger.balance(ESCROW, "WRLD"), atto(0));
+    assert_eq!(ledger.balance("addressA", "WRLD"), atto(10));
+
+    ledger.credit(ESCROW, "FIL", "1.5 FIL".parse().unwrap()).unwrap();
+    let err = ledger.debit(ESCROW, "FIL", "2 FIL".parse().unwrap()).unwrap_err();
+    assert_eq!(err.to_string(), "Insufficient funds: escrow holds 1.5 FIL but 2 FIL is needed");
+  }
+
+  #[test]
+  fn test_overflow() {
+    let mut ledger = Ledger::new();
+    ledger.credit(ESCROW, "WRLD", atto(u128::MAX)).unwrap();
+    ledger.credit("addressA", "WRLD", atto(1)).unwrap();
+
+    let err = ledger.credit(ESCROW, "WRLD", atto(1)).unwrap_err();
+    assert_eq!(err.to_string(), "Balance overflow: escrow would hold more WRLD than a token amount can");
+    assert!(ledger.transfer("addressA", ESCROW, "WRLD", atto(1)).is_err());
+    assert_eq!(ledger.balance(ESCROW, "WRLD"), atto(u128::MAX));
+    assert_eq!(ledger.balance("addressA", "WRLD"), atto(1));
+  }
+}
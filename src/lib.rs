@@ -2,15 +2,16 @@ include!(concat!(env!("OUT_DIR"), "/gen.rs"));
 
 mod ast;
 mod backend;
+pub mod diagnostic;
 pub mod parser;
 
 // use backend::wasm;
 use wasm_bindgen::prelude::*;
 use crate::ast::Expr;
-use crate::parser::expression_parser;
+use crate::parser::parse_expression;
 use crate::backend::wasm::translate_to_rust;
-use combine::Parser;
 use std::env;
+use std::error::Error;
 
 #[wasm_bindgen]
 extern "C" {
@@ -32,13 +33,14 @@ pub fn translate_to_str(e: Expr) -> String {
   translate_to_rust(e)
 }
 
+/// Reads and parses an expression file. A parse error comes back as a
+/// `Diagnostic` naming the file.
 // #[wasm_bindgen]
-pub fn read_monet_file(path: &str) -> Expr {
+pub fn read_monet_file(path: &str) -> Result<Expr, Box<dyn Error>> {
   let contents = std::fs::read_to_string(path)
-    .expect("Something went wrong reading the file");
+    .map_err(|err| format!("Could not read `{}`: {}", path, err))?;
 
-  let expr = expression_parser().parse(contents.trim()).unwrap().0;
-  expr
+  parse_expression(&contents).map_err(|diagnostic| (*diagnostic).with_file(path).into())
 }
 
 #[macro_export]
@@ -72,7 +74,7 @@ mod tests {
   #[test]
   fn test_read_file() {
     let path = "src/expression.mt";
-    let expr = read_monet_file(path);
+    let expr = read_monet_file(path).unwrap();
     let expect = Expr::BinOp {
       op: '+',
       lhs: Box::new(Expr::Number(1200.21)),
@@ -86,7 +88,7 @@ mod tests {
   #[test]
   fn test_translate_to_str() {
     let path = "src/expression.mt";
-    let expr = read_monet_file(path).to_string();
+    let expr = read_monet_file(path).unwrap().to_string();
     let expect = "1200.21 + 54.012";
     assert_eq!(expr, expect);
   }
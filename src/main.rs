@@ -1,14 +1,29 @@
 #![allow(unused)]
 #![allow(dead_code)]
 
+mod address;
+mod diagnostic;
 mod expr;
 mod op;
+mod engine;
+mod ledger;
+mod error;
+mod cbor;
+mod clock;
+mod deal;
+mod eval;
 mod parser;
 mod ast;
 // mod wasm;
 // mod compiler;
 // mod integration;
 mod backend;
+mod formatter;
+mod json;
+mod registry;
+mod replay;
+mod schema;
+mod solidity;
 
 use std::fs::File;
 use std::path::Path;
@@ -297,6 +312,45 @@ use crate::ast::Function;
 // }
 
 
+/// `monet fmt` prints a contract in its canonical layout, `monet export`
+/// as JSON, and `monet import` reads JSON back into contract source.
+/// `monet check` only reports what is wrong with a contract, if anything.
+/// `monet solidity` compiles a contract to Solidity, named after its file,
+/// and `monet solidity-tests` prints Foundry tests for that contract.
+fn tool(command: &str, path: &str) -> Result<String, error::ContractError> {
+    let source = fs::read_to_string(path)
+        .map_err(|err| error::ContractError::Io(format!("Could not read `{}`: {}", path, err)))?;
+    let output = match command {
+        "fmt" => formatter::format_source(&source),
+        "check" => expr::parse_contract(&source).map(|_| String::new()),
+        "export" => expr::parse_contract(&source).map(|contract| json::to_json(&contract) + "\n"),
+        "solidity" | "solidity-tests" => expr::parse_contract(&source).and_then(|contract| {
+            let stem = Path::new(path).file_stem().and_then(|stem| stem.to_str()).unwrap_or("contract");
+            let name = solidity::contract_name(stem);
+            match command {
+                "solidity" => solidity::to_solidity(&contract, &registry::Registry::new(), &name),
+                _ => solidity::to_solidity_tests(&contract, &registry::Registry::new(), &name),
+            }
+        }),
+        _ => json::from_json(&source).map(|contract| formatter::format_contract(&contract, &[])),
+    };
+    output.map_err(|err| match err {
+        error::ContractError::Parse(diagnostic) => error::ContractError::Parse(Box::new((*diagnostic).with_file(path))),
+        err => err,
+    })
+}
+
+/// Replays the contract at `path` against the event log at `log`.
+fn replay(path: &str, log: &str) -> Result<String, error::ContractError> {
+    let read = |path: &str| fs::read_to_string(path)
+        .map_err(|err| error::ContractError::Io(format!("Could not read `{}`: {}", path, err)));
+    let contract = expr::parse_contract(&read(path)?).map_err(|err| match err {
+        error::ContractError::Parse(diagnostic) => error::ContractError::Parse(Box::new((*diagnostic).with_file(path))),
+        err => err,
+    })?;
+    Ok(replay::replay(contract, &read(log)?)?.to_string())
+}
+
 fn main() {
     let data_layout_str = "e-m:e-p:32:32-i64:64-n32:64-S128";
     // let target = Target::from_name("wasm32-unknown-unknown").unwrap();
@@ -372,6 +426,22 @@ fn main() {
 
         // compiler.module.print_to_file(Path::new(output)).unwrap();
         // compiler.module.write_bitcode_to_file(&output_file, true, true);
+    } else if args.len() == 3 && ["fmt", "check", "export", "import", "solidity", "solidity-tests"].contains(&args[1].as_str()) {
+        match tool(&args[1], &args[2]) {
+            Ok(output) => print!("{}", output),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            },
+        }
+    } else if args.len() == 4 && args[1] == "replay" {
+        match replay(&args[2], &args[3]) {
+            Ok(output) => print!("{}", output),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            },
+        }
     } else if args.len() == 2 && vec!["-ll", "--llvm-prompt"].contains(&args[1].as_str()) {
         loop {
             print!("monet-llvm> ");
@@ -1,130 +1,882 @@
-use std::thread::panicking;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
-use crate::expr::Expr;
+use cid::Cid;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 
-pub(crate) struct Token {
-  name: String,
-  ticker: String,
-  amount: usize
+use crate::address::Address;
+use crate::cbor::{Decoder, Encoder};
+use crate::error::{ContractError, FieldError};
+use crate::expr::{Contract, Expr};
+use crate::ledger::{Ledger, ESCROW};
+
+/// Multicodec of an unsealed piece commitment, `fil-commitment-unsealed`.
+pub(crate) const FIL_COMMITMENT_UNSEALED: u64 = 0xf101;
+/// Multihash of a piece commitment, `sha2-256-trunc254-padded`.
+pub(crate) const SHA2_256_TRUNC254_PADDED: u64 = 0x1012;
+
+/// Filecoin's denominations, largest first, with the power of ten of
+/// attoFIL each is worth.
+const UNITS: [(&str, u32); 7] = [
+  ("FIL", 18),
+  ("milliFIL", 15),
+  ("microFIL", 12),
+  ("nanoFIL", 9),
+  ("picoFIL", 6),
+  ("femtoFIL", 3),
+  ("attoFIL", 0),
+];
+
+/// The named units amounts of `ticker` are written in, largest first. Only
+/// FIL has any; every other token is counted in its base unit.
+fn units(ticker: &str) -> &'static [(&'static str, u32)] {
+  match ticker {
+    "FIL" => &UNITS,
+    _ => &[],
+  }
+}
+
+/// An exact, non-negative amount of tokens counted in the token's base
+/// unit: attoFIL for FIL. Amounts written with a unit are FIL. Arithmetic
+/// is checked: anything that would overflow or go negative fails instead
+/// of wrapping.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TokenAmount(u128);
+
+impl TokenAmount {
+  pub const fn from_atto(atto: u128) -> Self {
+    TokenAmount(atto)
+  }
+
+  pub fn atto(self) -> u128 {
+    self.0
+  }
+
+  pub fn checked_add(self, other: TokenAmount) -> Option<TokenAmount> {
+    self.0.checked_add(other.0).map(TokenAmount)
+  }
+
+  pub fn checked_sub(self, other: TokenAmount) -> Option<TokenAmount> {
+    self.0.checked_sub(other.0).map(TokenAmount)
+  }
+
+  /// The amount as so much of `ticker`: in FIL's units for FIL, e.g.
+  /// `1.5 FIL`, and as a count of the base unit otherwise, e.g. `100 WRLD`.
+  pub fn with_ticker(self, ticker: &str) -> String {
+    match units(ticker) {
+      [] => format!("{} {}", self.0, ticker),
+      _ => self.to_string(),
+    }
+  }
+
+  /// Multiplies by `numerator / denominator`, rounding down.
+  pub fn checked_scale(self, numerator: u128, denominator: u128) -> Option<TokenAmount> {
+    self.0.checked_mul(numerator)?.checked_div(denominator).map(TokenAmount)
+  }
+
+  /// Reads a number written in one of the units, e.g. `1.5` and `FIL`,
+  /// exactly. Fails on an unknown unit, on anything finer than an attoFIL
+  /// and on overflow.
+  pub fn parse(number: &str, unit: &str) -> Result<TokenAmount, String> {
+    let exponent = match UNITS.iter().find(|(name, _)| *name == unit) {
+      Some((_, exponent)) => *exponent,
+      None => return Err(format!(
+        "`{}` is not a unit, expected one of {}",
+        unit,
+        UNITS.map(|(name, _)| name).join(", ")
+      )),
+    };
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let fraction = fraction.trim_end_matches('0');
+    let digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if whole.is_empty() || !digits(whole) || !digits(fraction) {
+      return Err(format!("`{}` is not a number", number));
+    }
+    if fraction.len() > exponent as usize {
+      return Err(format!("`{} {}` is finer than an attoFIL", number, unit));
+    }
+    let overflow = || format!("`{} {}` is too large for a token amount", number, unit);
+    let scale = |n: u128, exponent: u32| n.checked_mul(10u128.pow(exponent));
+    let whole = whole.parse::<u128>().ok().and_then(|n| scale(n, exponent)).ok_or_else(overflow)?;
+    let fraction = match fraction {
+      "" => 0,
+      f => scale(f.parse::<u128>().unwrap(), exponent - f.len() as u32).unwrap(),
+    };
+    whole.checked_add(fraction).map(TokenAmount).ok_or_else(overflow)
+  }
+}
+
+impl FromStr for TokenAmount {
+  type Err = String;
+
+  /// Reads an amount such as `1.5 FIL` or `10attoFIL`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    TokenAmount::parse(s[..split].trim(), s[split..].trim())
+  }
+}
+
+impl fmt::Display for TokenAmount {
+  /// Writes the amount in the largest unit it has at least one of, e.g.
+  /// `1.5 FIL` or `250 nanoFIL`, with no trailing zeros.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let (unit, exponent) = UNITS
+      .iter()
+      .find(|(_, exponent)| self.0 >= 10u128.pow(*exponent))
+      .unwrap_or(&UNITS[0]);
+    let scale = 10u128.pow(*exponent);
+    let (whole, fraction) = (self.0 / scale, self.0 % scale);
+    if fraction == 0 {
+      return write!(f, "{} {}", whole, unit);
+    }
+    let fraction = format!("{:0width$}", fraction, width = *exponent as usize);
+    write!(f, "{}.{} {}", whole, fraction.trim_end_matches('0'), unit)
+  }
+}
+
+/// Amounts serialize as a string of attoFIL, which JSON numbers can't hold
+/// exactly. Reading also takes an amount with its unit, e.g. `"1.5 FIL"`.
+impl Serialize for TokenAmount {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&self.0)
+  }
+}
+
+impl<'de> Deserialize<'de> for TokenAmount {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    match s.parse::<u128>() {
+      Ok(atto) => Ok(TokenAmount(atto)),
+      Err(_) => s.parse().map_err(de::Error::custom),
+    }
+  }
 }
 
-pub(crate) struct DealRequest {
-  piece_cid: String,
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Token {
+  pub(crate) name: String,
+  pub(crate) ticker: String,
+  pub(crate) amount: TokenAmount
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DealRequest {
+  piece_cid: Cid,
   piece_size: u64,
   verified_deal: bool,
   label: String,
   start_epoch: i64,
   end_epoch: i64,
-  storage_price_per_epoch: usize,
-  provider_collateral: usize,
+  storage_price_per_epoch: TokenAmount,
+  provider_collateral: TokenAmount,
   extra_params_version: u64,
 }
 
-impl From<&Expr> for DealRequest {
-  fn from(expr: &Expr) -> Self {
+/// Reads the fields of a dict argument, recording every missing or wrongly
+/// typed one instead of stopping at the first. Readers hand back a default
+/// value on failure; `finish` then decides whether the result stands.
+pub(crate) struct Fields<'a> {
+  hm: &'a HashMap<String, Expr>,
+  errors: Vec<FieldError>,
+}
+
+impl<'a> Fields<'a> {
+  pub(crate) fn new(hm: &'a HashMap<String, Expr>) -> Self {
+    Self { hm, errors: Vec::new() }
+  }
+
+  /// Reads a dict-valued argument, failing straight away if it isn't one.
+  pub(crate) fn of(expr: &'a Expr, target: &str) -> Result<Self, ContractError> {
     match expr {
-      Expr::Dict(hm) => {
-        let piece_cid = match hm.get("piece_cid") {
-          Some(Expr::QuotedString(s)) => s.to_string(),
-          _ => panic!("Missing piece_cid!")
-        };
-        let piece_size = match hm.get("piece_size") {
-          Some(Expr::Integer(n)) => *n,
-          _ => panic!("Missing piece_size!")
-        };
-        let verified_deal = match hm.get("verified_deal") {
-          Some(Expr::Bool(b)) => *b,
-          _ => panic!("Missing verified_deal!")
-        };
-        let label = match hm.get("label") {
-          Some(Expr::QuotedString(s)) => s.to_string(),
-          _ => panic!("Missing label!")
-        };
-        let start_epoch = match hm.get("start_epoch") {
-          Some(Expr::Integer(n)) => *n,
-          _ => panic!("Missing start_epoch!")
-        };
-        let end_epoch = match hm.get("end_epoch") {
-          Some(Expr::Integer(n)) => *n,
-          _ => panic!("Missing end_epoch!")
-        };
-        let storage_price_per_epoch = match hm.get("storage_price_per_epoch") {
-          Some(Expr::Integer(n)) => *n,
-          _ => panic!("Missing storage_price_per_epoch!")
-        };
-        let provider_collateral = match hm.get("provider_collateral") {
-          Some(Expr::Integer(n)) => *n,
-          _ => panic!("Missing provider_collateral!")
-        };
-        let extra_params_version = match hm.get("extra_params_version") {
-          Some(Expr::Integer(n)) => *n,
-          _ => panic!("Missing extra_params_version!")
-        };
-        DealRequest{
-          piece_cid,
-          piece_size: piece_size as u64,
-          verified_deal,
-          label,
-          start_epoch: start_epoch as i64,
-          end_epoch: end_epoch as i64,
-          storage_price_per_epoch,
-          provider_collateral,
-          extra_params_version: extra_params_version as u64
-        }
+      Expr::Dict(hm) => Ok(Self::new(hm)),
+      _ => Err(ContractError::InvalidArguments{
+        target: target.to_string(),
+        errors: vec![FieldError::WrongType{
+          field: String::new(),
+          expected: "Expr::Dict",
+          found: expr.variant_name(),
+        }],
+      }),
+    }
+  }
+
+  fn get(&mut self, key: &str, expected: &'static str) -> Option<&'a Expr> {
+    let value = self.hm.get(key);
+    if value.is_none() {
+      self.errors.push(FieldError::Missing{ field: key.to_string(), expected });
+    }
+    value
+  }
+
+  fn wrong_type(&mut self, key: &str, expected: &'static str, found: &Expr) {
+    self.errors.push(FieldError::WrongType{
+      field: key.to_string(),
+      expected,
+      found: found.variant_name(),
+    });
+  }
+
+  pub(crate) fn string(&mut self, key: &str) -> String {
+    match self.get(key, "Expr::QuotedString") {
+      Some(Expr::QuotedString(s)) => s.to_string(),
+      Some(e) => { self.wrong_type(key, "Expr::QuotedString", e); String::new() },
+      None => String::new(),
+    }
+  }
+
+  /// An account: a plain string naming it, or an address literal, which is
+  /// kept in its canonical form.
+  pub(crate) fn account(&mut self, key: &str) -> String {
+    match self.get(key, "Expr::QuotedString") {
+      Some(Expr::Address(address)) => address.canonical().to_string(),
+      Some(Expr::QuotedString(s)) => s.to_string(),
+      Some(e) => { self.wrong_type(key, "Expr::QuotedString", e); String::new() },
+      None => String::new(),
+    }
+  }
+
+  pub(crate) fn integer(&mut self, key: &str) -> usize {
+    match self.get(key, "Expr::Integer") {
+      Some(Expr::Integer(n)) => *n,
+      Some(e) => { self.wrong_type(key, "Expr::Integer", e); 0 },
+      None => 0,
+    }
+  }
+
+  /// A chain epoch, which the market actor keeps signed.
+  pub(crate) fn epoch(&mut self, key: &str) -> i64 {
+    let n = self.integer(key);
+    i64::try_from(n).unwrap_or_else(|_| {
+      self.errors.push(FieldError::Invalid{
+        field: key.to_string(),
+        reason: format!("epoch {} is out of range", n),
+      });
+      0
+    })
+  }
+
+  /// A token amount, either a FIL literal with a unit or a plain integer
+  /// counted in attoFIL.
+  pub(crate) fn amount(&mut self, key: &str) -> TokenAmount {
+    match self.get(key, "Expr::TokenAmount") {
+      Some(Expr::TokenAmount(amount)) => *amount,
+      Some(Expr::Integer(n)) => TokenAmount::from_atto(*n as u128),
+      Some(e) => { self.wrong_type(key, "Expr::TokenAmount", e); TokenAmount::default() },
+      None => TokenAmount::default(),
+    }
+  }
+
+  /// An amount of `ticker`, as `amount` reads it. Only FIL amounts can be
+  /// written with a unit; any other token's is a plain integer of its base
+  /// unit.
+  pub(crate) fn amount_of(&mut self, key: &str, ticker: &str) -> TokenAmount {
+    match self.hm.get(key) {
+      Some(Expr::TokenAmount(amount)) if !ticker.is_empty() && units(ticker).is_empty() => {
+        self.errors.push(FieldError::Invalid{
+          field: key.to_string(),
+          reason: format!("`{}` is in FIL units, but the token is {}", amount, ticker),
+        });
+        TokenAmount::default()
       },
-      _ => panic!("Not a Expr::Dict!")
+      _ => self.amount(key),
     }
   }
-}
 
-impl From<&Expr> for Token {
-  fn from(expr: &Expr) -> Self {
-    match expr {
-      Expr::Token{name, ticker, amount} => {
-        Token{
-          name: name.to_string(),
-          ticker: ticker.to_string(),
-          amount: *amount
-        }
+  pub(crate) fn piece_cid(&mut self, key: &str) -> Cid {
+    match self.get(key, "Expr::QuotedString") {
+      Some(Expr::QuotedString(s)) => parse_piece_cid(s).unwrap_or_else(|reason| {
+        self.errors.push(FieldError::Invalid{ field: key.to_string(), reason });
+        Cid::default()
+      }),
+      Some(e) => { self.wrong_type(key, "Expr::QuotedString", e); Cid::default() },
+      None => Cid::default(),
+    }
+  }
+
+  pub(crate) fn bool(&mut self, key: &str) -> bool {
+    match self.get(key, "Expr::Bool") {
+      Some(Expr::Bool(b)) => *b,
+      Some(e) => { self.wrong_type(key, "Expr::Bool", e); false },
+      None => false,
+    }
+  }
+
+  /// Reads a nested argument, recording its field errors under `key`.
+  /// Any other error is passed on as is.
+  pub(crate) fn nested<T>(&mut self, key: &str) -> Result<T, ContractError>
+    where T: Default + for<'e> TryFrom<&'e Expr, Error = ContractError>
+  {
+    let value = match self.get(key, "Expr::Dict") {
+      Some(value) => value,
+      None => return Ok(T::default()),
+    };
+    match T::try_from(value) {
+      Err(ContractError::InvalidArguments{ errors, .. }) => {
+        self.errors.extend(errors.into_iter().map(|e| e.nest(key)));
+        Ok(T::default())
       },
-      _ => panic!("Not a Expr::Token!")
+      result => result,
     }
   }
-}
 
-// Some dummy implementation for now.
-pub(crate) fn propose(expr: Option<Expr>) {
-  if let Some(Expr::Dict(hm)) = expr {
-    if let Some(deal_expr) = hm.get("deal_request") {
-      propose_inner(deal_expr.into());
+  pub(crate) fn finish<T>(self, target: &str, value: T) -> Result<T, ContractError> {
+    if self.errors.is_empty() {
+      Ok(value)
     } else {
-      panic!("Missing argument for propose!");
+      Err(ContractError::InvalidArguments{ target: target.to_string(), errors: self.errors })
     }
-  } else {
-    panic!("Wrong argument type for propose!");
   }
 }
 
-pub(crate) fn pay(expr: Option<Expr>) {
-  if let Some(Expr::Dict(hm)) = expr {
-    if let (
-      Some(Expr::QuotedString(to)),
-      Some(token_expr)
-    )  = (hm.get("to"), hm.get("token")) {
-      pay_inner(to.to_string(), token_expr.into());
-    } else {
-      panic!("Missing argument for pay!");
+/// Parses a piece CID, checking that it really is an unsealed piece
+/// commitment rather than any CID.
+pub(crate) fn parse_piece_cid(s: &str) -> Result<Cid, String> {
+  let cid = Cid::try_from(s).map_err(|err| format!("`{}` is not a CID: {}", s, err))?;
+  check_piece_cid(&cid)?;
+  Ok(cid)
+}
+
+fn check_piece_cid(cid: &Cid) -> Result<(), String> {
+  if cid.codec() != FIL_COMMITMENT_UNSEALED {
+    return Err(format!(
+      "`{}` has codec {:#x}, expected fil-commitment-unsealed ({:#x})",
+      cid, cid.codec(), FIL_COMMITMENT_UNSEALED
+    ));
+  }
+  if cid.hash().code() != SHA2_256_TRUNC254_PADDED {
+    return Err(format!(
+      "`{}` has multihash {:#x}, expected sha2-256-trunc254-padded ({:#x})",
+      cid, cid.hash().code(), SHA2_256_TRUNC254_PADDED
+    ));
+  }
+  if cid.hash().size() != 32 {
+    return Err(format!("`{}` has a {} byte digest, expected 32", cid, cid.hash().size()));
+  }
+  Ok(())
+}
+
+/// Checks every literal piece CID in a contract, both in `propose` deal
+/// requests and in event patterns, so that a bad one is caught on load
+/// rather than when the clause fires.
+pub(crate) fn check_piece_cids(contract: &Contract) -> Result<(), ContractError> {
+  let check = |target: &str, hm: &HashMap<String, Expr>| match hm.get("piece_cid") {
+    Some(Expr::QuotedString(s)) => parse_piece_cid(s).map(|_| ()).map_err(|reason| {
+      ContractError::InvalidArguments{
+        target: target.to_string(),
+        errors: vec![FieldError::Invalid{ field: "piece_cid".to_string(), reason }],
+      }
+    }),
+    _ => Ok(()),
+  };
+
+  for (event_op, ops) in contract.clauses() {
+    if let Expr::Event{ name, args } = &event_op.event {
+      check(name, args)?;
+    }
+    for op in ops.iter().chain(event_op.timeout_ops()) {
+      if let Some(Expr::Dict(hm)) = &op.arg {
+        if let Some(Expr::Dict(deal_request)) = hm.get("deal_request") {
+          check("deal_request", deal_request)?;
+        }
+      }
     }
-  } else {
-    panic!("Wrong argument type for pay!");
   }
+  Ok(())
 }
 
-// Just print stuff for now.
-fn pay_inner(to: String, token: Token) {
-    println!("Sending token {} to {}!", token.name, to);
+impl TryFrom<&Expr> for DealRequest {
+  type Error = ContractError;
+
+  fn try_from(expr: &Expr) -> Result<Self, Self::Error> {
+    let mut fields = Fields::of(expr, "deal_request")?;
+    let deal_request = DealRequest{
+      piece_cid: fields.piece_cid("piece_cid"),
+      piece_size: fields.integer("piece_size") as u64,
+      verified_deal: fields.bool("verified_deal"),
+      label: fields.string("label"),
+      start_epoch: fields.epoch("start_epoch"),
+      end_epoch: fields.epoch("end_epoch"),
+      storage_price_per_epoch: fields.amount("storage_price_per_epoch"),
+      provider_collateral: fields.amount("provider_collateral"),
+      extra_params_version: fields.integer("extra_params_version") as u64,
+    };
+    fields.finish("deal_request", deal_request)
+  }
+}
+
+/// A deal as the builtin market actor stores it: what a contract proposes,
+/// with the parties and the client's collateral, which the host supplies.
+/// `extra_params_version` only matters to the deal client contract and
+/// isn't part of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DealProposal {
+  pub(crate) request: DealRequest,
+  pub(crate) client: Address,
+  pub(crate) provider: Address,
+  pub(crate) client_collateral: TokenAmount,
+}
+
+impl DealProposal {
+  pub fn new(request: DealRequest, client: Address, provider: Address, client_collateral: TokenAmount) -> Self {
+    Self { request, client, provider, client_collateral }
+  }
+
+  /// Encodes the proposal as the market actor's `DealProposal` tuple:
+  /// the piece CID under tag 42, the piece size, whether the deal is
+  /// verified, the client and provider addresses as bytes, the label as a
+  /// string, the start and end epochs, and the price per epoch and both
+  /// collaterals as big-integer bytes.
+  pub fn to_cbor(&self) -> Vec<u8> {
+    let mut encoder = Encoder::new();
+    self.encode(&mut encoder);
+    encoder.into_bytes()
+  }
+
+  fn encode(&self, encoder: &mut Encoder) {
+    let request = &self.request;
+    encoder
+      .array(11)
+      .cid(&request.piece_cid)
+      .uint(request.piece_size)
+      .bool(request.verified_deal)
+      .bytes(&self.client.to_bytes())
+      .bytes(&self.provider.to_bytes())
+      .text(&request.label)
+      .int(request.start_epoch)
+      .int(request.end_epoch)
+      .big_uint(request.storage_price_per_epoch.atto())
+      .big_uint(request.provider_collateral.atto())
+      .big_uint(self.client_collateral.atto());
+  }
+
+  /// Reads a `DealProposal` tuple. The request's `extra_params_version` is
+  /// not in it and comes back as 0.
+  pub fn from_cbor(bytes: &[u8]) -> Result<Self, ContractError> {
+    let mut decoder = Decoder::new(bytes);
+    decoder.array(11)?;
+    let piece_cid = decoder.cid()?;
+    check_piece_cid(&piece_cid).map_err(ContractError::Cbor)?;
+    let piece_size = decoder.uint()?;
+    let verified_deal = decoder.bool()?;
+    let address = |bytes: &[u8]| Address::from_bytes(bytes).map_err(ContractError::Cbor);
+    let client = address(decoder.bytes()?)?;
+    let provider = address(decoder.bytes()?)?;
+    let request = DealRequest{
+      piece_cid,
+      piece_size,
+      verified_deal,
+      label: decoder.text()?,
+      start_epoch: decoder.int()?,
+      end_epoch: decoder.int()?,
+      storage_price_per_epoch: TokenAmount::from_atto(decoder.big_uint()?),
+      provider_collateral: TokenAmount::from_atto(decoder.big_uint()?),
+      extra_params_version: 0,
+    };
+    let client_collateral = TokenAmount::from_atto(decoder.big_uint()?);
+    decoder.finish()?;
+    Ok(DealProposal{ request, client, provider, client_collateral })
+  }
+}
+
+/// A proposal with the client's signature over its CBOR, in Filecoin's
+/// binary form: the signature type byte followed by the signature.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientDealProposal {
+  pub proposal: DealProposal,
+  pub client_signature: Vec<u8>,
+}
+
+/// Encodes the parameters of the market actor's `PublishStorageDeals`
+/// method: a tuple holding the list of signed proposals.
+pub fn publish_storage_deals_params(deals: &[ClientDealProposal]) -> Vec<u8> {
+  let mut encoder = Encoder::new();
+  encoder.array(1).array(deals.len());
+  for deal in deals {
+    encoder.array(2);
+    deal.proposal.encode(&mut encoder);
+    encoder.bytes(&deal.client_signature);
+  }
+  encoder.into_bytes()
+}
+
+impl TryFrom<&Expr> for Token {
+  type Error = ContractError;
+
+  fn try_from(expr: &Expr) -> Result<Self, Self::Error> {
+    if let Expr::Token{name, ticker, amount} = expr {
+      return Ok(Token{
+        name: name.to_string(),
+        ticker: ticker.to_string(),
+        amount: *amount
+      });
+    }
+    let mut fields = Fields::of(expr, "token")?;
+    let name = fields.string("name");
+    let ticker = fields.string("ticker");
+    let amount = fields.amount_of("amount", &ticker);
+    let token = Token{ name, ticker, amount };
+    fields.finish("token", token)
+  }
+}
+
+fn args<'a>(expr: &'a Option<Expr>, target: &str) -> Result<Fields<'a>, ContractError> {
+  match expr {
+    Some(expr) => Fields::of(expr, target),
+    None => Err(ContractError::InvalidArguments{
+      target: target.to_string(),
+      errors: vec![FieldError::Missing{ field: String::new(), expected: "Expr::Dict" }],
+    }),
+  }
+}
+
+/// Something a contract wants done outside of it, produced by its ops. The
+/// engine only tracks balances; it's up to the host to carry effects out.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Effect {
+  /// Pay `token` out of escrow to `to`.
+  Transfer { to: String, token: Token },
+  /// Propose a storage deal to the market actor. The host adds the parties
+  /// and the client collateral to make it a `DealProposal`.
+  ProposeDeal(DealRequest),
 }
 
-fn propose_inner(deal_request: DealRequest) {
-  println!("Proposing deal to {}!", deal_request.piece_cid);
-}
\ No newline at end of file
+pub(crate) fn propose(_ledger: &mut Ledger, expr: Option<Expr>) -> Result<Vec<Effect>, ContractError> {
+  let mut fields = args(&expr, "propose")?;
+  let deal_request = fields.nested::<DealRequest>("deal_request")?;
+  let deal_request = fields.finish("propose", deal_request)?;
+  Ok(vec![Effect::ProposeDeal(deal_request)])
+}
+
+/// Pays out of the contract's escrow, failing if it doesn't hold enough.
+pub(crate) fn pay(ledger: &mut Ledger, expr: Option<Expr>) -> Result<Vec<Effect>, ContractError> {
+  let mut fields = args(&expr, "pay")?;
+  let to = fields.account("to");
+  let token = fields.nested::<Token>("token")?;
+  let token = fields.finish("pay", token)?;
+  ledger.transfer(ESCROW, &to, &token.ticker, token.amount)?;
+  Ok(vec![Effect::Transfer{ to, token }])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::expr::decode;
+
+  const PIECE_CID: &str = "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy";
+  const RAW_CID: &str = "bafyreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku";
+
+  fn deal_request() -> DealRequest {
+    DealRequest{
+      piece_cid: Cid::try_from(PIECE_CID).unwrap(),
+      piece_size: 2048,
+      verified_deal: true,
+      label: "label".to_string(),
+      start_epoch: 1000,
+      end_epoch: 519400,
+      storage_price_per_epoch: TokenAmount::default(),
+      provider_collateral: TokenAmount::from_atto(1_000_000_000),
+      extra_params_version: 1,
+    }
+  }
+
+  fn proposal() -> DealProposal {
+    DealProposal::new(deal_request(), "f0100".parse().unwrap(), "f01000".parse().unwrap(), TokenAmount::default())
+  }
+
+  #[test]
+  fn test_deal_proposal_cbor() {
+    // Field by field as the market actor lays out a `DealProposal`.
+    let mut expected: Vec<u8> = vec![0x8b];
+    // The piece CID: tag 42 over 40 bytes, with the multibase prefix.
+    expected.extend([
+      0xd8, 0x2a, 0x58, 0x28, 0x00, 0x01, 0x81, 0xe2, 0x03, 0x92, 0x20, 0x20,
+      0xfc, 0x7e, 0x92, 0x82, 0x96, 0xe5, 0x16, 0xfa, 0xad, 0xe9, 0x86, 0xb2,
+      0x8f, 0x92, 0xd4, 0x4a, 0x4f, 0x24, 0xb9, 0x35, 0x48, 0x52, 0x23, 0x37,
+      0x6a, 0x79, 0x90, 0x27, 0xbc, 0x18, 0xf8, 0x33,
+    ]);
+    // The piece size and the verified flag.
+    expected.extend([0x19, 0x08, 0x00, 0xf5]);
+    // The client, f0100, and the provider, f01000.
+    expected.extend([0x42, 0x00, 0x64, 0x43, 0x00, 0xe8, 0x07]);
+    expected.extend([0x65, b'l', b'a', b'b', b'e', b'l']);
+    // The start and end epochs.
+    expected.extend([0x19, 0x03, 0xe8, 0x1a, 0x00, 0x07, 0xec, 0xe8]);
+    // The price, nothing; the provider collateral, a billion attoFIL; the
+    // client collateral, nothing.
+    expected.extend([0x40, 0x45, 0x00, 0x3b, 0x9a, 0xca, 0x00, 0x40]);
+
+    assert_eq!(proposal().to_cbor(), expected);
+    let request = DealRequest{ extra_params_version: 0, ..deal_request() };
+    assert_eq!(DealProposal::from_cbor(&expected), Ok(DealProposal{ request, ..proposal() }));
+  }
+
+  #[test]
+  fn test_deal_proposal_cbor_negative_epoch() {
+    let proposal = DealProposal{
+      request: DealRequest{ start_epoch: -1, extra_params_version: 0, ..deal_request() },
+      ..proposal()
+    };
+    let bytes = proposal.to_cbor();
+    // The start epoch follows the label and encodes as CBOR -1.
+    assert_eq!(bytes[56..62], [0x65, b'l', b'a', b'b', b'e', b'l']);
+    assert_eq!(bytes[62], 0x20);
+    assert_eq!(DealProposal::from_cbor(&bytes), Ok(proposal));
+  }
+
+  #[test]
+  fn test_deal_proposal_cbor_errors() {
+    // A dag-cbor CID in place of the piece commitment.
+    let request = DealRequest{ piece_cid: Cid::try_from(RAW_CID).unwrap(), ..deal_request() };
+    match DealProposal::from_cbor(&DealProposal{ request, ..proposal() }.to_cbor()) {
+      Err(ContractError::Cbor(message)) => assert!(message.contains("expected fil-commitment-unsealed"), "{}", message),
+      result => panic!("Unexpected {:?}", result),
+    }
+
+    let mut bytes = proposal().to_cbor();
+    assert!(DealProposal::from_cbor(&bytes[..bytes.len() - 1]).is_err());
+    bytes.push(0);
+    assert_eq!(
+      DealProposal::from_cbor(&bytes),
+      Err(ContractError::Cbor("1 trailing bytes".to_string()))
+    );
+    bytes.pop();
+    bytes[0] = 0x8a;
+    assert!(DealProposal::from_cbor(&bytes).is_err());
+    // A client address with an unknown protocol.
+    bytes[0] = 0x8b;
+    bytes[50] = 0x07;
+    assert!(DealProposal::from_cbor(&bytes).is_err());
+  }
+
+  #[test]
+  fn test_publish_storage_deals_params() {
+    // Not a real signature, only its type byte, 2 for BLS, and two bytes.
+    let deal = ClientDealProposal{ proposal: proposal(), client_signature: vec![0x02, 0xaa, 0xbb] };
+    let bytes = publish_storage_deals_params(&[deal.clone(), deal]);
+    let proposal = proposal().to_cbor();
+    let mut expected = vec![0x81, 0x82];
+    for _ in 0..2 {
+      expected.push(0x82);
+      expected.extend(&proposal);
+      expected.extend([0x43, 0x02, 0xaa, 0xbb]);
+    }
+    assert_eq!(bytes, expected);
+  }
+
+  #[test]
+  fn test_deal_request_try_from() {
+    let expr = decode(&format!(r#"{{
+      piece_cid: "{}",
+      piece_size: 2048,
+      verified_deal: false,
+      label: "label",
+      start_epoch: 100,
+      end_epoch: 200,
+      storage_price_per_epoch: 3,
+      provider_collateral: 0.5 FIL,
+      extra_params_version: 1
+    }}"#, PIECE_CID)).unwrap();
+    let deal_request = DealRequest::try_from(&expr).unwrap();
+    assert_eq!(deal_request, DealRequest{
+      piece_cid: Cid::try_from(PIECE_CID).unwrap(),
+      piece_size: 2048,
+      verified_deal: false,
+      label: "label".to_string(),
+      start_epoch: 100,
+      end_epoch: 200,
+      storage_price_per_epoch: TokenAmount::from_atto(3),
+      provider_collateral: "0.5 FIL".parse().unwrap(),
+      extra_params_version: 1,
+    });
+  }
+
+  #[test]
+  fn test_deal_request_reports_every_field() {
+    let expr = decode(r#"{
+      piece_cid: 12,
+      piece_size: 2048,
+      verified_deal: "yes",
+      label: "label",
+      start_epoch: 100,
+      storage_price_per_epoch: 3,
+      provider_collateral: 4,
+      extra_params_version: 1
+    }"#).unwrap();
+    let err = DealRequest::try_from(&expr).unwrap_err();
+    assert_eq!(err, ContractError::InvalidArguments{
+      target: "deal_request".to_string(),
+      errors: vec![
+        FieldError::WrongType{ field: "piece_cid".to_string(), expected: "Expr::QuotedString", found: "Expr::Integer" },
+        FieldError::WrongType{ field: "verified_deal".to_string(), expected: "Expr::Bool", found: "Expr::QuotedString" },
+        FieldError::Missing{ field: "end_epoch".to_string(), expected: "Expr::Integer" },
+      ],
+    });
+    assert_eq!(err.to_string(), "Invalid arguments for `deal_request`:
+  `piece_cid` should be Expr::QuotedString, found Expr::Integer
+  `verified_deal` should be Expr::Bool, found Expr::QuotedString
+  missing `end_epoch` (expected Expr::Integer)");
+
+    let err = DealRequest::try_from(&Expr::Integer(1)).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `deal_request`:\n  expected Expr::Dict, found Expr::Integer");
+  }
+
+  #[test]
+  fn test_deal_request_epoch_range() {
+    let expr = decode(&format!(r#"{{
+      piece_cid: "{}",
+      piece_size: 2048,
+      verified_deal: false,
+      label: "label",
+      start_epoch: 100,
+      end_epoch: 9223372036854775808,
+      storage_price_per_epoch: 3,
+      provider_collateral: 4,
+      extra_params_version: 1
+    }}"#, PIECE_CID)).unwrap();
+    let err = DealRequest::try_from(&expr).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `deal_request`:
+  `end_epoch` is invalid: epoch 9223372036854775808 is out of range");
+  }
+
+  #[test]
+  fn test_parse_piece_cid() {
+    let cid = parse_piece_cid(PIECE_CID).unwrap();
+    assert_eq!(cid.codec(), FIL_COMMITMENT_UNSEALED);
+    assert_eq!(cid.hash().code(), SHA2_256_TRUNC254_PADDED);
+    assert_eq!(cid.to_string(), PIECE_CID);
+
+    assert_eq!(
+      parse_piece_cid(RAW_CID),
+      Err(format!("`{}` has codec 0x71, expected fil-commitment-unsealed (0xf101)", RAW_CID))
+    );
+    assert!(parse_piece_cid("").is_err());
+    assert!(parse_piece_cid("QmX").unwrap_err().starts_with("`QmX` is not a CID"));
+  }
+
+  #[test]
+  fn test_check_piece_cids() {
+    use crate::expr::parse_contract;
+
+    let err = parse_contract(r#"
+    when DealActivated { piece_cid: "QmX" }
+    "#).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `DealActivated`:
+  `piece_cid` is invalid: `QmX` is not a CID: Failed to parse multihash");
+
+    let err = parse_contract(&format!(r#"
+    when DealPublished {{ piece_cid: "{}" }} then
+      propose {{
+        deal_request: {{ piece_cid: "{}" }}
+      }}
+    "#, PIECE_CID, RAW_CID)).unwrap_err();
+    match err {
+      ContractError::InvalidArguments{ target, errors } => {
+        assert_eq!(target, "deal_request");
+        assert_eq!(errors[0].field(), "piece_cid");
+      },
+      err => panic!("Unexpected error {:?}", err),
+    }
+  }
+
+  #[test]
+  fn test_token_try_from() {
+    let expr = decode(r#"{ name: "world", ticker: "WRLD", amount: 123 }"#).unwrap();
+    assert_eq!(Token::try_from(&expr), Ok(Token{
+      name: "world".to_string(),
+      ticker: "WRLD".to_string(),
+      amount: TokenAmount::from_atto(123),
+    }));
+
+    let amount = TokenAmount::from_atto(1);
+    let expr = Expr::Token{ name: "world".to_string(), ticker: "WRLD".to_string(), amount };
+    assert_eq!(Token::try_from(&expr).map(|token| token.amount), Ok(amount));
+
+    let expr = decode(r#"{ name: "world", amount: 1.5 }"#).unwrap();
+    assert_eq!(Token::try_from(&expr).unwrap_err(), ContractError::InvalidArguments{
+      target: "token".to_string(),
+      errors: vec![
+        FieldError::Missing{ field: "ticker".to_string(), expected: "Expr::QuotedString" },
+        FieldError::WrongType{ field: "amount".to_string(), expected: "Expr::TokenAmount", found: "Expr::Decimal" },
+      ],
+    });
+
+    // Units are FIL's alone.
+    let expr = decode(r#"{ name: "world", ticker: "WRLD", amount: 1.5 FIL }"#).unwrap();
+    assert_eq!(Token::try_from(&expr).unwrap_err().to_string(), "Invalid arguments for `token`:
+  `amount` is invalid: `1.5 FIL` is in FIL units, but the token is WRLD");
+    let expr = decode(r#"{ name: "Filecoin", ticker: "FIL", amount: 15 }"#).unwrap();
+    assert_eq!(Token::try_from(&expr).map(|token| token.amount.to_string()), Ok("15 attoFIL".to_string()));
+  }
+
+  #[test]
+  fn test_with_ticker() {
+    assert_eq!("1.5 FIL".parse::<TokenAmount>().unwrap().with_ticker("FIL"), "1.5 FIL");
+    assert_eq!(TokenAmount::from_atto(100).with_ticker("WRLD"), "100 WRLD");
+  }
+
+  #[test]
+  fn test_token_amount() {
+    let fil = |s: &str| s.parse::<TokenAmount>();
+    assert_eq!(fil("1.5 FIL"), Ok(TokenAmount::from_atto(1_500_000_000_000_000_000)));
+    assert_eq!(fil("250 nanoFIL"), Ok(TokenAmount::from_atto(250_000_000_000)));
+    assert_eq!(fil("10 attoFIL"), Ok(TokenAmount::from_atto(10)));
+    assert_eq!(fil("0.000000000000000001 FIL"), Ok(TokenAmount::from_atto(1)));
+    assert_eq!(fil("2.500 milliFIL"), fil("2500 microFIL"));
+
+    for s in ["1.5 FIL", "250 nanoFIL", "10 attoFIL", "1.000000001 nanoFIL", "0 FIL", "999 femtoFIL"] {
+      assert_eq!(fil(s).unwrap().to_string(), s);
+    }
+    assert_eq!(fil("1500 milliFIL").unwrap().to_string(), "1.5 FIL");
+    assert_eq!(TokenAmount::from_atto(u128::MAX).to_string(), "340282366920938463463.374607431768211455 FIL");
+
+    assert_eq!(fil("1.5 attoFIL"), Err("`1.5 attoFIL` is finer than an attoFIL".to_string()));
+    assert_eq!(fil("1000000000000000000000 FIL"), Err("`1000000000000000000000 FIL` is too large for a token amount".to_string()));
+    assert_eq!(
+      fil("1 gigaFIL"),
+      Err("`gigaFIL` is not a unit, expected one of FIL, milliFIL, microFIL, nanoFIL, picoFIL, femtoFIL, attoFIL".to_string())
+    );
+    assert!(fil("FIL").is_err());
+
+    let max = TokenAmount::from_atto(u128::MAX);
+    assert_eq!(max.checked_add(TokenAmount::from_atto(1)), None);
+    assert_eq!(TokenAmount::default().checked_sub(TokenAmount::from_atto(1)), None);
+    assert_eq!(fil("1 FIL").unwrap().checked_scale(9, 10), fil("0.9 FIL").ok());
+    assert_eq!(max.checked_scale(2, 1), None);
+  }
+
+  #[test]
+  fn test_pay_reports_nested_fields() {
+    let mut ledger = Ledger::new();
+    let arg = decode(r#"{ token: { name: "world", ticker: 1, amount: 5 } }"#).unwrap();
+    let err = pay(&mut ledger, Some(arg)).unwrap_err();
+    assert_eq!(err, ContractError::InvalidArguments{
+      target: "pay".to_string(),
+      errors: vec![
+        FieldError::Missing{ field: "to".to_string(), expected: "Expr::QuotedString" },
+        FieldError::WrongType{ field: "token.ticker".to_string(), expected: "Expr::QuotedString", found: "Expr::Integer" },
+      ],
+    });
+
+    let err = pay(&mut ledger, None).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `pay`:\n  missing argument (expected Expr::Dict)");
+  }
+
+  #[test]
+  fn test_propose_reports_nested_fields() {
+    let mut ledger = Ledger::new();
+    let arg = decode(r#"{ deal_request: { piece_cid: "QmX" } }"#).unwrap();
+    match propose(&mut ledger, Some(arg)).unwrap_err() {
+      ContractError::InvalidArguments{ target, errors } => {
+        assert_eq!(target, "propose");
+        assert_eq!(errors.len(), 9);
+        assert!(errors.iter().all(|e| e.field().starts_with("deal_request.")));
+        assert_eq!(errors[0].to_string(), "`deal_request.piece_cid` is invalid: `QmX` is not a CID: Failed to parse multihash");
+      },
+      err => panic!("Unexpected error {:?}", err),
+    }
+
+    let err = propose(&mut ledger, Some(decode("{}").unwrap())).unwrap_err();
+    assert_eq!(err.to_string(), "Invalid arguments for `propose`:\n  missing `deal_request` (expected Expr::Dict)");
+  }
+}
@@ -5,10 +5,11 @@ use combine::{optional, token};
 use combine::parser::char::{spaces, digit, char, letter, string};
 use combine::{attempt, between, choice, many, many1, sep_by, ParseError, Parser};
 use combine::parser::repeat::chainl1;
-use combine::stream::Stream;
-use combine::parser;
+use combine::stream::{position, Stream};
+use combine::{eof, parser, EasyParser};
 
 use crate::ast::{Expr, Prototype, Function};
+use crate::diagnostic::Diagnostic;
 
 fn parse_prototype<I>() -> impl Parser<I, Output = Prototype>
   where I: Stream<Token = char>,
@@ -195,18 +196,27 @@ where
     let div = token::<I>('/').map(|c| create_binop!(c));
     let mul = token::<I>('*').map(|c| create_binop!(c));
 
-    // let term = chainl1(chainl1(chainl1(factor, lt), div), mul);
-
-    let term = chainl1(chainl1(factor, div), mul);
+    // `*` and `/` share a level, as do `+` and `-`, and each level groups to
+    // the left, the way `expr::arithmetic` reads contract expressions.
+    let term = chainl1(factor, div.or(mul));
 
     let sub = token::<I>('-').map(|c| create_binop!(c));
     let add = token::<I>('+').map(|c| create_binop!(c));
 
-    let expr = chainl1(chainl1(chainl1(term, sub), add), lt);
+    let expr = chainl1(chainl1(term, sub.or(add)), lt);
 
     expr
 }
 
+/// Parses a whole source as one expression. Anything left over is an
+/// error, reported with its line, column and a snippet of the source.
+pub fn parse_expression(input: &str) -> Result<Expr, Box<Diagnostic>> {
+    match expression_parser().skip(eof()).easy_parse(position::Stream::new(input)) {
+      Ok((expr, _)) => Ok(expr),
+      Err(err) => Err(Box::new(Diagnostic::from_parse_error(input, err))),
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -215,6 +225,25 @@ mod tests {
   use super::*;
   use Expr::*;
 
+  #[test]
+  fn test_parse_expression() {
+    let expected = expression_parser().parse("1200.21 + 54.012").unwrap().0;
+    assert_eq!(parse_expression("\n 1200.21 + 54.012\n"), Ok(expected));
+
+    let err = parse_expression("3.0 +\n  * 2.0").unwrap_err();
+    assert_eq!((err.line, err.column), (2, 3));
+    assert_eq!(err.found.as_deref(), Some("`*`"));
+    assert_eq!(err.span, 8..9);
+
+    let err = parse_expression("foo(1.0 2.0").unwrap_err();
+    assert_eq!(err.to_string(), "\
+error: expected whitespaces or `)`, found end of input
+ --> 1:12
+  |
+1 | foo(1.0 2.0
+  |            ^");
+  }
+
   #[test]
   fn test_lt_op_precedence() {
     let result = expression_parser().parse("3.0 < 4.0 * 2.0").unwrap().0;
@@ -251,13 +280,13 @@ mod tests {
   fn test_sub_and_add_op_precedence() {
     let result = expression_parser().parse("3.0 + 4.0 - 2.0").unwrap().0;
     let expected = BinOp {
-      op: '+',
-      lhs: Box::new(Number(3.0)),
-      rhs: Box::new(BinOp {
-        op: '-',
-        lhs: Box::new(Number(4.0)),
-        rhs: Box::new(Number(2.0)),
+      op: '-',
+      lhs: Box::new(BinOp {
+        op: '+',
+        lhs: Box::new(Number(3.0)),
+        rhs: Box::new(Number(4.0)),
       }),
+      rhs: Box::new(Number(2.0)),
     };
 
     assert_eq!(result, expected);
@@ -267,14 +296,13 @@ mod tests {
   fn test_div_and_mul_op_precedence() {
     let result = expression_parser().parse("3.0 * 4.0 / 2.0").unwrap().0;
     let expected = BinOp {
-      op: '*',
-      lhs: Box::new(Number(3.0)),
-      rhs: Box::new(BinOp {
-        op: '/',
-        lhs: Box::new(Number(4.0)),
-        rhs: Box::new(Number(2.0)),
+      op: '/',
+      lhs: Box::new(BinOp {
+        op: '*',
+        lhs: Box::new(Number(3.0)),
+        rhs: Box::new(Number(4.0)),
       }),
-
+      rhs: Box::new(Number(2.0)),
     };
 
     assert_eq!(result, expected);
@@ -284,25 +312,53 @@ mod tests {
   fn test_all_op_precedence() {
     let result = expression_parser().parse("3.0 + 4.0 * 2.0 / 2.0 - 1.0").unwrap().0;
     let expected = BinOp {
-      op: '+',
-      lhs: Box::new(Number(3.0)),
-      rhs: Box::new(BinOp {
-        op: '-',
-        lhs: Box::new(BinOp {
-          op: '*',
-          lhs: Box::new(Number(4.0)),
-          rhs: Box::new(BinOp {
-            op: '/',
-            lhs: Box::new(Number(2.0)),
+      op: '-',
+      lhs: Box::new(BinOp {
+        op: '+',
+        lhs: Box::new(Number(3.0)),
+        rhs: Box::new(BinOp {
+          op: '/',
+          lhs: Box::new(BinOp {
+            op: '*',
+            lhs: Box::new(Number(4.0)),
             rhs: Box::new(Number(2.0)),
           }),
+          rhs: Box::new(Number(2.0)),
         }),
-        rhs: Box::new(Number(1.0)),
-      })
+      }),
+      rhs: Box::new(Number(1.0)),
     };
     assert_eq!(result, expected);
   }
 
+  /// Both grammars group arithmetic the same way.
+  #[test]
+  fn test_same_precedence_as_contracts() {
+    fn shape(expr: &Expr) -> String {
+      match expr {
+        Number(n) => n.to_string(),
+        Variable(name) => name.clone(),
+        BinOp{ op, lhs, rhs } => format!("({} {} {})", shape(lhs), op, shape(rhs)),
+        expr => panic!("Unexpected {:?}", expr),
+      }
+    }
+    fn contract_shape(expr: &crate::expr::Expr) -> String {
+      use crate::expr::Expr;
+      match expr {
+        Expr::Decimal(n) => n.to_string(),
+        Expr::Id(name) => name.clone(),
+        Expr::BinOp{ op, lhs, rhs } => format!("({} {} {})", contract_shape(lhs), op, contract_shape(rhs)),
+        expr => panic!("Unexpected {:?}", expr),
+      }
+    }
+
+    for source in ["1.0 + 2.0 * 3.0 / 4.0 - 5.0", "a * b / c", "a - b + c", "a / b * c - d - e", "(a + b) * c / d"] {
+      let result = expression_parser().skip(eof()).parse(source).unwrap().0;
+      let contract = crate::expr::decode(source).unwrap();
+      assert_eq!(shape(&result), contract_shape(&contract), "{}", source);
+    }
+  }
+
   #[test]
   fn test_op_with_paren() {
     let result = expression_parser().parse("(3.0 + 4.0) * 2.0").unwrap().0;
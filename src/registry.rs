@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::error::ContractError;
+use crate::expr::{Expr, Op, KEYWORDS};
+use crate::ledger::Ledger;
+use crate::op::{pay, propose, Effect};
+use crate::schema::{Field, CHOICE, DEAL_EVENT, DEPOSIT, NOTIFY, PAY, PROPOSE};
+
+/// What an op does. A handler is registered under the keyword that invokes
+/// it, e.g. `pay`, and is handed its argument once `$vars` and arithmetic
+/// in it have been evaluated. It returns the effects the host should carry
+/// out, rather than carrying them out itself.
+pub trait OpHandler: Send + Sync {
+  /// The fields the op's argument must have, checked when a contract is
+  /// loaded.
+  fn schema(&self) -> &[Field];
+
+  fn run(&self, ledger: &mut Ledger, arg: Option<Expr>) -> Result<Vec<Effect>, ContractError>;
+}
+
+struct Pay;
+
+impl OpHandler for Pay {
+  fn schema(&self) -> &[Field] {
+    PAY
+  }
+
+  fn run(&self, ledger: &mut Ledger, arg: Option<Expr>) -> Result<Vec<Effect>, ContractError> {
+    pay(ledger, arg)
+  }
+}
+
+struct Propose;
+
+impl OpHandler for Propose {
+  fn schema(&self) -> &[Field] {
+    PROPOSE
+  }
+
+  fn run(&self, ledger: &mut Ledger, arg: Option<Expr>) -> Result<Vec<Effect>, ContractError> {
+    propose(ledger, arg)
+  }
+}
+
+/// The ops a contract may use, by keyword, and the event types it may
+/// wait for besides those it declares itself. Contracts are parsed against
+/// a registry, so a keyword it doesn't have isn't an op at all.
+#[derive(Clone)]
+pub struct Registry {
+  handlers: HashMap<String, Arc<dyn OpHandler>>,
+  events: HashMap<String, Vec<Field>>,
+}
+
+impl Registry {
+  /// A registry of the builtin ops, `pay` and `propose`, and events:
+  /// `Deposit`, `Pay`, `Choice`, `Notify` and the deal lifecycle.
+  pub fn new() -> Self {
+    let mut registry = Self::empty();
+    registry.handlers.insert("pay".to_string(), Arc::new(Pay));
+    registry.handlers.insert("propose".to_string(), Arc::new(Propose));
+    let events = [("Deposit", DEPOSIT), ("Pay", PAY), ("Choice", CHOICE), ("Notify", NOTIFY)];
+    let deals = ["DealProposalCreated", "DealPublished", "DealActivated", "DealTerminated"].map(|name| (name, DEAL_EVENT));
+    for (name, fields) in events.into_iter().chain(deals) {
+      registry.events.insert(name.to_string(), fields.to_vec());
+    }
+    registry
+  }
+
+  pub fn empty() -> Self {
+    Self { handlers: HashMap::new(), events: HashMap::new() }
+  }
+
+  /// Registers `handler` as the op `name`, replacing any op of that name.
+  /// The name has to be a word, as the parser reads keywords, and can't be
+  /// one of the grammar's own, such as `when` or `close`.
+  pub fn register(&mut self, name: &str, handler: impl OpHandler + 'static) -> Result<&mut Self, ContractError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') || KEYWORDS.contains(&name) {
+      return Err(ContractError::InvalidName{ name: name.to_string(), expected: "an op keyword" });
+    }
+    self.handlers.insert(name.to_string(), Arc::new(handler));
+    Ok(self)
+  }
+
+  pub fn contains(&self, name: &str) -> bool {
+    self.handlers.contains_key(name)
+  }
+
+  /// The op `name` applied to `arg`, if it is registered.
+  pub fn op(&self, name: &str, arg: Option<Expr>) -> Option<Op> {
+    self.handlers.get(name).map(|handler| Op{
+      name: name.to_string(),
+      handler: handler.clone(),
+      arg,
+    })
+  }
+
+  /// Declares the event type `name`, replacing any of that name. Events are
+  /// named by capitalized words.
+  pub fn declare_event(&mut self, name: &str, fields: Vec<Field>) -> Result<&mut Self, ContractError> {
+    if !is_event_name(name) {
+      return Err(ContractError::InvalidName{ name: name.to_string(), expected: "an event name" });
+    }
+    self.events.insert(name.to_string(), fields);
+    Ok(self)
+  }
+
+  /// The fields of the event type `name`, if it is declared.
+  pub fn event_fields(&self, name: &str) -> Option<&[Field]> {
+    self.events.get(name).map(Vec::as_slice)
+  }
+
+  /// The registered keywords, sorted.
+  pub fn names(&self) -> Vec<&str> {
+    let mut names: Vec<&str> = self.handlers.keys().map(String::as_str).collect();
+    names.sort();
+    names
+  }
+}
+
+pub(crate) fn is_event_name(name: &str) -> bool {
+  name.starts_with(|c: char| c.is_uppercase()) && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+impl Default for Registry {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl fmt::Debug for Registry {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_tuple("Registry").field(&self.names()).finish()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::expr::parse_contract_with;
+  use crate::engine::Engine;
+  use crate::expr::{decode, parse_syntax};
+  use crate::formatter::format_source_with;
+  use crate::schema::{field, Type};
+
+  /// Burns tokens from an account, a stand-in for a host's own op.
+  struct Burn;
+
+  const BURN: &[Field] = &[field("from", Type::Account), field("amount", Type::Amount)];
+
+  impl OpHandler for Burn {
+    fn schema(&self) -> &[Field] {
+      BURN
+    }
+
+    fn run(&self, ledger: &mut Ledger, arg: Option<Expr>) -> Result<Vec<Effect>, ContractError> {
+      match arg {
+        Some(Expr::Dict(args)) => match (&args["from"], &args["amount"]) {
+          (Expr::QuotedString(from), Expr::TokenAmount(amount)) => ledger.debit(from, "FIL", *amount).map(|_| vec![]),
+          _ => Err(ContractError::Eval("burn expects a from and an amount".to_string())),
+        },
+        _ => Err(ContractError::Eval("burn expects a dict".to_string())),
+      }
+    }
+  }
+
+  const SOURCE: &str = r#"
+    when Deposit { from: $sender, token: { ticker: "FIL", amount: $amt } }
+    then burn { from: "escrow", amount: $amt / 2 }
+  "#;
+
+  #[test]
+  fn test_unregistered_op() {
+    let err = parse_contract_with(SOURCE, &Registry::new()).unwrap_err();
+    assert!(err.to_string().contains("found `burn`"), "{}", err);
+
+    let mut registry = Registry::empty();
+    registry.register("burn", Burn).unwrap();
+    let err = parse_contract_with("when Pay {} then pay { to: \"a\" }", &registry).unwrap_err();
+    assert!(err.to_string().contains("found `pay`"), "{}", err);
+  }
+
+  #[test]
+  fn test_registered_op() {
+    let mut registry = Registry::new();
+    registry.register("burn", Burn).unwrap();
+    assert_eq!(registry.names(), vec!["burn", "pay", "propose"]);
+
+    let contract = parse_contract_with(SOURCE, &registry).unwrap();
+    let mut engine = Engine::new(contract);
+    let deposit = decode(r#"Deposit { from: "alice", token: { name: "Filecoin", ticker: "FIL", amount: 3 FIL } }"#).unwrap();
+    assert_eq!(engine.handle(&deposit).unwrap(), Some(0));
+    assert_eq!(engine.ledger().balance("escrow", "FIL").to_string(), "1.5 FIL");
+  }
+
+  #[test]
+  fn test_declared_event() {
+    let source = "when Slashed { provider: $p } then burn { from: $p, amount: 1 FIL }";
+    let mut registry = Registry::new();
+    registry.register("burn", Burn).unwrap();
+    let err = parse_contract_with(source, &registry).unwrap_err();
+    assert!(err.to_string().contains("`Slashed`: unknown event"), "{}", err);
+
+    registry.declare_event("Slashed", vec![Field::new("provider", Type::Account)]).unwrap();
+    assert!(parse_contract_with(source, &registry).is_ok());
+    // A contract can't redeclare it.
+    let err = parse_contract_with("event Slashed {}", &registry).unwrap_err();
+    assert!(err.to_string().contains("Event `Slashed` is already defined"), "{}", err);
+  }
+
+  #[test]
+  fn test_invalid_names() {
+    let mut registry = Registry::new();
+    for name in ["close", "", "burn!"] {
+      let err = registry.register(name, Burn).err().unwrap();
+      assert_eq!(err, ContractError::InvalidName{ name: name.to_string(), expected: "an op keyword" });
+    }
+    assert_eq!(registry.register("close", Burn).err().unwrap().to_string(), "`close` can't be an op keyword");
+    let err = registry.declare_event("slashed", vec![]).err().unwrap();
+    assert_eq!(err.to_string(), "`slashed` can't be an event name");
+    assert_eq!(registry.names(), vec!["pay", "propose"]);
+  }
+
+  #[test]
+  fn test_registered_op_comments() {
+    let mut registry = Registry::new();
+    registry.register("burn", Burn).unwrap();
+    let source = "when Pay {} if $a > 0 // positive\nthen burn { from: \"escrow\", amount: $a } // half\n";
+    assert_eq!(format_source_with(source, &registry).unwrap(), "\
+when Pay {}
+if $a > 0 // positive
+then burn {
+  amount: $a,
+  from: \"escrow\"
+} // half
+");
+
+    // `burn` is a keyword wherever it is registered, so it can't name a
+    // field in a guard.
+    let source = "when Pay {} if $a > burn then pay {}";
+    assert!(parse_syntax(source, &Registry::new()).is_ok());
+    let err = parse_syntax(source, &registry).unwrap_err();
+    assert!(err.to_string().contains("found `burn`"), "{}", err);
+  }
+
+  #[test]
+  fn test_registered_op_schema() {
+    let mut registry = Registry::new();
+    registry.register("burn", Burn).unwrap();
+    let err = parse_contract_with("when Pay {} then burn { from: 1, to: \"b\" }", &registry).unwrap_err();
+    assert_eq!(err.to_string(), "\
+Contract does not match the schema:
+  clause 1, `burn`: `from` should be Expr::QuotedString, found Expr::Integer
+  clause 1, `burn`: unknown field `to`
+  clause 1, `burn`: missing `amount` (expected Expr::TokenAmount)");
+  }
+}
@@ -0,0 +1,265 @@
+use std::fmt;
+use std::sync::Arc;
+
+use serde::Deserialize;
+
+use crate::clock::{ChainClock, SimulatedClock};
+use crate::engine::Engine;
+use crate::error::ContractError;
+use crate::expr::{Contract, Expr};
+use crate::formatter::expr;
+use crate::op::TokenAmount;
+
+/// A line of an event log: when the event happened, in seconds since the
+/// Unix epoch, and the event in the JSON form `json` exports, e.g.
+/// `{"time":1700000000,"event":{"type":"Event","value":{"name":"Deposit","args":{...}}}}`.
+#[derive(Deserialize)]
+struct Record {
+  time: u64,
+  event: Expr,
+}
+
+/// What one event of the log, or a timeout that ran before it, did to the
+/// contract.
+#[derive(Debug, PartialEq)]
+pub struct Step {
+  /// The line of the log the event was on, from 1.
+  pub line: usize,
+  pub time: u64,
+  /// The event's type, or `timeout` for a timeout branch, which ran as the
+  /// event came in.
+  pub event: String,
+  /// The clause that fired, counted from zero, if any did.
+  pub clause: Option<usize>,
+  /// The ops that ran, with their evaluated arguments.
+  pub ops: Vec<(String, Option<Expr>)>,
+  /// Why the engine rejected the event, or the timeouts, if it did.
+  pub error: Option<String>,
+  /// Every non-zero balance once the event was handled, as
+  /// `(address, ticker, amount)` sorted by address and then ticker.
+  pub balances: Vec<(String, String, TokenAmount)>,
+}
+
+/// The result of replaying a log: a step per event, in log order, and
+/// whether every clause had fired by the end.
+#[derive(Debug, PartialEq)]
+pub struct Trace {
+  pub steps: Vec<Step>,
+  pub finished: bool,
+}
+
+/// Runs `contract` against a JSONL log of timestamped events, one per line,
+/// in order. Blank lines are skipped. The contract starts at the first
+/// event, and the clock moves on to each event's mainnet epoch before it is
+/// handled, running any timeouts that have passed first, as the host would
+/// have. An event or a timeout the engine rejects is recorded in its step,
+/// as it would have been in production, and the replay carries on; a line
+/// that can't be read, or whose time is earlier than the line before's,
+/// fails the whole replay. The same contract and log always give the same
+/// trace.
+pub fn replay(contract: Contract, log: &str) -> Result<Trace, ContractError> {
+  let records = read(log)?;
+  let chain = ChainClock::mainnet();
+  let clock = SimulatedClock::new(records.first().map_or(0, |(_, _, record)| chain.epoch_at(record.time)));
+  let mut engine = Engine::with_clock(contract, Arc::new(clock.clone()));
+  let mut steps = Vec::new();
+  for (line, name, record) in records {
+    let time = record.time;
+    let step = |engine: &Engine, event: &str, clause, ops, error| Step{
+      line,
+      time,
+      event: event.to_string(),
+      clause,
+      ops,
+      error,
+      balances: engine.ledger()
+        .balances()
+        .into_iter()
+        .map(|(address, ticker, amount)| (address.to_string(), ticker.to_string(), amount))
+        .collect(),
+    };
+
+    clock.set(chain.epoch_at(time));
+    match engine.tick() {
+      Ok(expired) => {
+        for fired in expired {
+          steps.push(step(&engine, "timeout", Some(fired.clause), fired.ops, None));
+        }
+      },
+      Err(err) => steps.push(step(&engine, "timeout", None, vec![], Some(err.to_string()))),
+    }
+
+    let (clause, ops, error) = match engine.fire(&record.event) {
+      Ok(Some(fired)) => (Some(fired.clause), fired.ops, None),
+      Ok(None) => (None, vec![], None),
+      Err(err) => (None, vec![], Some(err.to_string())),
+    };
+    steps.push(step(&engine, &name, clause, ops, error));
+  }
+  Ok(Trace{ steps, finished: engine.is_finished() })
+}
+
+/// The records of a log with their line numbers and event types, checked to
+/// be events in order of time.
+fn read(log: &str) -> Result<Vec<(usize, String, Record)>, ContractError> {
+  let mut records = Vec::new();
+  let mut last = 0;
+  for (i, text) in log.lines().enumerate() {
+    let line = i + 1;
+    if text.trim().is_empty() {
+      continue;
+    }
+    let record: Record = serde_json::from_str(text)
+      .map_err(|err| ContractError::Json(format!("line {}: {}", line, err)))?;
+    let name = match &record.event {
+      Expr::Event{ name, .. } => name.clone(),
+      event => return Err(ContractError::Json(format!(
+        "line {}: expected an Expr::Event, found {}",
+        line,
+        event.variant_name()
+      ))),
+    };
+    if record.time < last {
+      return Err(ContractError::Json(format!(
+        "line {}: time {} is before the previous event's, {}",
+        line, record.time, last
+      )));
+    }
+    last = record.time;
+    records.push((line, name, record));
+  }
+  Ok(records)
+}
+
+/// One block per step: the event and what became of it, the ops that ran
+/// and the balances after, e.g.
+///
+/// ```text
+/// line 1, time 1700000000, Deposit: clause 1 fired
+///   pay { to: "alice", token: { amount: 1 FIL, name: "Filecoin", ticker: "FIL" } }
+///   alice 1 FIL
+/// ```
+impl fmt::Display for Trace {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for step in &self.steps {
+      write!(f, "line {}, time {}, {}: ", step.line, step.time, step.event)?;
+      match (&step.error, step.clause) {
+        (Some(error), _) => writeln!(f, "rejected: {}", error)?,
+        (None, Some(clause)) => writeln!(f, "clause {} fired", clause + 1)?,
+        (None, None) => writeln!(f, "no clause matched")?,
+      }
+      for (op, arg) in &step.ops {
+        match arg {
+          Some(arg) => writeln!(f, "  {} {}", op, expr(arg))?,
+          None => writeln!(f, "  {}", op)?,
+        }
+      }
+      for (address, ticker, amount) in &step.balances {
+        writeln!(f, "  {} {}", address, amount.with_ticker(ticker))?;
+      }
+    }
+    match self.finished {
+      true => writeln!(f, "finished"),
+      false => writeln!(f, "not finished"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::expr::parse_contract;
+
+  const CONTRACT: &str = r#"
+    when Deposit { from: $sender, token: { ticker: "FIL", amount: $amt } } if $amt >= 1 FIL
+    then pay { to: "provider", token: { name: "Filecoin", ticker: "FIL", amount: $amt / 4 } }
+    then pay { to: $sender, token: { name: "Filecoin", ticker: "FIL", amount: $amt / 4 } }
+
+    when Pay { to: "provider" }
+    then pay { to: "provider", token: { name: "Filecoin", ticker: "FIL", amount: 10 FIL } }
+  "#;
+
+  fn deposit(time: u64, from: &str, amount: &str) -> String {
+    format!(
+      r#"{{"time":{},"event":{{"type":"Event","value":{{"name":"Deposit","args":{{"from":{{"type":"QuotedString","value":"{}"}},"token":{{"type":"Dict","value":{{"name":{{"type":"QuotedString","value":"Filecoin"}},"ticker":{{"type":"QuotedString","value":"FIL"}},"amount":{{"type":"TokenAmount","value":"{}"}}}}}}}}}}}}}}"#,
+      time, from, amount
+    )
+  }
+
+  fn log() -> String {
+    [
+      deposit(100, "alice", "1 milliFIL"),
+      String::new(),
+      deposit(160, "alice", "2 FIL"),
+      r#"{"time":200,"event":{"type":"Event","value":{"name":"Pay","args":{"to":{"type":"QuotedString","value":"provider"}}}}}"#.to_string(),
+    ].join("\n")
+  }
+
+  #[test]
+  fn test_replay() {
+    let trace = replay(parse_contract(CONTRACT).unwrap(), &log()).unwrap();
+    assert_eq!(trace.steps.iter().map(|step| step.line).collect::<Vec<_>>(), vec![1, 3, 4]);
+    assert_eq!(trace.steps[0].clause, None);
+    assert_eq!(trace.steps[1].clause, Some(0));
+    assert_eq!(trace.steps[1].ops.len(), 2);
+    assert!(!trace.finished);
+    assert_eq!(trace.to_string(), "\
+line 1, time 100, Deposit: no clause matched
+  escrow 1 milliFIL
+line 3, time 160, Deposit: clause 1 fired
+  pay { to: \"provider\", token: { amount: 500 milliFIL, name: \"Filecoin\", ticker: \"FIL\" } }
+  pay { to: \"alice\", token: { amount: 500 milliFIL, name: \"Filecoin\", ticker: \"FIL\" } }
+  alice 500 milliFIL
+  escrow 1.001 FIL
+  provider 500 milliFIL
+line 4, time 200, Pay: rejected: Insufficient funds: escrow holds 1.001 FIL but 10 FIL is needed
+  alice 500 milliFIL
+  escrow 1.001 FIL
+  provider 500 milliFIL
+not finished
+");
+    // Deterministic: the same log gives the same trace.
+    assert_eq!(replay(parse_contract(CONTRACT).unwrap(), &log()).unwrap(), trace);
+  }
+
+  #[test]
+  fn test_replay_timeout() {
+    let contract = parse_contract(r#"
+    when Deposit { from: $sender, token: { ticker: "FIL", amount: $amt } }
+    then when Notify { name: "delivered" }
+      then pay { to: "provider", token: { name: "Filecoin", ticker: "FIL", amount: $amt } }
+      timeout 10 epochs
+      else pay { to: $sender, token: { name: "Filecoin", ticker: "FIL", amount: $amt } }
+    "#).unwrap();
+    // Ten mainnet epochs are five minutes.
+    let start = 1700000000;
+    let delivered = format!(
+      r#"{{"time":{},"event":{{"type":"Event","value":{{"name":"Notify","args":{{"by":{{"type":"QuotedString","value":"provider"}},"name":{{"type":"QuotedString","value":"delivered"}}}}}}}}}}"#,
+      start + 300
+    );
+    let log = [deposit(start, "alice", "1 FIL"), delivered].join("\n");
+    let trace = replay(contract, &log).unwrap();
+    assert_eq!(trace.to_string(), "\
+line 1, time 1700000000, Deposit: clause 1 fired
+  escrow 1 FIL
+line 2, time 1700000300, timeout: clause 2 fired
+  pay { to: \"alice\", token: { amount: 1 FIL, name: \"Filecoin\", ticker: \"FIL\" } }
+  alice 1 FIL
+line 2, time 1700000300, Notify: no clause matched
+  alice 1 FIL
+finished
+");
+  }
+
+  #[test]
+  fn test_replay_errors() {
+    let error = |log: &str| replay(parse_contract(CONTRACT).unwrap(), log).unwrap_err().to_string();
+    assert!(error("{\"time\":1}").starts_with("JSON error: line 1: missing field `event`"), "{}", error("{\"time\":1}"));
+    assert_eq!(
+      error(r#"{"time":1,"event":{"type":"Integer","value":1}}"#),
+      "JSON error: line 1: expected an Expr::Event, found Expr::Integer"
+    );
+    let log = [deposit(10, "a", "1"), deposit(5, "a", "1")].join("\n");
+    assert_eq!(error(&log), "JSON error: line 2: time 5 is before the previous event's, 10");
+  }
+}
@@ -0,0 +1,406 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+
+use crate::address::Address;
+use crate::error::{ContractError, FieldError, SchemaError};
+use crate::expr::{Contract, Expr};
+use crate::registry::Registry;
+
+/// The type a schema expects of a field, named in errors as the `Expr`
+/// variant that satisfies it. Declarations in a contract spell them as
+/// the serialized names, e.g. `address` or `{ amount: amount }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Type {
+  String,
+  /// A string naming an account, or an address literal.
+  #[serde(rename = "address")]
+  Account,
+  #[serde(rename = "int")]
+  Integer,
+  /// A FIL amount with its unit, or an integer counted in the token's base
+  /// unit.
+  Amount,
+  Bool,
+  /// A string holding a CID.
+  Cid,
+  Dict(Cow<'static, [Field]>),
+}
+
+impl Type {
+  /// The type a declaration names, other than a dict.
+  pub(crate) fn named(name: &str) -> Option<Type> {
+    match name {
+      "string" => Some(Type::String),
+      "address" => Some(Type::Account),
+      "int" => Some(Type::Integer),
+      "amount" => Some(Type::Amount),
+      "bool" => Some(Type::Bool),
+      "cid" => Some(Type::Cid),
+      _ => None,
+    }
+  }
+
+  fn expected(&self) -> &'static str {
+    match self {
+      Type::String | Type::Account | Type::Cid => "Expr::QuotedString",
+      Type::Integer => "Expr::Integer",
+      Type::Amount => "Expr::TokenAmount",
+      Type::Bool => "Expr::Bool",
+      Type::Dict(_) => "Expr::Dict",
+    }
+  }
+
+  fn accepts(&self, value: &Expr) -> bool {
+    matches!(
+      (self, value),
+      (Type::String | Type::Cid, Expr::QuotedString(_))
+        | (Type::Account, Expr::QuotedString(_) | Expr::Address(_))
+        | (Type::Integer, Expr::Integer(_))
+        | (Type::Amount, Expr::TokenAmount(_) | Expr::Integer(_))
+        | (Type::Bool, Expr::Bool(_))
+        | (Type::Dict(_), Expr::Dict(_))
+    )
+  }
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Type::String => write!(f, "string"),
+      Type::Account => write!(f, "address"),
+      Type::Integer => write!(f, "int"),
+      Type::Amount => write!(f, "amount"),
+      Type::Bool => write!(f, "bool"),
+      Type::Cid => write!(f, "cid"),
+      Type::Dict(fields) => write_fields(f, fields),
+    }
+  }
+}
+
+/// `{ name: type, ... }` in declaration order.
+fn write_fields(f: &mut fmt::Formatter, fields: &[Field]) -> fmt::Result {
+  if fields.is_empty() {
+    return write!(f, "{{}}");
+  }
+  write!(f, "{{ ")?;
+  for (i, field) in fields.iter().enumerate() {
+    if i > 0 {
+      write!(f, ", ")?;
+    }
+    write!(f, "{}: {}", field.name, field.ty)?;
+  }
+  write!(f, " }}")
+}
+
+/// A field of an event or op argument, and the type of its value.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Field {
+  pub(crate) name: Cow<'static, str>,
+  #[serde(rename = "type")]
+  pub(crate) ty: Type,
+}
+
+pub const fn field(name: &'static str, ty: Type) -> Field {
+  Field{ name: Cow::Borrowed(name), ty }
+}
+
+impl Field {
+  pub fn new(name: &str, ty: Type) -> Self {
+    Field{ name: Cow::Owned(name.to_string()), ty }
+  }
+}
+
+/// An event type declared by a contract, e.g.
+/// `event BountyClaimed { claimant: address, piece: cid }`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventType {
+  pub name: String,
+  pub fields: Vec<Field>,
+}
+
+/// The declaration as written in a contract.
+impl fmt::Display for EventType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "event {} ", self.name)?;
+    write_fields(f, &self.fields)
+  }
+}
+
+pub(crate) const TOKEN: &[Field] = &[
+  field("name", Type::String),
+  field("ticker", Type::String),
+  field("amount", Type::Amount),
+];
+
+const DEAL_REQUEST: &[Field] = &[
+  field("piece_cid", Type::String),
+  field("piece_size", Type::Integer),
+  field("verified_deal", Type::Bool),
+  field("label", Type::String),
+  field("start_epoch", Type::Integer),
+  field("end_epoch", Type::Integer),
+  field("storage_price_per_epoch", Type::Amount),
+  field("provider_collateral", Type::Amount),
+  field("extra_params_version", Type::Integer),
+];
+
+pub(crate) const DEPOSIT: &[Field] = &[
+  field("from", Type::Account),
+  field("token", Type::Dict(Cow::Borrowed(TOKEN))),
+];
+
+/// A `Pay` event, and the argument of `pay`.
+pub(crate) const PAY: &[Field] = &[
+  field("to", Type::Account),
+  field("token", Type::Dict(Cow::Borrowed(TOKEN))),
+];
+
+pub(crate) const PROPOSE: &[Field] = &[
+  field("deal_request", Type::Dict(Cow::Borrowed(DEAL_REQUEST))),
+];
+
+/// A party's choice: who made it, what about, e.g. `"approve"`, and the
+/// number they chose. A `when` pattern may also give the `bounds` the
+/// number has to be within, as inclusive `(low, high)` pairs.
+pub(crate) const CHOICE: &[Field] = &[
+  field("by", Type::Account),
+  field("name", Type::String),
+  field("value", Type::Integer),
+];
+
+/// A notification from outside the contract, such as an oracle's, that
+/// something named `name` has happened.
+pub(crate) const NOTIFY: &[Field] = &[
+  field("by", Type::Account),
+  field("name", Type::String),
+];
+
+pub(crate) const DEAL_EVENT: &[Field] = &[
+  field("piece_cid", Type::String),
+  field("deal_id", Type::Integer),
+];
+
+/// Checks every `when` pattern and op argument of a contract against its
+/// schema, an event's being the one the contract declares or `registry`
+/// has, an op's the one its handler declares: no unknown keys, values of
+/// the right type and, in op arguments, every field present. Values only
+/// known once the clause fires, such as `$vars` and arithmetic, are taken
+/// on trust. All errors are reported together.
+pub(crate) fn check_schema(contract: &Contract, registry: &Registry) -> Result<(), ContractError> {
+  let event_fields = |name: &str| match contract.events.iter().find(|event| event.name == name) {
+    Some(event) => Some(&event.fields[..]),
+    None => registry.event_fields(name),
+  };
+
+  let mut errors = Vec::new();
+  for (clause, (event_op, ops)) in contract.clauses().into_iter().enumerate() {
+    let mut report = |target: &str, found: Vec<FieldError>| {
+      errors.extend(found.into_iter().map(|error| SchemaError{ clause, target: target.to_string(), error }));
+    };
+
+    match &event_op.event {
+      Expr::Event{ name, args } if name == "Choice" && args.contains_key("bounds") => {
+        let mut args = args.clone();
+        let bounds = args.remove("bounds").unwrap_or(Expr::Array(vec![]));
+        report(name, check_dict(event_fields(name).unwrap_or(CHOICE), &args, false));
+        if let Err(reason) = choice_bounds(&bounds) {
+          report(name, vec![FieldError::Invalid{ field: "bounds".to_string(), reason }]);
+        }
+      },
+      Expr::Event{ name, args } => match event_fields(name) {
+        Some(fields) => report(name, check_dict(fields, args, false)),
+        None => report(name, vec![FieldError::Unknown{ field: String::new() }]),
+      },
+      event => report(&event_op.name, vec![wrong_type("", &Type::Dict(Cow::Borrowed(&[])), event)]),
+    }
+
+    for op in ops.iter().chain(event_op.timeout_ops()) {
+      let found = match &op.arg {
+        Some(Expr::Dict(args)) => check_dict(op.handler.schema(), args, true),
+        Some(arg) => vec![wrong_type("", &Type::Dict(Cow::Borrowed(&[])), arg)],
+        None => vec![FieldError::Missing{ field: String::new(), expected: "Expr::Dict" }],
+      };
+      report(&op.name, found);
+    }
+  }
+
+  match errors.is_empty() {
+    true => Ok(()),
+    false => Err(ContractError::Schema(errors)),
+  }
+}
+
+/// The `bounds` of a `Choice` pattern, e.g. `[(0, 1), (5, 10)]`: at least
+/// one pair of integers, neither the wrong way round.
+pub(crate) fn choice_bounds(bounds: &Expr) -> Result<Vec<(usize, usize)>, String> {
+  let pairs = match bounds {
+    Expr::Array(pairs) if !pairs.is_empty() => pairs,
+    Expr::Array(_) => return Err("there has to be at least one bound".to_string()),
+    bounds => return Err(format!("expected a list of bounds such as `[(0, 1)]`, found {}", bounds.variant_name())),
+  };
+  pairs
+    .iter()
+    .map(|pair| match pair {
+      Expr::Pair(low, high) => match (&**low, &**high) {
+        (Expr::Integer(low), Expr::Integer(high)) if low <= high => Ok((*low, *high)),
+        (Expr::Integer(low), Expr::Integer(high)) => Err(format!("`({}, {})` is the wrong way round", low, high)),
+        _ => Err("a bound is a pair of integers, such as `(0, 1)`".to_string()),
+      },
+      pair => Err(format!("expected a bound such as `(0, 1)`, found {}", pair.variant_name())),
+    })
+    .collect()
+}
+
+/// The errors in an incoming event of a declared type: it must have every
+/// field, each of its declared type.
+pub(crate) fn check_event(event: &EventType, args: &HashMap<String, Expr>) -> Result<(), ContractError> {
+  match check_dict(&event.fields, args, true) {
+    errors if errors.is_empty() => Ok(()),
+    errors => Err(ContractError::InvalidArguments{ target: event.name.clone(), errors }),
+  }
+}
+
+/// Unknown keys in key order, then missing ones in schema order.
+fn check_dict(fields: &[Field], dict: &HashMap<String, Expr>, required: bool) -> Vec<FieldError> {
+  let mut errors = Vec::new();
+  let mut keys: Vec<&String> = dict.keys().collect();
+  keys.sort();
+  for key in keys {
+    match fields.iter().find(|field| field.name == key.as_str()) {
+      Some(field) => errors.extend(check_value(key, &field.ty, &dict[key], required)),
+      None => errors.push(FieldError::Unknown{ field: key.to_string() }),
+    }
+  }
+  if required {
+    for field in fields.iter().filter(|field| !dict.contains_key(&field.name[..])) {
+      errors.push(FieldError::Missing{ field: field.name.to_string(), expected: field.ty.expected() });
+    }
+  }
+  errors
+}
+
+fn check_value(key: &str, ty: &Type, value: &Expr, required: bool) -> Vec<FieldError> {
+  match (ty, value) {
+    // A misspelled address reads as a name, which can't be an account.
+    (Type::Account, Expr::Id(name)) if name.starts_with(['f', 't']) && name[1..].starts_with(|c| ('0'..='4').contains(&c)) => {
+      match name.parse::<Address>() {
+        Ok(_) => vec![],
+        Err(reason) => vec![FieldError::Invalid{ field: key.to_string(), reason }],
+      }
+    },
+    (_, Expr::Var(_) | Expr::Id(_) | Expr::Field(..) | Expr::BinOp{ .. } | Expr::Not(_)) => vec![],
+    (Type::Dict(fields), Expr::Dict(dict)) => check_dict(fields, dict, required)
+      .into_iter()
+      .map(|error| if key.is_empty() { error } else { error.nest(key) })
+      .collect(),
+    (Type::Cid, Expr::QuotedString(s)) => match Cid::try_from(s.as_str()) {
+      Ok(_) => vec![],
+      Err(err) => vec![FieldError::Invalid{ field: key.to_string(), reason: format!("`{}` is not a CID: {}", s, err) }],
+    },
+    (ty, value) if ty.accepts(value) => vec![],
+    (ty, value) => vec![wrong_type(key, ty, value)],
+  }
+}
+
+fn wrong_type(key: &str, ty: &Type, found: &Expr) -> FieldError {
+  FieldError::WrongType{ field: key.to_string(), expected: ty.expected(), found: found.variant_name() }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::expr::parse_contract;
+
+  fn schema_errors(source: &str) -> Vec<String> {
+    match parse_contract(source) {
+      Err(ContractError::Schema(errors)) => errors.iter().map(|e| e.to_string()).collect(),
+      result => panic!("expected schema errors, got {:?}", result),
+    }
+  }
+
+  #[test]
+  fn test_check_schema() {
+    let errors = schema_errors(r#"
+    when Deposit { from: 12, token: { amount: "lots", color: "red" } }
+    then pay { to: "a", token: { name: "world", ticker: "WRLD" }, memo: "hi" }
+
+    when DealPublished { piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy" }
+    then propose { deal_request: { piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy", piece_size: 2048, verified_deal: false, label: "x", start_epoch: 10, storage_price_per_epoch: 1 FIL, provider_collateral: 0, extra_params_version: 1 } }
+    "#);
+    assert_eq!(errors, vec![
+      "clause 1, `Deposit`: `from` should be Expr::QuotedString, found Expr::Integer",
+      "clause 1, `Deposit`: `token.amount` should be Expr::TokenAmount, found Expr::QuotedString",
+      "clause 1, `Deposit`: unknown field `token.color`",
+      "clause 1, `pay`: unknown field `memo`",
+      "clause 1, `pay`: missing `token.amount` (expected Expr::TokenAmount)",
+      "clause 2, `propose`: missing `deal_request.end_epoch` (expected Expr::Integer)",
+    ]);
+  }
+
+  #[test]
+  fn test_check_choice_bounds() {
+    let errors = schema_errors(r#"
+    when Choice { name: "a", bounds: [(3, 1)], color: 1 }
+    when Choice { bounds: [] }
+    when Choice { bounds: 1 }
+    when Choice { bounds: [(0, "b")] }
+    when Choice { bounds: [(0, 1)], value: "b" }
+    "#);
+    assert_eq!(errors, vec![
+      "clause 1, `Choice`: unknown field `color`",
+      "clause 1, `Choice`: `bounds` is invalid: `(3, 1)` is the wrong way round",
+      "clause 2, `Choice`: `bounds` is invalid: there has to be at least one bound",
+      "clause 3, `Choice`: `bounds` is invalid: expected a list of bounds such as `[(0, 1)]`, found Expr::Integer",
+      "clause 4, `Choice`: `bounds` is invalid: a bound is a pair of integers, such as `(0, 1)`",
+      "clause 5, `Choice`: `value` should be Expr::Integer, found Expr::QuotedString",
+    ]);
+    assert!(parse_contract(r#"when Choice { by: $who, bounds: [(0, 1), (5, 9)] } when Notify { name: "done" }"#).is_ok());
+  }
+
+  #[test]
+  fn test_check_schema_trusts_runtime_values() {
+    let contract = parse_contract(r#"
+    when Deposit { from: $sender, token: { amount: $amt } }
+    then pay { to: $sender, token: { name: "world", ticker: "WRLD", amount: $amt - 1 } }
+    when Pay { to: f01234 }
+    "#);
+    assert!(contract.is_ok(), "{:?}", contract);
+  }
+
+  #[test]
+  fn test_check_declared_events() {
+    let errors = schema_errors(r#"
+    event BountyClaimed { claimant: address, piece: cid }
+    when BountyClaimed { claimant: 1, piece: "bafy", memo: "x" }
+    when Unheard {}
+    "#);
+    assert_eq!(errors, vec![
+      "clause 1, `BountyClaimed`: `claimant` should be Expr::QuotedString, found Expr::Integer",
+      "clause 1, `BountyClaimed`: unknown field `memo`",
+      "clause 1, `BountyClaimed`: `piece` is invalid: `bafy` is not a CID: Failed to parse multihash",
+      "clause 2, `Unheard`: unknown event",
+    ]);
+  }
+
+  #[test]
+  fn test_type_display() {
+    let ty = Type::Dict(Cow::Owned(vec![field("piece", Type::Cid), field("token", Type::Dict(Cow::Borrowed(TOKEN)))]));
+    assert_eq!(ty.to_string(), "{ piece: cid, token: { name: string, ticker: string, amount: amount } }");
+    assert_eq!(Type::Dict(Cow::Borrowed(&[])).to_string(), "{}");
+  }
+
+  #[test]
+  fn test_schema_error_display() {
+    let err = parse_contract("when Pay { from: \"a\" } then pay { to: true }").unwrap_err();
+    assert_eq!(err.to_string(), "\
+Contract does not match the schema:
+  clause 1, `Pay`: unknown field `from`
+  clause 1, `pay`: `to` should be Expr::QuotedString, found Expr::Bool
+  clause 1, `pay`: missing `token` (expected Expr::Dict)");
+  }
+}
@@ -0,0 +1,933 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+
+use crate::address::{Address, Payload};
+use crate::engine::Engine;
+use crate::error::ContractError;
+use crate::eval::ratio;
+use crate::expr::{BinOp, Contract, Expr};
+use crate::formatter::expr as source;
+use crate::op::{parse_piece_cid, Effect, TokenAmount};
+use crate::registry::Registry;
+use crate::schema::{choice_bounds, Field, Type};
+
+const INDENT: &str = "    ";
+
+const HEADER: &str = "// SPDX-License-Identifier: MIT
+// Generated from a Monet contract: edit the contract rather than this file.
+pragma solidity ^0.8.17;
+";
+
+/// The structs of the FEVM reference DealClient, field for field, so that
+/// `makeDealProposal` has its selector.
+const DEAL_CLIENT: &str = "
+struct ExtraParamsV1 {
+    string location_ref;
+    uint64 car_size;
+    bool skip_ipni_announce;
+    bool remove_unsealed_copy;
+}
+
+/// A deal proposal as the FEVM DealClient takes it.
+struct DealRequest {
+    bytes piece_cid;
+    uint64 piece_size;
+    bool verified_deal;
+    string label;
+    int64 start_epoch;
+    int64 end_epoch;
+    uint256 storage_price_per_epoch;
+    uint256 provider_collateral;
+    uint256 client_collateral;
+    uint64 extra_params_version;
+    ExtraParamsV1 extra_params;
+}
+
+interface IDealClient {
+    function makeDealProposal(DealRequest calldata deal) external returns (bytes32);
+}
+";
+
+/// Events the engine follows deals by. The generated contract doesn't track
+/// deals, so clauses waiting for them aren't compiled.
+const DEAL_EVENTS: [&str; 4] = ["DealProposalCreated", "DealPublished", "DealActivated", "DealTerminated"];
+
+/// Names Solidity reserves, which event fields can't be called as they are.
+const RESERVED: &[&str] = &[
+  "address", "after", "bool", "bytes", "calldata", "constant", "contract", "delete", "do", "else",
+  "emit", "enum", "event", "external", "false", "for", "function", "if", "immutable", "import",
+  "interface", "internal", "is", "library", "mapping", "memory", "modifier", "new", "payable",
+  "private", "public", "pure", "return", "returns", "storage", "string", "struct", "this", "true",
+  "type", "uint", "uint256", "using", "var", "view", "while",
+];
+
+fn unsupported(message: String) -> ContractError {
+  ContractError::Solidity(message)
+}
+
+/// The Solidity type of an event field or an expression.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Sol {
+  Uint,
+  Bool,
+  String,
+  Address,
+  Bytes,
+}
+
+impl Sol {
+  fn of(ty: &Type) -> Sol {
+    match ty {
+      Type::String => Sol::String,
+      Type::Account => Sol::Address,
+      Type::Integer | Type::Amount => Sol::Uint,
+      Type::Bool => Sol::Bool,
+      Type::Cid => Sol::Bytes,
+      Type::Dict(_) => unreachable!("dicts are flattened into their fields"),
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      Sol::Uint => "uint256",
+      Sol::Bool => "bool",
+      Sol::String => "string",
+      Sol::Address => "address",
+      Sol::Bytes => "bytes",
+    }
+  }
+
+  fn param(self) -> String {
+    match self {
+      Sol::String | Sol::Bytes => format!("{} calldata", self.name()),
+      _ => self.name().to_string(),
+    }
+  }
+}
+
+/// A field of the event a function handles, by its key path, and the
+/// Solidity expression that holds it.
+struct EventField {
+  path: Vec<String>,
+  code: String,
+  sol: Sol,
+}
+
+/// The fields of an incoming event. A `Deposit` is a call to the payable
+/// `deposit()`, so its sender and value stand in for its fields, and a
+/// `Choice` is the chooser's call to `choose(name, value)`; any other event
+/// is relayed by the owner with its fields as arguments, dicts flattened
+/// into names like `token_amount`.
+fn event_fields(name: &str, schema: &[Field]) -> Vec<EventField> {
+  let field = |path: &[&str], code: &str, sol| EventField{
+    path: path.iter().map(|key| key.to_string()).collect(),
+    code: code.to_string(),
+    sol,
+  };
+  match name {
+    "Deposit" => return vec![
+      field(&["from"], "msg.sender", Sol::Address),
+      field(&["token", "name"], "\"Filecoin\"", Sol::String),
+      field(&["token", "ticker"], "\"FIL\"", Sol::String),
+      field(&["token", "amount"], "msg.value", Sol::Uint),
+    ],
+    "Choice" => return vec![
+      field(&["by"], "msg.sender", Sol::Address),
+      field(&["name"], "name", Sol::String),
+      field(&["value"], "value", Sol::Uint),
+    ],
+    _ => {},
+  }
+  let mut fields = Vec::new();
+  flatten(schema, &[], &mut fields);
+  fields
+}
+
+fn flatten(schema: &[Field], prefix: &[String], fields: &mut Vec<EventField>) {
+  for field in schema {
+    let path: Vec<String> = prefix.iter().cloned().chain([field.name.to_string()]).collect();
+    match &field.ty {
+      Type::Dict(nested) => flatten(nested, &path, fields),
+      ty => fields.push(EventField{ code: identifier(&path.join("_")), path, sol: Sol::of(ty) }),
+    }
+  }
+}
+
+fn identifier(name: &str) -> String {
+  match RESERVED.contains(&name) {
+    true => format!("{}_", name),
+    false => name.to_string(),
+  }
+}
+
+/// The EVM form of an address: an Ethereum address or the f410 address
+/// wrapping one as it is, and an ID address as FEVM masks it, `0xff`
+/// followed by the ID.
+fn evm_address(address: &Address) -> Result<String, ContractError> {
+  if let Some(ethereum) = address.to_ethereum() {
+    return Ok(ethereum.to_string());
+  }
+  match address {
+    Address::Filecoin{ payload: Payload::Id(id), .. } => {
+      let mut bytes = [0; 20];
+      bytes[0] = 0xff;
+      bytes[12..].copy_from_slice(&id.to_be_bytes());
+      Ok(Address::Ethereum(bytes).to_string())
+    },
+    address => Err(unsupported(format!("`{}` has no EVM address", address))),
+  }
+}
+
+fn string_literal(s: &str) -> String {
+  let mut literal = String::new();
+  for c in s.chars() {
+    match c {
+      '"' => literal.push_str("\\\""),
+      '\\' => literal.push_str("\\\\"),
+      '\n' => literal.push_str("\\n"),
+      '\t' => literal.push_str("\\t"),
+      c if (c as u32) < 0x20 => literal.push_str(&format!("\\x{:02x}", c as u32)),
+      c => literal.push(c),
+    }
+  }
+  match s.is_ascii() {
+    true => format!("\"{}\"", literal),
+    false => format!("unicode\"{}\"", literal),
+  }
+}
+
+fn hex_literal(bytes: &[u8]) -> String {
+  format!("hex\"{}\"", bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+fn cid_literal(s: &str) -> Result<String, ContractError> {
+  parse_piece_cid(s).map(|cid| hex_literal(&cid.to_bytes())).map_err(unsupported)
+}
+
+fn equals(lhs: &str, rhs: &str, sol: Sol) -> String {
+  match sol {
+    Sol::String => format!("keccak256(bytes({})) == keccak256(bytes({}))", lhs, rhs),
+    Sol::Bytes => format!("keccak256({}) == keccak256({})", lhs, rhs),
+    _ => format!("{} == {}", lhs, rhs),
+  }
+}
+
+/// `key` of a dict, or the field `key` of whatever else `expr` is.
+fn member(expr: &Expr, key: &str) -> Expr {
+  match expr {
+    Expr::Dict(dict) => dict.get(key).cloned().unwrap_or(Expr::Field(Box::new(expr.clone()), key.to_string())),
+    expr => Expr::Field(Box::new(expr.clone()), key.to_string()),
+  }
+}
+
+/// A clause being compiled, with what its pattern bound so far.
+struct Clause<'a> {
+  fields: &'a [EventField],
+  /// Pattern variables, by the key path of the field each was bound to.
+  vars: HashMap<String, Vec<String>>,
+}
+
+impl<'a> Clause<'a> {
+  fn field(&self, path: &[String]) -> Result<&'a EventField, ContractError> {
+    self.fields.iter().find(|field| field.path == path).ok_or_else(|| {
+      match self.fields.iter().any(|field| field.path.starts_with(path)) {
+        true => unsupported(format!("`{}` is a dict; only its fields can be used", path.join("."))),
+        false => unsupported(format!("the event has no field `{}`", path.join("."))),
+      }
+    })
+  }
+
+  /// The key path an event field or a variable bound to one is at.
+  fn path(&self, expr: &Expr) -> Option<Vec<String>> {
+    match expr {
+      Expr::Id(name) => Some(vec![name.clone()]),
+      Expr::Var(name) => self.vars.get(name).cloned(),
+      Expr::Field(base, field) => self.path(base).map(|mut path| {
+        path.push(field.clone());
+        path
+      }),
+      _ => None,
+    }
+  }
+
+  /// Adds a condition for every literal of the pattern and binds its
+  /// variables. A variable that appears twice must be equal both times.
+  fn pattern(&mut self, args: &HashMap<String, Expr>, prefix: &[String], conditions: &mut Vec<String>) -> Result<(), ContractError> {
+    let mut keys: Vec<&String> = args.keys().collect();
+    keys.sort();
+    for key in keys {
+      let path: Vec<String> = prefix.iter().chain([key]).cloned().collect();
+      match &args[key] {
+        Expr::Dict(dict) => self.pattern(dict, &path, conditions)?,
+        Expr::Var(name) => match self.vars.get(name) {
+          Some(bound) => {
+            let (first, field) = (self.field(bound)?, self.field(&path)?);
+            if first.sol != field.sol {
+              return Err(unsupported(format!("`${}` is bound to a {} and a {}", name, first.sol.name(), field.sol.name())));
+            }
+            conditions.push(equals(&first.code, &field.code, field.sol));
+          },
+          None => {
+            self.vars.insert(name.clone(), path);
+          },
+        },
+        value => {
+          let field = self.field(&path)?;
+          conditions.push(equals(&field.code, &self.typed(value, field.sol)?, field.sol));
+        },
+      }
+    }
+    Ok(())
+  }
+
+  fn typed(&self, expr: &Expr, sol: Sol) -> Result<String, ContractError> {
+    match self.expr(expr)? {
+      (code, found) if found == sol => Ok(code),
+      (_, found) => Err(unsupported(format!("`{}` is a {}, expected a {}", source(expr), found.name(), sol.name()))),
+    }
+  }
+
+  fn expr(&self, expr: &Expr) -> Result<(String, Sol), ContractError> {
+    if let Some(path) = self.path(expr) {
+      let field = self.field(&path)?;
+      return Ok((field.code.clone(), field.sol));
+    }
+    match expr {
+      Expr::Integer(n) => Ok((n.to_string(), Sol::Uint)),
+      Expr::TokenAmount(amount) => Ok((amount.atto().to_string(), Sol::Uint)),
+      Expr::Bool(b) => Ok((b.to_string(), Sol::Bool)),
+      Expr::QuotedString(s) => Ok((string_literal(s), Sol::String)),
+      Expr::Address(address) => Ok((evm_address(address)?, Sol::Address)),
+      Expr::Not(inner) => Ok((format!("!{}", self.typed(inner, Sol::Bool)?), Sol::Bool)),
+      Expr::BinOp{ op, lhs, rhs } => self.binop(*op, lhs, rhs),
+      Expr::Decimal(_) => Err(unsupported(format!(
+        "`{}`: decimals can only scale a number, as in `$amount * 0.9`",
+        source(expr)
+      ))),
+      expr => Err(unsupported(format!("`{}` has no Solidity equivalent", source(expr)))),
+    }
+  }
+
+  /// Arithmetic is on `uint256`, reverting where the engine would fail.
+  /// Scaling by a decimal multiplies before it divides, rounding down like
+  /// the engine does.
+  fn binop(&self, op: BinOp, lhs: &Expr, rhs: &Expr) -> Result<(String, Sol), ContractError> {
+    let ratio = |e: &Expr| ratio(e).map_err(unsupported);
+    match (op, lhs, rhs) {
+      (BinOp::Mul, e, d @ Expr::Decimal(_)) | (BinOp::Mul, d @ Expr::Decimal(_), e) => {
+        let (n, d) = ratio(d)?;
+        Ok((format!("({} * {} / {})", self.typed(e, Sol::Uint)?, n, d), Sol::Uint))
+      },
+      (BinOp::Div, e, d @ Expr::Decimal(_)) => match ratio(d)? {
+        (0, _) => Err(unsupported(format!("`{}` divides by zero", source(d)))),
+        (n, d) => Ok((format!("({} * {} / {})", self.typed(e, Sol::Uint)?, d, n), Sol::Uint)),
+      },
+      (BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div, _, _) => Ok((
+        format!("({} {} {})", self.typed(lhs, Sol::Uint)?, op, self.typed(rhs, Sol::Uint)?),
+        Sol::Uint,
+      )),
+      (BinOp::And | BinOp::Or, _, _) => Ok((
+        format!("({} {} {})", self.typed(lhs, Sol::Bool)?, op, self.typed(rhs, Sol::Bool)?),
+        Sol::Bool,
+      )),
+      (BinOp::Eq | BinOp::Ne, _, _) => {
+        let ((l, lt), (r, rt)) = (self.expr(lhs)?, self.expr(rhs)?);
+        if lt != rt {
+          return Err(unsupported(format!("`{}` compares a {} with a {}", source(&Expr::BinOp{
+            op,
+            lhs: Box::new(lhs.clone()),
+            rhs: Box::new(rhs.clone()),
+          }), lt.name(), rt.name())));
+        }
+        let eq = equals(&l, &r, lt);
+        match op {
+          BinOp::Eq => Ok((format!("({})", eq), Sol::Bool)),
+          _ => Ok((format!("!({})", eq), Sol::Bool)),
+        }
+      },
+      _ => Ok((
+        format!("({} {} {})", self.typed(lhs, Sol::Uint)?, op, self.typed(rhs, Sol::Uint)?),
+        Sol::Bool,
+      )),
+    }
+  }
+
+  /// The statements of an op, given its argument.
+  fn op(&self, name: &str, arg: &Expr) -> Result<Vec<String>, ContractError> {
+    match name {
+      "pay" => {
+        let to = self.typed(&member(arg, "to"), Sol::Address)?;
+        let token = member(arg, "token");
+        let ticker = self.typed(&member(&token, "ticker"), Sol::String)?;
+        let amount = self.typed(&member(&token, "amount"), Sol::Uint)?;
+        let mut statements = Vec::new();
+        if ticker != "\"FIL\"" {
+          statements.push(format!("require({}, \"only FIL can be paid\");", equals(&ticker, "\"FIL\"", Sol::String)));
+        }
+        statements.push(format!("sendValue({}, {});", to, amount));
+        Ok(statements)
+      },
+      "propose" => {
+        let deal = member(arg, "deal_request");
+        let field = |key: &str, sol| self.typed(&member(&deal, key), sol);
+        let piece_cid = match member(&deal, "piece_cid") {
+          Expr::QuotedString(s) => cid_literal(&s)?,
+          e => self.typed(&e, Sol::Bytes)?,
+        };
+        let fields = [
+          ("piece_cid", piece_cid),
+          ("piece_size", format!("uint64({})", field("piece_size", Sol::Uint)?)),
+          ("verified_deal", field("verified_deal", Sol::Bool)?),
+          ("label", field("label", Sol::String)?),
+          ("start_epoch", format!("int64(int256({}))", field("start_epoch", Sol::Uint)?)),
+          ("end_epoch", format!("int64(int256({}))", field("end_epoch", Sol::Uint)?)),
+          ("storage_price_per_epoch", field("storage_price_per_epoch", Sol::Uint)?),
+          ("provider_collateral", field("provider_collateral", Sol::Uint)?),
+          // Contracts can't set these, so the client puts nothing up and the
+          // provider gets no extra parameters.
+          ("client_collateral", "0".to_string()),
+          ("extra_params_version", format!("uint64({})", field("extra_params_version", Sol::Uint)?)),
+          ("extra_params", "ExtraParamsV1({location_ref: \"\", car_size: 0, skip_ipni_announce: false, remove_unsealed_copy: false})".to_string()),
+        ];
+        let mut call = "dealClient.makeDealProposal(DealRequest({\n".to_string();
+        for (i, (key, value)) in fields.iter().enumerate() {
+          let comma = if i + 1 < fields.len() { "," } else { "" };
+          call.push_str(&format!("{}{}: {}{}\n", INDENT, key, value, comma));
+        }
+        call.push_str("}));");
+        Ok(vec![call])
+      },
+      name => Err(unsupported(format!("the op `{}` has no Solidity equivalent", name))),
+    }
+  }
+}
+
+/// The schema of the event `name`, from the contract's declarations or
+/// `registry`.
+fn event_schema<'a>(contract: &'a Contract, registry: &'a Registry, name: &str) -> Result<&'a [Field], ContractError> {
+  if DEAL_EVENTS.contains(&name) {
+    return Err(unsupported(format!("`{}` clauses need the engine's deal tracking", name)));
+  }
+  match contract.events.iter().find(|event| event.name == name) {
+    Some(event) => Ok(&event.fields),
+    None => registry.event_fields(name).ok_or_else(|| unsupported(format!("unknown event `{}`", name))),
+  }
+}
+
+/// The event a clause waits for, by name.
+fn event_name(event: &Expr) -> Result<&str, ContractError> {
+  match event {
+    Expr::Event{ name, .. } => Ok(name),
+    event => Err(unsupported(format!("`{}` is not an event", source(event)))),
+  }
+}
+
+/// The function handling the event `name`: the clauses waiting for it are
+/// tried in source order, as the engine does, and the first pending one
+/// whose pattern matches and whose guard holds fires.
+fn function(contract: &Contract, registry: &Registry, name: &str, clauses: &[usize]) -> Result<String, ContractError> {
+  let fields = event_fields(name, event_schema(contract, registry, name)?);
+  let mut out = match name {
+    "Deposit" => format!("{}function deposit() external payable {{\n", INDENT),
+    "Choice" => format!("{}function choose(string calldata name, uint256 value) external {{\n", INDENT),
+    _ => format!(
+      "{}function on{}({}) external onlyOwner {{\n",
+      INDENT,
+      name,
+      fields.iter().map(|field| format!("{} {}", field.sol.param(), field.code)).collect::<Vec<_>>().join(", ")
+    ),
+  };
+  let body = INDENT.repeat(2);
+  let block = INDENT.repeat(3);
+  for &i in clauses {
+    let (event_op, ops) = &contract.stmts[i];
+    let mut clause = Clause{ fields: &fields, vars: HashMap::new() };
+    let mut conditions = vec![format!("clauses[{}] == ClauseState.Pending", i)];
+    if let Expr::Event{ args, .. } = &event_op.event {
+      let mut args = args.clone();
+      if let Some(bounds) = args.remove("bounds").filter(|_| name == "Choice") {
+        let bounds = choice_bounds(&bounds).map_err(unsupported)?;
+        let within = bounds.iter().map(|(low, high)| format!("(value >= {} && value <= {})", low, high));
+        conditions.push(format!("({})", within.collect::<Vec<_>>().join(" || ")));
+      }
+      clause.pattern(&args, &[], &mut conditions)?;
+    }
+    if let Some(guard) = &event_op.guard {
+      conditions.push(clause.typed(guard, Sol::Bool)?);
+    }
+
+    out.push_str(&format!("{}// Clause {}\n", body, i + 1));
+    out.push_str(&format!("{}if ({}) {{\n", body, conditions.join(" && ")));
+    out.push_str(&format!("{}clauses[{}] = ClauseState.Fired;\n", block, i));
+    out.push_str(&format!("{}emit ClauseFired({});\n", block, i));
+    for op in ops {
+      let arg = op.arg.clone().unwrap_or(Expr::Dict(HashMap::new()));
+      for statement in clause.op(&op.name, &arg)? {
+        for line in statement.lines() {
+          out.push_str(&format!("{}{}\n", block, line));
+        }
+      }
+    }
+    out.push_str(&format!("{}return;\n", block));
+    out.push_str(&format!("{}}}\n", body));
+  }
+  // Unmatched deposits are refunded, as the engine doesn't take them
+  // either, and a choice nothing waits for is an error to the chooser.
+  if name == "Deposit" || name == "Choice" {
+    out.push_str(&format!("{}revert(\"no clause matched\");\n", body));
+  }
+  out.push_str(&format!("{}}}\n", INDENT));
+  Ok(out)
+}
+
+/// The name of a Solidity contract for a file stem, e.g. `BountyEscrow`
+/// for `bounty_escrow`.
+pub fn contract_name(stem: &str) -> String {
+  let name: String = stem
+    .split(|c: char| !c.is_ascii_alphanumeric())
+    .map(|word| {
+      let mut chars = word.chars();
+      chars.next().map(|first| first.to_ascii_uppercase().to_string() + chars.as_str()).unwrap_or_default()
+    })
+    .collect();
+  match name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+    true => name,
+    false => format!("Monet{}", name),
+  }
+}
+
+/// Compiles a contract into a Solidity contract called `name` for FEVM.
+///
+/// Every clause gets a slot in a `ClauseState` storage array. Deposits
+/// are calls to the payable `deposit()`, with the caller as `from` and the
+/// FIL sent as the token; every other event type gets an `on` function,
+/// e.g. `onPay`, through which the deploying owner relays events with their
+/// fields as arguments. `pay` transfers FIL out of the contract's balance
+/// and `propose` calls `makeDealProposal` on the DealClient the contract is
+/// deployed with. Anything without an equivalent, such as a payee that
+/// isn't an address or a deal lifecycle event, is an error.
+pub fn to_solidity(contract: &Contract, registry: &Registry, name: &str) -> Result<String, ContractError> {
+  if contract.stmts.is_empty() {
+    return Err(unsupported("the contract has no clauses".to_string()));
+  }
+  // One function per event type, in order of first appearance.
+  let mut handlers: Vec<(&str, Vec<usize>)> = Vec::new();
+  for (i, (event_op, _)) in contract.stmts.iter().enumerate() {
+    if event_op.timeout.is_some() {
+      return Err(unsupported(format!("clause {} has a timeout, which has no Solidity equivalent yet", i + 1)));
+    }
+    if event_op.next.is_some() {
+      return Err(unsupported(format!("clause {} goes on to a nested `when` or `close`, which has no Solidity equivalent yet", i + 1)));
+    }
+    let event = event_name(&event_op.event)?;
+    match handlers.iter_mut().find(|(name, _)| *name == event) {
+      Some((_, clauses)) => clauses.push(i),
+      None => handlers.push((event, vec![i])),
+    }
+  }
+  let functions = handlers
+    .iter()
+    .map(|(event, clauses)| function(contract, registry, event, clauses))
+    .collect::<Result<Vec<_>, _>>()?;
+
+  let mut out = format!("{}{}\ncontract {} {{\n", HEADER, DEAL_CLIENT, name);
+  out.push_str(&format!("{}enum ClauseState {{ Pending, Fired }}\n\n", INDENT));
+  out.push_str(&format!("{}event ClauseFired(uint256 clause);\n\n", INDENT));
+  out.push_str(&format!("{}address public immutable owner;\n", INDENT));
+  out.push_str(&format!("{}IDealClient public immutable dealClient;\n", INDENT));
+  out.push_str(&format!("{}ClauseState[{}] public clauses;\n\n", INDENT, contract.stmts.len()));
+  out.push_str(&format!("\
+{0}constructor(IDealClient _dealClient) {{
+{0}{0}owner = msg.sender;
+{0}{0}dealClient = _dealClient;
+{0}}}
+
+{0}modifier onlyOwner() {{
+{0}{0}require(msg.sender == owner, \"only the owner relays events\");
+{0}{0}_;
+{0}}}
+
+{0}/// Sends FIL with all the gas left, as `transfer`'s 2300 isn't enough for
+{0}/// a contract or an account abstraction to receive it.
+{0}function sendValue(address to, uint256 amount) private {{
+{0}{0}(bool ok, ) = payable(to).call{{value: amount}}(\"\");
+{0}{0}require(ok, \"payment failed\");
+{0}}}
+
+{0}function isFinished() external view returns (bool) {{
+{0}{0}for (uint256 i = 0; i < clauses.length; i++) {{
+{0}{0}{0}if (clauses[i] == ClauseState.Pending) {{
+{0}{0}{0}{0}return false;
+{0}{0}{0}}}
+{0}{0}}}
+{0}{0}return true;
+{0}}}
+", INDENT));
+  for function in functions {
+    out.push('\n');
+    out.push_str(&function);
+  }
+  out.push_str("}\n");
+  Ok(out)
+}
+
+/// A value for every field of `schema`: the pattern's literal where it has
+/// one, the same value wherever a variable repeats, and a default
+/// otherwise.
+fn sample(schema: &[Field], pattern: &HashMap<String, Expr>, vars: &mut HashMap<String, Expr>, accounts: &mut u8) -> HashMap<String, Expr> {
+  let mut args = HashMap::new();
+  for field in schema {
+    let value = match (pattern.get(&field.name[..]), &field.ty) {
+      (Some(Expr::Dict(nested)), Type::Dict(fields)) => Expr::Dict(sample(fields, nested, vars, accounts)),
+      (Some(Expr::Var(name)), ty) => match vars.get(name) {
+        Some(value) => value.clone(),
+        None => {
+          let value = default(ty, vars, accounts);
+          vars.insert(name.clone(), value.clone());
+          value
+        },
+      },
+      (Some(literal), _) => literal.clone(),
+      (None, ty) => default(ty, vars, accounts),
+    };
+    args.insert(field.name.to_string(), value);
+  }
+  args
+}
+
+const PIECE_CID: &str = "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy";
+
+fn default(ty: &Type, vars: &mut HashMap<String, Expr>, accounts: &mut u8) -> Expr {
+  match ty {
+    Type::Account => {
+      *accounts += 1;
+      let mut bytes = [0; 20];
+      bytes[0] = 0x10;
+      bytes[19] = *accounts;
+      Expr::Address(Address::Ethereum(bytes))
+    },
+    Type::Amount => Expr::TokenAmount(TokenAmount::from_atto(10u128.pow(18))),
+    Type::Integer => Expr::Integer(1),
+    Type::String => Expr::QuotedString("x".to_string()),
+    Type::Bool => Expr::Bool(true),
+    Type::Cid => Expr::QuotedString(PIECE_CID.to_string()),
+    Type::Dict(fields) => Expr::Dict(sample(fields, &HashMap::new(), vars, accounts)),
+  }
+}
+
+/// A value of an event field as a Solidity literal.
+fn literal(value: &Expr, sol: Sol) -> Result<String, ContractError> {
+  match (value, sol) {
+    (Expr::QuotedString(s), Sol::Bytes) => cid_literal(s),
+    (Expr::QuotedString(s), Sol::Address) => Address::from_str(s)
+      .map_err(unsupported)
+      .and_then(|address| evm_address(&address)),
+    (value, sol) => Clause{ fields: &[], vars: HashMap::new() }.typed(value, sol),
+  }
+}
+
+fn lookup<'a>(args: &'a HashMap<String, Expr>, path: &[String]) -> Option<&'a Expr> {
+  let (first, rest) = path.split_first()?;
+  match (args.get(first)?, rest) {
+    (value, []) => Some(value),
+    (Expr::Dict(nested), rest) => lookup(nested, rest),
+    _ => None,
+  }
+}
+
+/// A Foundry test per clause for the contract `to_solidity` generates. Each
+/// sends the generated contract an event made up to fit the clause's
+/// pattern and checks that it does what the engine does with the same
+/// event: the clause that fires, or a revert, and the FIL paid out and
+/// deals proposed. The tests import `<name>.sol` from their own directory.
+pub fn to_solidity_tests(contract: &Contract, registry: &Registry, name: &str) -> Result<String, ContractError> {
+  to_solidity(contract, registry, name)?;
+
+  let mut out = format!("{}\nimport \"forge-std/Test.sol\";\nimport \"./{}.sol\";\n", HEADER, name);
+  out.push_str(&format!("
+contract MockDealClient is IDealClient {{
+{0}uint256 public proposals;
+
+{0}function makeDealProposal(DealRequest calldata) external returns (bytes32) {{
+{0}{0}proposals += 1;
+{0}{0}return bytes32(proposals);
+{0}}}
+}}
+
+contract {1}Test is Test {{
+{0}MockDealClient client;
+{0}{1} monet;
+
+{0}function setUp() public {{
+{0}{0}client = new MockDealClient();
+{0}{0}monet = new {1}(client);
+{0}}}
+", INDENT, name));
+
+  let body = INDENT.repeat(2);
+  for (i, (event_op, _)) in contract.stmts.iter().enumerate() {
+    let event = event_name(&event_op.event)?;
+    let schema = event_schema(contract, registry, event)?;
+    let pattern = match &event_op.event {
+      Expr::Event{ args, .. } => args.clone(),
+      _ => HashMap::new(),
+    };
+    let mut args = sample(schema, &pattern, &mut HashMap::new(), &mut 0);
+    let fields = event_fields(event, schema);
+
+    out.push_str(&format!("\n{}function test_clause_{}() public {{\n", INDENT, i + 1));
+    if event == "Deposit" {
+      // Deposits on FEVM are always of FIL.
+      if let Some(Expr::Dict(token)) = args.get_mut("token") {
+        token.insert("name".to_string(), Expr::QuotedString("Filecoin".to_string()));
+        token.insert("ticker".to_string(), Expr::QuotedString("FIL".to_string()));
+      }
+    }
+    if event == "Choice" && !matches!(pattern.get("value"), Some(Expr::Integer(_))) {
+      // Choose the least number the bounds allow.
+      if let Some(Ok(bounds)) = pattern.get("bounds").map(choice_bounds) {
+        args.insert("value".to_string(), Expr::Integer(bounds[0].0));
+      }
+    }
+    let value = |path: &[&str]| {
+      let path: Vec<String> = path.iter().map(|key| key.to_string()).collect();
+      lookup(&args, &path).cloned().unwrap_or(Expr::Integer(0))
+    };
+    let call = match event {
+      "Deposit" => {
+        let sender = literal(&value(&["from"]), Sol::Address)?;
+        let amount = literal(&value(&["token", "amount"]), Sol::Uint)?;
+        out.push_str(&format!("{}address sender = {};\n", body, sender));
+        out.push_str(&format!("{}vm.deal(sender, {});\n", body, amount));
+        out.push_str(&format!("{}vm.prank(sender);\n", body));
+        format!("monet.deposit{{value: {}}}();", amount)
+      },
+      "Choice" => {
+        let by = literal(&value(&["by"]), Sol::Address)?;
+        out.push_str(&format!("{}vm.prank({});\n", body, by));
+        let name = literal(&value(&["name"]), Sol::String)?;
+        format!("monet.choose({}, {});", name, literal(&value(&["value"]), Sol::Uint)?)
+      },
+      _ => {
+        let args = fields
+          .iter()
+          .map(|field| literal(lookup(&args, &field.path).unwrap_or(&Expr::Integer(0)), field.sol))
+          .collect::<Result<Vec<_>, _>>()?;
+        format!("monet.on{}({});", event, args.join(", "))
+      },
+    };
+
+    let event = Expr::Event{ name: event.to_string(), args };
+    let fired = match Engine::new(contract.clone()).fire(&event) {
+      Ok(Some(fired)) => Some(fired),
+      Ok(None) if !matches!(event_name(&event)?, "Deposit" | "Choice") => None,
+      _ => {
+        out.push_str(&format!("{}vm.expectRevert();\n{}{}\n{}}}\n", body, body, call, INDENT));
+        continue;
+      },
+    };
+    out.push_str(&format!("{}{}\n", body, call));
+    for k in 0..contract.stmts.len() {
+      let state = match fired.as_ref().is_some_and(|fired| fired.clause == k) {
+        true => "Fired",
+        false => "Pending",
+      };
+      out.push_str(&format!(
+        "{}assertEq(uint256(monet.clauses({})), uint256({}.ClauseState.{}));\n",
+        body, k, name, state
+      ));
+    }
+    let effects = fired.map(|fired| fired.effects).unwrap_or_default();
+    let mut paid: BTreeMap<String, u128> = BTreeMap::new();
+    for effect in &effects {
+      if let Effect::Transfer{ to, token } = effect {
+        let to = literal(&Expr::QuotedString(to.clone()), Sol::Address)?;
+        *paid.entry(to).or_default() += token.amount.atto();
+      }
+    }
+    for (to, amount) in paid {
+      out.push_str(&format!("{}assertEq({}.balance, {});\n", body, to, amount));
+    }
+    let proposals = effects.iter().filter(|effect| matches!(effect, Effect::ProposeDeal(_))).count();
+    out.push_str(&format!("{}assertEq(client.proposals(), {});\n", body, proposals));
+    out.push_str(&format!("{}}}\n", INDENT));
+  }
+  out.push_str("}\n");
+  Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::expr::parse_contract;
+
+  const SOURCE: &str = r#"
+    when Deposit { from: $sender, token: { amount: $amt } } if $amt >= 1 FIL
+    then pay { to: $sender, token: { name: "Filecoin", ticker: "FIL", amount: $amt * 0.9 } }
+    then propose {
+      deal_request: {
+        piece_cid: "baga6ea4seaqpy7usqklokfx2vxuynmupslkeutzexe2uqurdg5vhtebhxqmpqmy",
+        piece_size: 2048,
+        verified_deal: false,
+        label: "x",
+        start_epoch: 10,
+        end_epoch: 20,
+        storage_price_per_epoch: 1,
+        provider_collateral: 0,
+        extra_params_version: 1
+      }
+    }
+
+    when Pay { to: f01234, token: { amount: $amt } }
+    then pay { to: 0x52908400098527886E0F7030069857D2E4169EE7, token: { name: "Filecoin", ticker: "FIL", amount: $amt } }
+  "#;
+
+  #[test]
+  fn test_to_solidity() {
+    let solidity = to_solidity(&parse_contract(SOURCE).unwrap(), &Registry::new(), "Escrow").unwrap();
+    assert!(solidity.starts_with(HEADER), "{}", solidity);
+    assert!(solidity.contains("contract Escrow {\n    enum ClauseState { Pending, Fired }\n"), "{}", solidity);
+    assert!(solidity.contains("    ClauseState[2] public clauses;\n"), "{}", solidity);
+    // The reference DealClient's struct, for `makeDealProposal`'s selector.
+    assert!(solidity.contains("    uint256 client_collateral;\n    uint64 extra_params_version;\n    ExtraParamsV1 extra_params;\n}"), "{}", solidity);
+    assert!(solidity.contains("    function deposit() external payable {
+        // Clause 1
+        if (clauses[0] == ClauseState.Pending && (msg.value >= 1000000000000000000)) {
+            clauses[0] = ClauseState.Fired;
+            emit ClauseFired(0);
+            sendValue(msg.sender, (msg.value * 9 / 10));
+            dealClient.makeDealProposal(DealRequest({
+                piece_cid: hex\"0181e203922020"), "{}", solidity);
+    assert!(solidity.contains("
+                piece_size: uint64(2048),
+                verified_deal: false,
+                label: \"x\",
+                start_epoch: int64(int256(10)),
+                end_epoch: int64(int256(20)),
+                storage_price_per_epoch: 1,
+                provider_collateral: 0,
+                client_collateral: 0,
+                extra_params_version: uint64(1),
+                extra_params: ExtraParamsV1({location_ref: \"\", car_size: 0, skip_ipni_announce: false, remove_unsealed_copy: false})
+            }));
+            return;
+        }
+        revert(\"no clause matched\");
+    }
+"), "{}", solidity);
+    assert!(solidity.contains("    function onPay(address to, string calldata token_name, string calldata token_ticker, uint256 token_amount) external onlyOwner {
+        // Clause 2
+        if (clauses[1] == ClauseState.Pending && to == 0xFF000000000000000000000000000000000004d2) {
+            clauses[1] = ClauseState.Fired;
+            emit ClauseFired(1);
+            sendValue(0x52908400098527886E0F7030069857D2E4169EE7, token_amount);
+            return;
+        }
+    }
+"), "{}", solidity);
+  }
+
+  /// Compiles the output of `SOURCE` with `solc`.
+  #[test]
+  #[ignore = "needs solc on the PATH; run with `cargo test test_solc -- --ignored`"]
+  fn test_solc() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let solidity = to_solidity(&parse_contract(SOURCE).unwrap(), &Registry::new(), "Escrow").unwrap();
+    let mut solc = Command::new("solc")
+      .args(["--bin", "-"])
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .stderr(Stdio::piped())
+      .spawn()
+      .expect("solc should be on the PATH");
+    solc.stdin.take().unwrap().write_all(solidity.as_bytes()).unwrap();
+    let output = solc.wait_with_output().unwrap();
+    assert!(output.status.success(), "{}\n{}", String::from_utf8_lossy(&output.stderr), solidity);
+  }
+
+  #[test]
+  fn test_to_solidity_tests() {
+    let tests = to_solidity_tests(&parse_contract(SOURCE).unwrap(), &Registry::new(), "Escrow").unwrap();
+    assert!(tests.contains("import \"./Escrow.sol\";\n"), "{}", tests);
+    // The deposit meets the guard, so clause 1 pays back 90% of it.
+    assert!(tests.contains("    function test_clause_1() public {
+        address sender = 0x1000000000000000000000000000000000000001;
+        vm.deal(sender, 1000000000000000000);
+        vm.prank(sender);
+        monet.deposit{value: 1000000000000000000}();
+        assertEq(uint256(monet.clauses(0)), uint256(Escrow.ClauseState.Fired));
+        assertEq(uint256(monet.clauses(1)), uint256(Escrow.ClauseState.Pending));
+        assertEq(0x1000000000000000000000000000000000000001.balance, 900000000000000000);
+        assertEq(client.proposals(), 1);
+    }
+"), "{}", tests);
+    // The contract holds nothing to pay with, in the engine as on chain.
+    assert!(tests.contains("    function test_clause_2() public {
+        vm.expectRevert();
+        monet.onPay(0xFF000000000000000000000000000000000004d2, \"x\", \"x\", 1000000000000000000);
+    }
+"), "{}", tests);
+  }
+
+  #[test]
+  fn test_choice() {
+    let contract = parse_contract(r#"
+    when Choice { by: f01234, name: "approve", bounds: [(1, 1), (3, 5)], value: $n }
+    then pay { to: f01234, token: { name: "Filecoin", ticker: "FIL", amount: $n } }
+    "#).unwrap();
+    let solidity = to_solidity(&contract, &Registry::new(), "Vote").unwrap();
+    assert!(solidity.contains("    function choose(string calldata name, uint256 value) external {
+        // Clause 1
+        if (clauses[0] == ClauseState.Pending && ((value >= 1 && value <= 1) || (value >= 3 && value <= 5)) && msg.sender == 0xFF000000000000000000000000000000000004d2 && keccak256(bytes(name)) == keccak256(bytes(\"approve\"))) {
+            clauses[0] = ClauseState.Fired;
+            emit ClauseFired(0);
+            sendValue(0xFF000000000000000000000000000000000004d2, value);
+            return;
+        }
+        revert(\"no clause matched\");
+    }
+"), "{}", solidity);
+    let tests = to_solidity_tests(&contract, &Registry::new(), "Vote").unwrap();
+    // The least number in bounds is chosen; there is nothing to pay it with.
+    assert!(tests.contains("    function test_clause_1() public {
+        vm.prank(0xFF000000000000000000000000000000000004d2);
+        vm.expectRevert();
+        monet.choose(\"approve\", 1);
+    }
+"), "{}", tests);
+  }
+
+  #[test]
+  fn test_unsupported() {
+    let error = |source: &str| to_solidity(&parse_contract(source).unwrap(), &Registry::new(), "C").unwrap_err().to_string();
+    assert_eq!(
+      error(r#"when Deposit {} then pay { to: "alice", token: { name: "a", ticker: "FIL", amount: 1 } }"#),
+      "Can't compile to Solidity: `\"alice\"` is a string, expected a address"
+    );
+    assert_eq!(
+      error(r#"when DealActivated { deal_id: 1 }"#),
+      "Can't compile to Solidity: `DealActivated` clauses need the engine's deal tracking"
+    );
+    assert_eq!(
+      error(r#"when Deposit { token: $t } if $t > 1"#),
+      "Can't compile to Solidity: `token` is a dict; only its fields can be used"
+    );
+    assert_eq!(
+      error(r#"when Deposit {} timeout 10 epochs else pay { to: f01, token: { name: "a", ticker: "FIL", amount: 1 } }"#),
+      "Can't compile to Solidity: clause 1 has a timeout, which has no Solidity equivalent yet"
+    );
+    assert_eq!(
+      error("when Deposit {} then close"),
+      "Can't compile to Solidity: clause 1 goes on to a nested `when` or `close`, which has no Solidity equivalent yet"
+    );
+    assert_eq!(error("close"), "Can't compile to Solidity: the contract has no clauses");
+  }
+
+  #[test]
+  fn test_contract_name() {
+    assert_eq!(contract_name("bounty_escrow"), "BountyEscrow");
+    assert_eq!(contract_name("2-party"), "Monet2Party");
+  }
+}